@@ -1,13 +1,45 @@
-use std::{cell::UnsafeCell, slice};
+use std::{mem::size_of, slice};
+
+use crate::{
+    heatmap::Heatmap,
+    pixel_ownership::{OwnerId, PixelOwnership},
+    protected_regions::ProtectedRegions,
+    shared_memory::{self, SharedMemory, SharedMemoryLocation},
+};
+
+/// Width and height of the backing buffer of a padded [`FrameBuffer`].
+/// Coordinates are parsed with at most 4 digits and the connection offset has at most 4 digits as well,
+/// so every coordinate a client can send is below this.
+pub const PADDED_SIZE: usize = 20_000;
+
+/// Owns the memory the pixels are stored in, all accesses go through [`FrameBuffer::buffer`]
+enum PixelStorage {
+    Heap(#[allow(dead_code)] Vec<u32>),
+    SharedMemory(SharedMemory),
+}
 
 pub struct FrameBuffer {
+    /// Visible width
     width: usize,
+    /// Visible height
     height: usize,
-    buffer: UnsafeCell<Vec<u32>>,
+    /// Number of pixels between the starts of two rows in `buffer`
+    stride: usize,
+    /// Points into `storage`, so that we don't need to check where the pixels are stored on every access
+    buffer: *mut u32,
+    buffer_len: usize,
+    storage: PixelStorage,
+    /// Client writes don't need a bounds check, as they always land in the padded buffer
+    skip_bounds_check: bool,
+    pixel_ownership: Option<PixelOwnership>,
+    heatmap: Option<Heatmap>,
+    protected_regions: ProtectedRegions,
 }
 
 // FIXME Nothing to see here, I don't know what I'm doing ¯\_(ツ)_/¯
 unsafe impl Sync for FrameBuffer {}
+// `buffer` points into `storage`, which moves together with the FrameBuffer
+unsafe impl Send for FrameBuffer {}
 
 impl FrameBuffer {
     pub fn new(width: usize, height: usize) -> Self {
@@ -16,10 +48,110 @@ impl FrameBuffer {
         FrameBuffer {
             width,
             height,
-            buffer: UnsafeCell::from(buffer),
+            stride: width,
+            buffer: buffer.as_mut_ptr(),
+            buffer_len: buffer.len(),
+            storage: PixelStorage::Heap(buffer),
+            skip_bounds_check: false,
+            pixel_ownership: None,
+            heatmap: None,
+            protected_regions: ProtectedRegions::new(width, height),
+        }
+    }
+
+    /// Creates a [`FrameBuffer`] backed by a [`PADDED_SIZE`] x [`PADDED_SIZE`] buffer, of which only the top left
+    /// `width` x `height` pixels are visible.
+    /// Writes by clients outside of the visible area land in the padding, so they don't need a bounds check.
+    ///
+    /// The buffer is allocated zeroed, so the operating system only backs the memory pages that actually get
+    /// written to with physical memory. Clients writing everywhere can still cause up to 1.6 GB to be used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `width` or `height` exceed [`PADDED_SIZE`].
+    pub fn new_padded(width: usize, height: usize) -> Self {
+        assert!(
+            width <= PADDED_SIZE && height <= PADDED_SIZE,
+            "padded framebuffer can be at most {PADDED_SIZE}x{PADDED_SIZE} pixels"
+        );
+        let mut buffer = vec![0; PADDED_SIZE * PADDED_SIZE];
+        FrameBuffer {
+            width,
+            height,
+            stride: PADDED_SIZE,
+            buffer: buffer.as_mut_ptr(),
+            buffer_len: buffer.len(),
+            storage: PixelStorage::Heap(buffer),
+            skip_bounds_check: true,
+            pixel_ownership: None,
+            heatmap: None,
+            protected_regions: ProtectedRegions::new(width, height),
         }
     }
 
+    /// Creates a [`FrameBuffer`] whose pixels are stored in shared memory, so that other processes can read them,
+    /// see [`shared_memory`].
+    pub fn new_in_shared_memory(
+        width: usize,
+        height: usize,
+        location: SharedMemoryLocation,
+    ) -> Result<Self, shared_memory::Error> {
+        let mut shared_memory = SharedMemory::create(location, width, height)?;
+        Ok(FrameBuffer {
+            width,
+            height,
+            stride: width,
+            buffer: shared_memory.pixels_ptr(),
+            buffer_len: width * height,
+            storage: PixelStorage::SharedMemory(shared_memory),
+            skip_bounds_check: false,
+            pixel_ownership: None,
+            heatmap: None,
+            protected_regions: ProtectedRegions::new(width, height),
+        })
+    }
+
+    pub fn shared_memory(&self) -> Option<&SharedMemory> {
+        match &self.storage {
+            PixelStorage::Heap(_) => None,
+            PixelStorage::SharedMemory(shared_memory) => Some(shared_memory),
+        }
+    }
+
+    #[inline(always)]
+    #[allow(clippy::mut_from_ref)]
+    fn pixels(&self) -> &mut [u32] {
+        unsafe { slice::from_raw_parts_mut(self.buffer, self.buffer_len) }
+    }
+
+    /// Additionally track which client last wrote each pixel, see [`PixelOwnership`].
+    pub fn with_pixel_ownership(mut self, ipv6_prefix_length: u8) -> Self {
+        self.pixel_ownership = Some(PixelOwnership::new(self.get_size(), ipv6_prefix_length));
+        // The ownership is only tracked for the visible pixels
+        self.skip_bounds_check = false;
+        self
+    }
+
+    pub fn pixel_ownership(&self) -> Option<&PixelOwnership> {
+        self.pixel_ownership.as_ref()
+    }
+
+    /// Additionally count the writes to each pixel, see [`Heatmap`].
+    pub fn with_heatmap(mut self) -> Self {
+        self.heatmap = Some(Heatmap::new(self.get_size()));
+        // The heatmap only covers the visible pixels
+        self.skip_bounds_check = false;
+        self
+    }
+
+    pub fn heatmap(&self) -> Option<&Heatmap> {
+        self.heatmap.as_ref()
+    }
+
+    pub fn protected_regions(&self) -> &ProtectedRegions {
+        &self.protected_regions
+    }
+
     pub fn get_width(&self) -> usize {
         self.width
     }
@@ -28,14 +160,36 @@ impl FrameBuffer {
         self.height
     }
 
+    /// Number of visible pixels
     pub fn get_size(&self) -> usize {
         self.width * self.height
     }
 
+    /// Number of pixels between the starts of two rows in [`FrameBuffer::get_buffer`].
+    /// Equals the width, unless the [`FrameBuffer`] is padded.
+    pub fn get_stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Memory allocated for the drawing surface and all per-pixel data, such as the heatmap
+    pub fn allocated_bytes(&self) -> usize {
+        let buffer = self.buffer_len * size_of::<u32>();
+        let pixel_ownership = self
+            .pixel_ownership
+            .as_ref()
+            .map_or(0, |_| self.get_size() * size_of::<OwnerId>());
+        let heatmap = self
+            .heatmap
+            .as_ref()
+            .map_or(0, |_| self.get_size() * size_of::<u16>());
+
+        buffer + pixel_ownership + heatmap + self.protected_regions.allocated_bytes()
+    }
+
     #[inline(always)]
     pub fn get(&self, x: usize, y: usize) -> Option<u32> {
         if x < self.width && y < self.height {
-            unsafe { Some((*self.buffer.get())[x + y * self.width]) }
+            Some(self.pixels()[x + y * self.stride])
         } else {
             None
         }
@@ -43,26 +197,101 @@ impl FrameBuffer {
 
     #[inline(always)]
     pub fn get_unchecked(&self, x: usize, y: usize) -> u32 {
-        unsafe { (*self.buffer.get())[x + y * self.width] }
+        self.pixels()[x + y * self.stride]
     }
 
     #[inline(always)]
     pub fn set(&self, x: usize, y: usize, rgba: u32) {
-        // TODO: If we make the FrameBuffer large enough (e.g. 10_000 x 10_000) we don't need to check the bounds here (x and y are max 4 digit numbers).
-        // (flamegraph has shown 5.21% of runtime in this bound check O.o)
         if x < self.width && y < self.height {
-            unsafe { (*self.buffer.get())[x + y * self.width] = rgba }
+            self.pixels()[x + y * self.stride] = rgba;
+        }
+    }
+
+    /// Same as [`FrameBuffer::set`], but meant for writes by clients: Protected regions are not written to,
+    /// the owner of the pixel is recorded if ownership tracking is enabled and the write is counted in the heatmap if enabled.
+    ///
+    /// For padded [`FrameBuffer`]s `x` and `y` must be below [`PADDED_SIZE`].
+    #[inline(always)]
+    pub fn set_with_owner(&self, x: usize, y: usize, rgba: u32, owner: OwnerId) {
+        if self.skip_bounds_check {
+            // (flamegraph has shown 5.21% of runtime in the bound check below O.o)
+            debug_assert!(x < PADDED_SIZE && y < PADDED_SIZE);
+            if !self.protected_regions.is_protected(x, y) {
+                unsafe { *self.buffer.add(x + y * self.stride) = rgba }
+            }
+            return;
+        }
+
+        if x < self.width && y < self.height {
+            if self.protected_regions.is_protected_unchecked(x, y) {
+                return;
+            }
+            let index = x + y * self.stride;
+            self.pixels()[index] = rgba;
+
+            let visible_index = x + y * self.width;
+            if let Some(pixel_ownership) = &self.pixel_ownership {
+                pixel_ownership.set_unchecked(visible_index, owner);
+            }
+            if let Some(heatmap) = &self.heatmap {
+                heatmap.increment_unchecked(visible_index);
+            }
+        }
+    }
+
+    pub fn get_owner(&self, x: usize, y: usize) -> Option<OwnerId> {
+        match &self.pixel_ownership {
+            Some(pixel_ownership) if x < self.width && y < self.height => {
+                Some(pixel_ownership.get_unchecked(x + y * self.width))
+            }
+            _ => None,
+        }
+    }
+
+    /// Replaces the whole drawing surface, e.g. to restore a snapshot.
+    /// Pixel ownership and heatmap are left untouched.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pixels` does not contain exactly [`FrameBuffer::get_size`] pixels.
+    pub fn overwrite(&self, pixels: &[u32]) {
+        assert_eq!(pixels.len(), self.get_size());
+        let buffer = self.pixels();
+        for (y, row) in pixels.chunks_exact(self.width).enumerate() {
+            let start = y * self.stride;
+            buffer[start..start + self.width].copy_from_slice(row);
+        }
+    }
+
+    /// The visible pixels of row `y`
+    pub fn get_row(&self, y: usize) -> &[u32] {
+        let start = y * self.stride;
+        &self.pixels()[start..start + self.width]
+    }
+
+    /// Copies the visible pixels row by row into `target`, which is filled completely.
+    /// This way sinks don't need to care about padding.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `target` is larger than [`FrameBuffer::get_size`] or doesn't contain complete rows.
+    pub fn copy_visible_into(&self, target: &mut [u32]) {
+        assert_eq!(target.len() % self.width, 0);
+        for (y, row) in target.chunks_exact_mut(self.width).enumerate() {
+            row.copy_from_slice(self.get_row(y));
         }
     }
 
-    pub fn get_buffer(&self) -> *mut Vec<u32> {
-        self.buffer.get()
+    /// The complete backing buffer, including the padding. Pixel `(x, y)` is at `x + y * stride`,
+    /// see [`FrameBuffer::get_stride`].
+    pub fn get_buffer(&self) -> *mut [u32] {
+        self.pixels()
     }
 
+    /// The complete backing buffer, including the padding
     pub fn as_bytes(&self) -> &[u8] {
-        let buffer = self.buffer.get();
-        let len_in_bytes: usize = unsafe { (*buffer).len() } * 4;
+        let len_in_bytes = self.buffer_len * 4;
 
-        unsafe { slice::from_raw_parts((*buffer).as_ptr() as *const u8, len_in_bytes) }
+        unsafe { slice::from_raw_parts(self.buffer as *const u8, len_in_bytes) }
     }
 }
@@ -0,0 +1,92 @@
+use std::cell::UnsafeCell;
+
+/// Parallel buffer to the [`crate::framebuffer::FrameBuffer`] counting the writes to each pixel.
+/// The counters saturate instead of overflowing and should be decayed periodically (see [`Heatmap::decay`]),
+/// so that the heatmap shows the recent activity.
+pub struct Heatmap {
+    counters: UnsafeCell<Vec<u16>>,
+}
+
+// Same story as for the FrameBuffer, we might lose a count here and there, but who cares
+unsafe impl Sync for Heatmap {}
+
+impl Heatmap {
+    pub fn new(size: usize) -> Self {
+        Heatmap {
+            counters: UnsafeCell::from(vec![0; size]),
+        }
+    }
+
+    #[inline(always)]
+    pub fn increment_unchecked(&self, index: usize) {
+        unsafe {
+            let counter = (*self.counters.get()).get_unchecked_mut(index);
+            *counter = counter.saturating_add(1);
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_unchecked(&self, index: usize) -> u16 {
+        unsafe { *(*self.counters.get()).get_unchecked(index) }
+    }
+
+    /// Divides all counters by two, so a heavily painted pixel cools down again once nobody paints it anymore.
+    /// Calling this every `half_life` results in the counts halving every `half_life`.
+    pub fn decay(&self) {
+        let counters = unsafe { &mut *self.counters.get() };
+        for counter in counters.iter_mut() {
+            *counter >>= 1;
+        }
+    }
+
+    /// Renders the heatmap into the same pixel format the [`crate::framebuffer::FrameBuffer`] uses.
+    /// The colors are scaled logarithmically relative to the hottest pixel, going from black over blue and red to yellow.
+    pub fn render(&self) -> Vec<u32> {
+        let mut rendered = vec![0; unsafe { &*self.counters.get() }.len()];
+        self.render_into(&mut rendered);
+        rendered
+    }
+
+    /// Like [`Heatmap::render`], but renders into an existing buffer, e.g. every frame of a VNC server.
+    /// Only the first `target.len()` pixels are rendered.
+    pub fn render_into(&self, target: &mut [u32]) {
+        let counters = unsafe { &*self.counters.get() };
+        let max = counters.iter().copied().max().unwrap_or_default();
+        // ln(1 + max), so that a single write is not black
+        let max_ln = (max as f32).ln_1p();
+
+        // There are at most `max + 1` different colors, so they are only calculated once
+        let colors: Vec<u32> = (0..=max)
+            .map(|counter| {
+                if counter == 0 {
+                    return 0;
+                }
+                heat_color((counter as f32).ln_1p() / max_ln)
+            })
+            .collect();
+        for (pixel, counter) in target.iter_mut().zip(counters) {
+            *pixel = colors[*counter as usize];
+        }
+    }
+}
+
+/// `heat` must be in the range `0.0..=1.0`
+fn heat_color(heat: f32) -> u32 {
+    let (r, g, b) = if heat < 1.0 / 3.0 {
+        // black -> blue
+        (0.0, 0.0, heat * 3.0)
+    } else if heat < 2.0 / 3.0 {
+        // blue -> red
+        let t = (heat - 1.0 / 3.0) * 3.0;
+        (t, 0.0, 1.0 - t)
+    } else {
+        // red -> yellow
+        (1.0, (heat - 2.0 / 3.0) * 3.0, 0.0)
+    };
+
+    // The FrameBuffer stores the colors as little endian rrggbb
+    let r = (r * 255.0) as u32;
+    let g = (g * 255.0) as u32;
+    let b = (b * 255.0) as u32;
+    b << 16 | g << 8 | r
+}
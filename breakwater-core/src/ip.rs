@@ -0,0 +1,18 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+/// Network address of the first `prefix_length` bits of the given IPv6 address
+pub fn ipv6_prefix(ip: Ipv6Addr, prefix_length: u8) -> Ipv6Addr {
+    let mask = u128::MAX
+        .checked_shl(128 - prefix_length.min(128) as u32)
+        .unwrap_or(0);
+    Ipv6Addr::from(u128::from(ip) & mask)
+}
+
+/// Identifies a client by its IPv4 address or by the prefix of its IPv6 address, as IPv6 clients usually have a
+/// whole prefix they can pick addresses from
+pub fn client_address(ip: IpAddr, ipv6_prefix_length: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => IpAddr::V6(ipv6_prefix(v6, ipv6_prefix_length)),
+    }
+}
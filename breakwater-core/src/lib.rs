@@ -1,8 +1,16 @@
 use const_format::formatcp;
 
 pub mod framebuffer;
+pub mod heatmap;
+pub mod ip;
+pub mod pixel_ownership;
+pub mod protected_regions;
+pub mod shared_memory;
 pub mod test;
 
+/// Canvas names need to fit into a single `CANVAS <name>` command of the parser
+pub const MAX_CANVAS_NAME_LENGTH: usize = 16;
+
 pub const HELP_TEXT: &[u8] = formatcp!("\
 Pixelflut server powered by breakwater https://github.com/sbernauer/breakwater
 Available commands:
@@ -11,8 +19,11 @@ PX x y rrggbb: Color the pixel (x,y) with the given hexadecimal color rrggbb
 {}
 PX x y gg: Color the pixel (x,y) with the hexadecimal color gggggg. Basically this is the same as the other commands, but is a more efficient way of filling white, black or gray areas
 PX x y: Get the color value of the pixel (x,y)
-SIZE: Get the size of the drawing surface, e.g. `SIZE 1920 1080`
+WHO x y: Get the client that last wrote the pixel (x,y), e.g. `WHO 0 0 10.0.0.1`. Only answered if the server tracks pixel ownership
+SIZE: Get the size of the drawing surface, e.g. `SIZE 1920 1080`. Protected regions at the bottom or right edge spanning the whole surface (such as a stats bar) are not included
+CAPS: Get the supported commands and the protected regions clients can not draw on, e.g. `CAPS PX SIZE HELP OFFSET CAPS PROTECTED:0,1045,1920,35` (PROTECTED:x,y,width,height)
 OFFSET x y: Apply offset (x,y) to all further pixel draws on this connection. This can e.g. be used to pre-calculate an image/animation and simply use the OFFSET command to move it around the screen without the need to re-calculate it
+CANVAS name: Switch this connection to the canvas with the given name, if the server hosts multiple canvases. All further commands (including SIZE and OFFSET) apply to the selected canvas
 ",
 if cfg!(feature = "alpha") {
     "PX x y rrggbbaa: Color the pixel (x,y) with the given hexadecimal color rrggbb and a transparency of aa, where ff means draw normally on top of the existing pixel and 00 means fully transparent (no change at all)"
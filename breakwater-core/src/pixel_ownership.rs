@@ -0,0 +1,107 @@
+use std::{cell::UnsafeCell, collections::HashMap, net::IpAddr, sync::Mutex};
+
+use crate::ip::client_address;
+
+/// Identifies the client that last wrote a pixel. `0` means nobody has written the pixel yet.
+pub type OwnerId = u32;
+
+pub const NO_OWNER: OwnerId = 0;
+
+/// Parallel buffer to the [`crate::framebuffer::FrameBuffer`] recording which client last wrote each pixel.
+pub struct PixelOwnership {
+    owners: UnsafeCell<Vec<OwnerId>>,
+    ipv6_prefix_length: u8,
+    registry: Mutex<OwnerRegistry>,
+}
+
+#[derive(Default)]
+struct OwnerRegistry {
+    ids: HashMap<IpAddr, OwnerId>,
+    // Index is `OwnerId - 1`
+    addresses: Vec<IpAddr>,
+}
+
+// Same story as for the FrameBuffer, races between clients writing the same pixel are fine
+unsafe impl Sync for PixelOwnership {}
+
+impl PixelOwnership {
+    /// IPv6 clients are grouped by the first `ipv6_prefix_length` bits of their address,
+    /// as a single client can usually use all the addresses of e.g. a /64.
+    pub fn new(size: usize, ipv6_prefix_length: u8) -> Self {
+        PixelOwnership {
+            owners: UnsafeCell::from(vec![NO_OWNER; size]),
+            ipv6_prefix_length: ipv6_prefix_length.min(128),
+            registry: Mutex::new(OwnerRegistry::default()),
+        }
+    }
+
+    /// Returns the [`OwnerId`] of the given client, registering it if it was not seen before.
+    /// This takes a lock, so call it once per connection and not once per pixel.
+    pub fn register(&self, ip: IpAddr) -> OwnerId {
+        let address = self.owner_address(ip);
+        let mut registry = self.registry.lock().unwrap();
+        if let Some(id) = registry.ids.get(&address) {
+            return *id;
+        }
+
+        registry.addresses.push(address);
+        let id = registry.addresses.len() as OwnerId;
+        registry.ids.insert(address, id);
+        id
+    }
+
+    /// The address that gets recorded as owner for the given client IP.
+    /// This is the network address of the prefix for IPv6 clients.
+    pub fn owner_address(&self, ip: IpAddr) -> IpAddr {
+        client_address(ip, self.ipv6_prefix_length)
+    }
+
+    /// Human readable representation of the owner, e.g. `10.0.0.1` or `2001:db8::/64`.
+    pub fn owner_label(&self, address: IpAddr) -> String {
+        match address {
+            IpAddr::V6(_) if self.ipv6_prefix_length < 128 => {
+                format!("{address}/{}", self.ipv6_prefix_length)
+            }
+            _ => address.to_string(),
+        }
+    }
+
+    pub fn get_address(&self, id: OwnerId) -> Option<IpAddr> {
+        if id == NO_OWNER {
+            return None;
+        }
+        let registry = self.registry.lock().unwrap();
+        registry.addresses.get(id as usize - 1).copied()
+    }
+
+    #[inline(always)]
+    pub fn get_unchecked(&self, index: usize) -> OwnerId {
+        unsafe { (*self.owners.get())[index] }
+    }
+
+    #[inline(always)]
+    pub fn set_unchecked(&self, index: usize, owner: OwnerId) {
+        unsafe { (*self.owners.get())[index] = owner }
+    }
+
+    /// Counts the pixels currently owned by every client.
+    /// Walks the whole buffer, so this should only be called periodically (e.g. by the statistics).
+    pub fn pixels_per_owner(&self) -> HashMap<IpAddr, u64> {
+        let owners = unsafe { &*self.owners.get() };
+        let addresses = self.registry.lock().unwrap().addresses.clone();
+
+        let mut counts = vec![0_u64; addresses.len() + 1];
+        for owner in owners {
+            // Owners registered after we cloned the addresses are skipped
+            if let Some(count) = counts.get_mut(*owner as usize) {
+                *count += 1;
+            }
+        }
+
+        addresses
+            .into_iter()
+            .zip(counts.into_iter().skip(1))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Area of the drawing surface, e.g. a sponsor logo or the stats bar of the VNC server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Display for Rect {
+    /// Formats as `x,y,width,height`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseRectError {
+    input: String,
+}
+
+impl Display for ParseRectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid region {:?}, expected x,y,width,height",
+            self.input
+        )
+    }
+}
+
+impl std::error::Error for ParseRectError {}
+
+impl FromStr for Rect {
+    type Err = ParseRectError;
+
+    /// Parses `x,y,width,height`
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let err = || ParseRectError {
+            input: input.to_string(),
+        };
+        let parts = input
+            .split(',')
+            .map(|part| part.trim().parse::<usize>().map_err(|_| err()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match parts.as_slice() {
+            [x, y, width, height] => Ok(Rect {
+                x: *x,
+                y: *y,
+                width: *width,
+                height: *height,
+            }),
+            _ => Err(err()),
+        }
+    }
+}
+
+/// Regions of the [`crate::framebuffer::FrameBuffer`] clients can not draw on.
+/// The regions can be changed at runtime, the parser only checks a precomputed bitmask with one bit per visible pixel.
+pub struct ProtectedRegions {
+    width: usize,
+    height: usize,
+    /// Regions together with their id
+    regions: Mutex<Vec<(u32, Rect)>>,
+    next_id: AtomicU32,
+    /// One bit per pixel, set if the pixel is protected
+    mask: Vec<AtomicU64>,
+    /// Shortcut, so that we don't need to check the mask at all if there are no protected regions
+    any_protected: AtomicBool,
+}
+
+impl ProtectedRegions {
+    /// `width` and `height` are the visible size of the [`crate::framebuffer::FrameBuffer`], the padding of a
+    /// padded one can't be protected (and is never shown anyway)
+    pub fn new(width: usize, height: usize) -> Self {
+        ProtectedRegions {
+            width,
+            height,
+            regions: Mutex::new(Vec::new()),
+            next_id: AtomicU32::new(1),
+            mask: (0..(width * height).div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            any_protected: AtomicBool::new(false),
+        }
+    }
+
+    /// Pixels outside of the visible area are never protected
+    #[inline(always)]
+    pub fn is_protected(&self, x: usize, y: usize) -> bool {
+        self.any_protected.load(Ordering::Relaxed)
+            && x < self.width
+            && y < self.height
+            && self.is_masked(x + y * self.width)
+    }
+
+    /// `x` and `y` must be inside the visible area
+    #[inline(always)]
+    pub fn is_protected_unchecked(&self, x: usize, y: usize) -> bool {
+        self.any_protected.load(Ordering::Relaxed) && self.is_masked(x + y * self.width)
+    }
+
+    #[inline(always)]
+    fn is_masked(&self, index: usize) -> bool {
+        unsafe { self.mask.get_unchecked(index / 64) }.load(Ordering::Relaxed) & (1 << (index % 64))
+            != 0
+    }
+
+    /// Protects the given region and returns its id.
+    /// If the exact same region is already protected, the id of the existing region is returned.
+    pub fn add(&self, rect: Rect) -> u32 {
+        let mut regions = self.regions.lock().unwrap();
+        if let Some((id, _)) = regions.iter().find(|(_, existing)| *existing == rect) {
+            return *id;
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        regions.push((id, rect));
+        self.update_mask(&regions);
+        id
+    }
+
+    /// Returns `false` if there is no region with the given id
+    pub fn remove(&self, id: u32) -> bool {
+        let mut regions = self.regions.lock().unwrap();
+        let len_before = regions.len();
+        regions.retain(|(existing_id, _)| *existing_id != id);
+        self.update_mask(&regions);
+        regions.len() != len_before
+    }
+
+    pub fn list(&self) -> Vec<(u32, Rect)> {
+        self.regions.lock().unwrap().clone()
+    }
+
+    /// The size of the drawing surface without the protected regions spanning the whole width at the bottom
+    /// (such as the stats bar of the VNC server) or spanning the whole height at the right.
+    pub fn usable_size(&self) -> (usize, usize) {
+        let regions = self.regions.lock().unwrap();
+        let (mut width, mut height) = (self.width, self.height);
+
+        // Regions might be stacked, so repeat until nothing changes anymore
+        loop {
+            let (previous_width, previous_height) = (width, height);
+            for (_, rect) in regions.iter() {
+                if rect.x == 0
+                    && rect.x.saturating_add(rect.width) >= width
+                    && rect.y.saturating_add(rect.height) >= height
+                {
+                    height = height.min(rect.y);
+                }
+                if rect.y == 0
+                    && rect.y.saturating_add(rect.height) >= height
+                    && rect.x.saturating_add(rect.width) >= width
+                {
+                    width = width.min(rect.x);
+                }
+            }
+            if (width, height) == (previous_width, previous_height) {
+                return (width, height);
+            }
+        }
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.mask.len() * std::mem::size_of::<AtomicU64>()
+    }
+
+    fn update_mask(&self, regions: &[(u32, Rect)]) {
+        let mut mask = vec![0_u64; self.mask.len()];
+        for (_, rect) in regions {
+            for y in rect.y..rect.y.saturating_add(rect.height).min(self.height) {
+                for x in rect.x..rect.x.saturating_add(rect.width).min(self.width) {
+                    let index = x + y * self.width;
+                    mask[index / 64] |= 1 << (index % 64);
+                }
+            }
+        }
+
+        for (word, new) in self.mask.iter().zip(mask) {
+            word.store(new, Ordering::Relaxed);
+        }
+        self.any_protected
+            .store(!regions.is_empty(), Ordering::Relaxed);
+    }
+}
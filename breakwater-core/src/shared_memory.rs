@@ -0,0 +1,328 @@
+//! Places the pixels of the [`crate::framebuffer::FrameBuffer`] in a named POSIX shared memory segment or a
+//! memory-mapped file, so that other processes can read the canvas without copying it.
+//!
+//! The memory starts with a [`HEADER_SIZE`] bytes header (all fields in native endianness):
+//!
+//! | Offset | Type      | Content                                                             |
+//! |--------|-----------|---------------------------------------------------------------------|
+//! | 0      | `[u8; 8]` | Magic bytes `BRKWATER`                                              |
+//! | 8      | `u32`     | Version of the layout, currently 1                                  |
+//! | 12     | `u32`     | Size of the header in bytes ([`HEADER_SIZE`]), the pixels follow it |
+//! | 16     | `u32`     | Width                                                               |
+//! | 20     | `u32`     | Height                                                              |
+//! | 24     | `u32`     | [`PixelFormat`]                                                     |
+//! | 32     | `u64`     | Frame counter, incremented (atomically) every time a frame is done  |
+//!
+//! The pixels follow row by row without any padding. Clients draw all the time, so readers can see partially
+//! drawn frames, the frame counter only tells them how often they should read.
+
+use std::{
+    ffi::CString,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    os::fd::FromRawFd,
+    path::PathBuf,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use memmap2::{Mmap, MmapMut};
+use snafu::{ensure, ResultExt, Snafu};
+
+pub const MAGIC: [u8; 8] = *b"BRKWATER";
+pub const VERSION: u32 = 1;
+pub const HEADER_SIZE: usize = 64;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid shared memory name {name:?}"))]
+    InvalidSharedMemoryName { name: String },
+
+    #[snafu(display("Failed to open shared memory {shared_memory}"))]
+    OpenSharedMemory {
+        source: std::io::Error,
+        shared_memory: SharedMemoryLocation,
+    },
+
+    #[snafu(display("Failed to resize shared memory {shared_memory} to {size} bytes"))]
+    ResizeSharedMemory {
+        source: std::io::Error,
+        shared_memory: SharedMemoryLocation,
+        size: usize,
+    },
+
+    #[snafu(display("Failed to map shared memory {shared_memory}"))]
+    MapSharedMemory {
+        source: std::io::Error,
+        shared_memory: SharedMemoryLocation,
+    },
+
+    #[snafu(display("Shared memory {shared_memory} does not contain a breakwater framebuffer"))]
+    InvalidMagic { shared_memory: SharedMemoryLocation },
+
+    #[snafu(display(
+        "Shared memory {shared_memory} has version {version}, only version {VERSION} is supported"
+    ))]
+    UnsupportedVersion {
+        shared_memory: SharedMemoryLocation,
+        version: u32,
+    },
+
+    #[snafu(display("Shared memory {shared_memory} has unsupported pixel format {format}"))]
+    UnsupportedPixelFormat {
+        shared_memory: SharedMemoryLocation,
+        format: u32,
+    },
+
+    #[snafu(display(
+        "Shared memory {shared_memory} has {size} bytes, which is too small for {width}x{height} pixels"
+    ))]
+    SharedMemoryTooSmall {
+        shared_memory: SharedMemoryLocation,
+        size: usize,
+        width: usize,
+        height: usize,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PixelFormat {
+    /// 4 bytes per pixel: red, green, blue and one unused byte, the format of the [`crate::framebuffer::FrameBuffer`]
+    Rgb0 = 1,
+}
+
+/// Where the shared memory lives
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SharedMemoryLocation {
+    /// Named POSIX shared memory segment, as created by `shm_open`
+    Posix(String),
+    File(PathBuf),
+}
+
+impl Display for SharedMemoryLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SharedMemoryLocation::Posix(name) => write!(f, "shm:{name}"),
+            SharedMemoryLocation::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for SharedMemoryLocation {
+    type Err = Error;
+
+    /// Parses `shm:<name>` as named POSIX shared memory segment, everything else as path of a file
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.strip_prefix("shm:") {
+            Some(name) => {
+                let name = name.trim_start_matches('/');
+                ensure!(
+                    !name.is_empty() && !name.contains(['/', '\0']),
+                    InvalidSharedMemoryNameSnafu { name }
+                );
+                Ok(SharedMemoryLocation::Posix(name.to_string()))
+            }
+            None => Ok(SharedMemoryLocation::File(PathBuf::from(input))),
+        }
+    }
+}
+
+impl SharedMemoryLocation {
+    fn open(&self, writable: bool) -> Result<File, Error> {
+        let file = match self {
+            SharedMemoryLocation::Posix(name) => {
+                // Checked when parsing the location
+                let name = CString::new(format!("/{name}")).expect("name contains no NUL bytes");
+                let flags = if writable {
+                    libc::O_RDWR | libc::O_CREAT
+                } else {
+                    libc::O_RDONLY
+                };
+                let fd = unsafe { libc::shm_open(name.as_ptr(), flags, 0o644 as libc::c_uint) };
+                if fd < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(unsafe { File::from_raw_fd(fd) })
+                }
+            }
+            SharedMemoryLocation::File(path) => OpenOptions::new()
+                .read(true)
+                .write(writable)
+                .create(writable)
+                .truncate(false)
+                .open(path),
+        };
+
+        file.context(OpenSharedMemorySnafu {
+            shared_memory: self.clone(),
+        })
+    }
+}
+
+#[repr(C)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    header_size: u32,
+    width: u32,
+    height: u32,
+    format: u32,
+    _reserved: u32,
+    frame: AtomicU64,
+}
+
+const _: () = assert!(std::mem::size_of::<Header>() <= HEADER_SIZE);
+
+/// Writing side of the shared memory, owned by the [`crate::framebuffer::FrameBuffer`]
+pub struct SharedMemory {
+    location: SharedMemoryLocation,
+    mmap: MmapMut,
+}
+
+impl SharedMemory {
+    /// Creates the shared memory (or takes over an existing one), writes the header and clears all pixels
+    pub fn create(
+        location: SharedMemoryLocation,
+        width: usize,
+        height: usize,
+    ) -> Result<Self, Error> {
+        let file = location.open(true)?;
+        let size = HEADER_SIZE + width * height * std::mem::size_of::<u32>();
+        file.set_len(size as u64).context(ResizeSharedMemorySnafu {
+            shared_memory: location.clone(),
+            size,
+        })?;
+        let mut mmap = unsafe { MmapMut::map_mut(&file) }.context(MapSharedMemorySnafu {
+            shared_memory: location.clone(),
+        })?;
+
+        mmap.fill(0);
+        unsafe {
+            (mmap.as_mut_ptr() as *mut Header).write(Header {
+                magic: MAGIC,
+                version: VERSION,
+                header_size: HEADER_SIZE as u32,
+                width: width as u32,
+                height: height as u32,
+                format: PixelFormat::Rgb0 as u32,
+                _reserved: 0,
+                frame: AtomicU64::new(0),
+            });
+        }
+
+        Ok(SharedMemory { location, mmap })
+    }
+
+    pub fn location(&self) -> &SharedMemoryLocation {
+        &self.location
+    }
+
+    pub fn size(&self) -> usize {
+        self.mmap.len()
+    }
+
+    /// Start of the pixels, there are `width * height` of them
+    pub(crate) fn pixels_ptr(&mut self) -> *mut u32 {
+        unsafe { self.mmap.as_mut_ptr().add(HEADER_SIZE) as *mut u32 }
+    }
+
+    /// Tells the readers that a new frame is done
+    pub fn increment_frame(&self) {
+        let header = unsafe { &*(self.mmap.as_ptr() as *const Header) };
+        header.frame.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Read-only access to a [`crate::framebuffer::FrameBuffer`] placed in shared memory by another process
+pub struct SharedMemoryReader {
+    mmap: Mmap,
+    width: usize,
+    height: usize,
+}
+
+impl SharedMemoryReader {
+    pub fn open(location: SharedMemoryLocation) -> Result<Self, Error> {
+        let file = location.open(false)?;
+        let mmap = unsafe { Mmap::map(&file) }.context(MapSharedMemorySnafu {
+            shared_memory: location.clone(),
+        })?;
+
+        ensure!(
+            mmap.len() >= HEADER_SIZE && mmap[0..8] == MAGIC,
+            InvalidMagicSnafu {
+                shared_memory: location
+            }
+        );
+        let header = unsafe { &*(mmap.as_ptr() as *const Header) };
+        ensure!(
+            header.version == VERSION,
+            UnsupportedVersionSnafu {
+                shared_memory: location,
+                version: header.version
+            }
+        );
+        ensure!(
+            header.format == PixelFormat::Rgb0 as u32,
+            UnsupportedPixelFormatSnafu {
+                shared_memory: location,
+                format: header.format
+            }
+        );
+        let (width, height) = (header.width as usize, header.height as usize);
+        ensure!(
+            mmap.len() >= HEADER_SIZE + width * height * std::mem::size_of::<u32>(),
+            SharedMemoryTooSmallSnafu {
+                shared_memory: location,
+                size: mmap.len(),
+                width,
+                height,
+            }
+        );
+
+        Ok(SharedMemoryReader {
+            mmap,
+            width,
+            height,
+        })
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.mmap.as_ptr() as *const Header) }
+    }
+
+    pub fn get_width(&self) -> usize {
+        self.width
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get_format(&self) -> PixelFormat {
+        PixelFormat::Rgb0
+    }
+
+    /// Number of frames done so far
+    pub fn get_frame(&self) -> u64 {
+        self.header().frame.load(Ordering::Acquire)
+    }
+
+    /// All pixels row by row. They are changed by the writing process while you read them.
+    pub fn get_pixels(&self) -> &[u32] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self.mmap.as_ptr().add(HEADER_SIZE) as *const u32,
+                self.width * self.height,
+            )
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<u32> {
+        if x < self.width && y < self.height {
+            Some(self.get_pixels()[x + y * self.width])
+        } else {
+            None
+        }
+    }
+}
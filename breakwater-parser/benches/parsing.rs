@@ -69,6 +69,15 @@ fn invoke_benchmark(
             .iter(|| invoke_simple_implementation(input, &fb));
     });
 
+    c_group.bench_with_input("Simple (padded framebuffer)", &commands, |b, input| {
+        let fb = Arc::new(FrameBuffer::new_padded(
+            FRAMEBUFFER_WIDTH,
+            FRAMEBUFFER_HEIGHT,
+        ));
+        b.to_async(tokio::runtime::Runtime::new().expect("Failed to start tokio runtime"))
+            .iter(|| invoke_simple_implementation(input, &fb));
+    });
+
     // c_group.bench_with_input("Assembler", &commands, |b, input| {
     //     let fb = Arc::new(FrameBuffer::new(FRAMEBUFFER_WIDTH, FRAMEBUFFER_HEIGHT));
     //     b.to_async(tokio::runtime::Runtime::new().expect("Failed to start tokio runtime"))
@@ -4,18 +4,110 @@ use std::{
 };
 
 use async_trait::async_trait;
-use breakwater_core::{framebuffer::FrameBuffer, HELP_TEXT};
+use breakwater_core::{
+    framebuffer::FrameBuffer, pixel_ownership::OwnerId, HELP_TEXT, MAX_CANVAS_NAME_LENGTH,
+};
 use snafu::ResultExt;
 use tokio::io::AsyncWriteExt;
 
 use crate::{Parser, ParserError};
 
-const PARSER_LOOKAHEAD: usize = "PX 1234 1234 rrggbbaa\n".len(); // Longest possible command
+const LONGEST_PX_COMMAND: usize = "PX 1234 1234 rrggbbaa\n".len();
+const LONGEST_CANVAS_COMMAND: usize = "CANVAS \n".len() + MAX_CANVAS_NAME_LENGTH;
+// Longest possible command
+const PARSER_LOOKAHEAD: usize = if LONGEST_PX_COMMAND > LONGEST_CANVAS_COMMAND {
+    LONGEST_PX_COMMAND
+} else {
+    LONGEST_CANVAS_COMMAND
+};
 
-#[derive(Default)]
 pub struct SimpleParser {
     connection_x_offset: usize,
     connection_y_offset: usize,
+    owner: OwnerId,
+    requested_canvas: Option<String>,
+    /// Number of pixels the parser is allowed to draw
+    pixel_budget: u64,
+    /// Stop parsing before the first pixel exceeding the budget instead of dropping it
+    pause_on_exhausted_pixel_budget: bool,
+    pixel_budget_exhausted: bool,
+    /// See [`SimpleParser::resume_offset`]
+    resume_offset: usize,
+}
+
+impl Default for SimpleParser {
+    fn default() -> Self {
+        SimpleParser {
+            connection_x_offset: 0,
+            connection_y_offset: 0,
+            owner: Default::default(),
+            requested_canvas: None,
+            pixel_budget: u64::MAX,
+            pause_on_exhausted_pixel_budget: false,
+            pixel_budget_exhausted: false,
+            resume_offset: 0,
+        }
+    }
+}
+
+impl SimpleParser {
+    /// Pixels drawn by this parser are attributed to the given owner (if the [`FrameBuffer`] tracks pixel ownership).
+    pub fn with_owner(owner: OwnerId) -> Self {
+        SimpleParser {
+            owner,
+            ..Default::default()
+        }
+    }
+
+    /// Parsing stops right after a `CANVAS <name>` command, so that the caller can switch to the requested
+    /// [`FrameBuffer`] before parsing the rest of the buffer.
+    pub fn take_requested_canvas(&mut self) -> Option<String> {
+        self.requested_canvas.take()
+    }
+
+    /// Limits the number of pixels the following calls to [`Parser::parse`] draw, unlimited by default.
+    /// Pixels exceeding the budget are dropped, or if `pause` is set, parsing stops right before the first of them.
+    pub fn set_pixel_budget(&mut self, pixels: u64, pause: bool) {
+        self.pixel_budget = pixels;
+        self.pause_on_exhausted_pixel_budget = pause;
+    }
+
+    /// The part of the budget that was not used up yet
+    pub fn pixel_budget(&self) -> u64 {
+        self.pixel_budget
+    }
+
+    /// Whether parsing stopped as the pixel budget was exhausted, see [`SimpleParser::set_pixel_budget`]
+    pub fn take_pixel_budget_exhausted(&mut self) -> bool {
+        std::mem::take(&mut self.pixel_budget_exhausted)
+    }
+
+    /// Where in the buffer passed to the last [`Parser::parse`] call parsing has to continue, i.e. the number of bytes
+    /// parsed. Unlike the return value of [`Parser::parse`] this is `0` only if nothing was parsed.
+    pub fn resume_offset(&self) -> usize {
+        self.resume_offset
+    }
+
+    /// Stops parsing right before the command starting at `command_start`
+    fn pause_at(&mut self, command_start: usize) -> usize {
+        self.resume_offset = command_start;
+        command_start.saturating_sub(1)
+    }
+
+    /// Takes a pixel about to be drawn from the budget and returns whether it can be drawn.
+    /// Returns `None` if parsing has to stop before the pixel.
+    #[inline(always)]
+    fn take_pixel(&mut self) -> Option<bool> {
+        if self.pixel_budget > 0 {
+            self.pixel_budget -= 1;
+            Some(true)
+        } else if self.pause_on_exhausted_pixel_budget {
+            self.pixel_budget_exhausted = true;
+            None
+        } else {
+            Some(false)
+        }
+    }
 }
 
 #[async_trait]
@@ -34,6 +126,7 @@ impl Parser for SimpleParser {
             let current_command =
                 unsafe { (buffer.as_ptr().add(i) as *const u64).read_unaligned() };
             if current_command & 0x00ff_ffff == string_to_number(b"PX \0\0\0\0\0") {
+                let command_start = i;
                 i += 3;
 
                 let (mut x, mut y, present) = parse_pixel_coordinates(buffer.as_ptr(), &mut i);
@@ -51,24 +144,34 @@ impl Parser for SimpleParser {
 
                         // Must be followed by 6 bytes RGB and newline or ...
                         if unsafe { *buffer.get_unchecked(i + 6) } == b'\n' {
+                            let Some(within_pixel_budget) = self.take_pixel() else {
+                                return Ok(self.pause_at(command_start));
+                            };
                             last_byte_parsed = i + 6;
                             i += 7; // We can advance one byte more than normal as we use continue and therefore not get incremented at the end of the loop
 
                             let rgba: u32 = simd_unhex(unsafe { buffer.as_ptr().add(i - 7) });
 
-                            fb.set(x, y, rgba & 0x00ff_ffff);
+                            if within_pixel_budget {
+                                fb.set_with_owner(x, y, rgba & 0x00ff_ffff, self.owner);
+                            }
                             continue;
                         }
 
                         // ... or must be followed by 8 bytes RGBA and newline
                         #[cfg(not(feature = "alpha"))]
                         if unsafe { *buffer.get_unchecked(i + 8) } == b'\n' {
+                            let Some(within_pixel_budget) = self.take_pixel() else {
+                                return Ok(self.pause_at(command_start));
+                            };
                             last_byte_parsed = i + 8;
                             i += 9; // We can advance one byte more than normal as we use continue and therefore not get incremented at the end of the loop
 
                             let rgba: u32 = simd_unhex(unsafe { buffer.as_ptr().add(i - 9) });
 
-                            fb.set(x, y, rgba & 0x00ff_ffff);
+                            if within_pixel_budget {
+                                fb.set_with_owner(x, y, rgba & 0x00ff_ffff, self.owner);
+                            }
                             continue;
                         }
                         #[cfg(feature = "alpha")]
@@ -83,6 +186,9 @@ impl Parser for SimpleParser {
                             if alpha == 0 || x >= fb.get_width() || y >= fb.get_height() {
                                 continue;
                             }
+                            let Some(within_pixel_budget) = self.take_pixel() else {
+                                return Ok(self.pause_at(command_start));
+                            };
 
                             let alpha_comp = 0xff - alpha;
                             let current = fb.get_unchecked(x, y);
@@ -94,12 +200,17 @@ impl Parser for SimpleParser {
                             let g: u32 = (((current >> 16) & 0xff) * alpha_comp + g * alpha) / 0xff;
                             let b: u32 = (((current >> 8) & 0xff) * alpha_comp + b * alpha) / 0xff;
 
-                            fb.set(x, y, r << 16 | g << 8 | b);
+                            if within_pixel_budget {
+                                fb.set_with_owner(x, y, r << 16 | g << 8 | b, self.owner);
+                            }
                             continue;
                         }
 
                         // ... for the efficient/lazy clients
                         if unsafe { *buffer.get_unchecked(i + 2) } == b'\n' {
+                            let Some(within_pixel_budget) = self.take_pixel() else {
+                                return Ok(self.pause_at(command_start));
+                            };
                             last_byte_parsed = i + 2;
                             i += 3; // We can advance one byte more than normal as we use continue and therefore not get incremented at the end of the loop
 
@@ -110,7 +221,9 @@ impl Parser for SimpleParser {
 
                             let rgba: u32 = base << 16 | base << 8 | base;
 
-                            fb.set(x, y, rgba);
+                            if within_pixel_budget {
+                                fb.set_with_owner(x, y, rgba, self.owner);
+                            }
 
                             continue;
                         }
@@ -153,15 +266,82 @@ impl Parser for SimpleParser {
                     self.connection_y_offset = y;
                     continue;
                 }
+            } else if current_command & 0xffff_ffff == string_to_number(b"WHO \0\0\0\0") {
+                i += 4;
+
+                let (x, y, present) = parse_pixel_coordinates(buffer.as_ptr(), &mut i);
+
+                // End of command to get the owner of a pixel
+                if present && unsafe { *buffer.get_unchecked(i) } == b'\n' {
+                    last_byte_parsed = i;
+                    i += 1;
+                    let x = x + self.connection_x_offset;
+                    let y = y + self.connection_y_offset;
+                    if let (Some(owner), Some(pixel_ownership)) =
+                        (fb.get_owner(x, y), fb.pixel_ownership())
+                    {
+                        let owner = match pixel_ownership.get_address(owner) {
+                            Some(address) => pixel_ownership.owner_label(address),
+                            None => "none".to_string(),
+                        };
+                        stream
+                            .write_all(
+                                format!(
+                                    "WHO {} {} {owner}\n",
+                                    x - self.connection_x_offset,
+                                    y - self.connection_y_offset,
+                                )
+                                .as_bytes(),
+                            )
+                            .await
+                            .context(crate::WriteToTcpSocketSnafu)?;
+                    }
+                    continue;
+                }
             } else if current_command & 0xffff_ffff == string_to_number(b"SIZE\0\0\0\0") {
                 i += 4;
                 last_byte_parsed = i - 1;
 
+                let (width, height) = fb.protected_regions().usable_size();
                 stream
-                    .write_all(format!("SIZE {} {}\n", fb.get_width(), fb.get_height()).as_bytes())
+                    .write_all(format!("SIZE {width} {height}\n").as_bytes())
                     .await
                     .context(crate::WriteToTcpSocketSnafu)?;
                 continue;
+            } else if current_command & 0xffff_ffff == string_to_number(b"CAPS\0\0\0\0") {
+                i += 4;
+                last_byte_parsed = i - 1;
+
+                let mut caps = "CAPS PX SIZE HELP OFFSET CAPS".to_string();
+                if fb.pixel_ownership().is_some() {
+                    caps += " WHO";
+                }
+                for (_, rect) in fb.protected_regions().list() {
+                    caps += &format!(" PROTECTED:{rect}");
+                }
+                caps += "\n";
+
+                stream
+                    .write_all(caps.as_bytes())
+                    .await
+                    .context(crate::WriteToTcpSocketSnafu)?;
+                continue;
+            } else if current_command & 0x00ff_ffff_ffff_ffff == string_to_number(b"CANVAS \0") {
+                i += 7;
+
+                let name_start = i;
+                let name_end = (name_start + MAX_CANVAS_NAME_LENGTH + 1).min(buffer.len());
+                if let Some(name_length) = buffer[name_start..name_end]
+                    .iter()
+                    .position(|byte| *byte == b'\n')
+                {
+                    i += name_length;
+                    last_byte_parsed = i;
+                    self.requested_canvas =
+                        Some(String::from_utf8_lossy(&buffer[name_start..i]).into_owned());
+                    self.resume_offset = last_byte_parsed + 1;
+                    return Ok(last_byte_parsed);
+                }
             } else if current_command & 0xffff_ffff == string_to_number(b"HELP\0\0\0\0") {
                 i += 4;
                 last_byte_parsed = i - 1;
@@ -176,6 +356,12 @@ impl Parser for SimpleParser {
             i += 1;
         }
 
+        // Every command is longer than a byte, so `0` means nothing was parsed
+        self.resume_offset = if last_byte_parsed == 0 {
+            0
+        } else {
+            last_byte_parsed + 1
+        };
         Ok(last_byte_parsed)
     }
 
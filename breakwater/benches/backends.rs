@@ -0,0 +1,108 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+const FRAMEBUFFER_WIDTH: usize = 1280;
+const FRAMEBUFFER_HEIGHT: usize = 720;
+const CONNECTIONS: usize = 8;
+
+/// Kills the server once the benchmark is done
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn compare_backends(c: &mut Criterion) {
+    // Every connection draws the whole drawing surface once per iteration
+    let commands: String = (0..FRAMEBUFFER_HEIGHT)
+        .flat_map(|y| (0..FRAMEBUFFER_WIDTH).map(move |x| format!("PX {x} {y} ff8000\n")))
+        .collect();
+
+    let mut c_group = c.benchmark_group("loopback_draw_commands");
+    c_group.throughput(Throughput::Bytes((commands.len() * CONNECTIONS) as u64));
+    c_group.sample_size(10);
+
+    for (name, port_offset, io_uring) in [("tokio", 0, false), ("io_uring", 1, true)] {
+        let port = 41234 + port_offset;
+        let _server = start_server(port_offset, io_uring);
+        let mut connections: Vec<_> = (0..CONNECTIONS).map(|_| connect(port)).collect();
+
+        c_group.bench_function(name, |b| {
+            b.iter(|| {
+                thread::scope(|scope| {
+                    for connection in &mut connections {
+                        let commands = &commands;
+                        scope.spawn(move || draw(connection, commands));
+                    }
+                })
+            })
+        });
+    }
+}
+
+fn start_server(port_offset: u16, io_uring: bool) -> Server {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_breakwater"));
+    command
+        .args([
+            "--listen-address",
+            &format!("127.0.0.1:{}", 41234 + port_offset),
+        ])
+        .args([
+            "--prometheus-listen-address",
+            &format!("127.0.0.1:{}", 49100 + port_offset),
+        ])
+        .args(["--width", &FRAMEBUFFER_WIDTH.to_string()])
+        .args(["--height", &FRAMEBUFFER_HEIGHT.to_string()])
+        .arg("--disable-statistics-save-file")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if cfg!(feature = "vnc") {
+        command.args(["--vnc-port", &(45900 + port_offset).to_string()]);
+    }
+    if io_uring {
+        command.arg("--io-uring");
+    }
+
+    Server(command.spawn().expect("Failed to start breakwater"))
+}
+
+fn connect(port: u16) -> BufReader<TcpStream> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => return BufReader::new(stream),
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            Err(err) => panic!("Failed to connect to breakwater: {err}"),
+        }
+    }
+}
+
+/// Waits for the response to a trailing `SIZE`, so that the server processed all commands
+fn draw(connection: &mut BufReader<TcpStream>, commands: &str) {
+    let stream = connection.get_mut();
+    stream
+        .write_all(commands.as_bytes())
+        .expect("Failed to send commands");
+    stream
+        .write_all(b"SIZE\n")
+        .expect("Failed to send commands");
+
+    let mut response = String::new();
+    connection
+        .read_line(&mut response)
+        .expect("Failed to read response");
+    assert!(response.starts_with("SIZE "), "{response:?}");
+}
+
+criterion_group!(benches, compare_backends);
+criterion_main!(benches);
@@ -0,0 +1,109 @@
+use std::{io, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// Delay before the first retry once accepting connections runs out of resources
+const MIN_DELAY: Duration = Duration::from_millis(5);
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// Why accepting a new client connection failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AcceptError {
+    /// The process or the system ran out of file descriptors (`EMFILE` or `ENFILE`)
+    FileDescriptorLimit,
+    /// The kernel ran out of memory for socket buffers
+    OutOfMemory,
+    /// E.g. the client closed the connection before it was accepted
+    Other,
+}
+
+impl AcceptError {
+    /// Returns `None` if the listening socket itself is broken, so that retrying is pointless
+    pub fn from_io_error(err: &io::Error) -> Option<Self> {
+        match err.raw_os_error() {
+            Some(libc::EMFILE | libc::ENFILE) => Some(AcceptError::FileDescriptorLimit),
+            Some(libc::ENOBUFS | libc::ENOMEM) => Some(AcceptError::OutOfMemory),
+            Some(libc::EBADF | libc::EFAULT | libc::EINVAL | libc::ENOTSOCK | libc::EOPNOTSUPP) => {
+                None
+            }
+            _ => Some(AcceptError::Other),
+        }
+    }
+
+    /// Used as label of the Prometheus metrics
+    pub fn label(&self) -> &'static str {
+        match self {
+            AcceptError::FileDescriptorLimit => "file-descriptor-limit",
+            AcceptError::OutOfMemory => "out-of-memory",
+            AcceptError::Other => "other",
+        }
+    }
+}
+
+/// Exponential backoff for accept loops (and the receive loop of the UDP server), so that they don't spin while the
+/// process is out of file descriptors or memory. Other errors only concern a single connection and are retried right
+/// away.
+#[derive(Debug, Default)]
+pub struct AcceptBackoff {
+    /// `None` while accepting works
+    delay: Option<Duration>,
+    /// Whether the current failures include running out of file descriptors
+    file_descriptor_limit: bool,
+}
+
+impl AcceptBackoff {
+    /// Returns how long to wait before accepting the next connection
+    pub fn failed(&mut self, error: AcceptError) -> Duration {
+        match error {
+            AcceptError::FileDescriptorLimit | AcceptError::OutOfMemory => {
+                self.file_descriptor_limit |= error == AcceptError::FileDescriptorLimit;
+                let delay = self
+                    .delay
+                    .map_or(MIN_DELAY, |delay| (delay * 2).min(MAX_DELAY));
+                self.delay = Some(delay);
+                delay
+            }
+            AcceptError::Other => Duration::ZERO,
+        }
+    }
+
+    /// Returns whether the accept loop was backing off before, i.e. it just recovered
+    pub fn succeeded(&mut self) -> bool {
+        self.file_descriptor_limit = false;
+        self.delay.take().is_some()
+    }
+
+    /// Whether the listener ran out of file descriptors since it last accepted a connection
+    pub fn file_descriptor_limit_reached(&self) -> bool {
+        self.file_descriptor_limit
+    }
+
+    pub fn is_backing_off(&self) -> bool {
+        self.delay.is_some()
+    }
+}
+
+/// Raises the soft limit of open files to the hard limit, as every client connection needs a file descriptor.
+///
+/// Returns the previous and the new soft limit.
+pub fn raise_open_files_limit() -> io::Result<(libc::rlim_t, libc::rlim_t)> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid rlimit to write into
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let previous = limit.rlim_cur;
+    if previous < limit.rlim_max {
+        limit.rlim_cur = limit.rlim_max;
+        // SAFETY: `limit` is a valid rlimit, the kernel checks that we are allowed to set it
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok((previous, limit.rlim_cur))
+}
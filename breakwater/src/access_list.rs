@@ -0,0 +1,147 @@
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::RwLock,
+};
+
+use ipnet::IpNet;
+use snafu::{ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to read access list from {path:?}"))]
+    ReadAccessListFile {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Invalid line {line_number} in access list {path:?}: {reason}"))]
+    ParseAccessList {
+        path: PathBuf,
+        line_number: usize,
+        reason: String,
+    },
+}
+
+/// IP ranges that are allowed or denied to connect, loaded from a file.
+///
+/// Every line of the file is either `allow <cidr>` or `deny <cidr>`, e.g. `deny 192.0.2.0/24` or
+/// `allow 2001:db8::/32`. A plain IP address matches only itself. Everything after `#` is a comment.
+/// Denied ranges take precedence. If there is at least one allowed range, only IPs within the allowed ranges can
+/// connect, otherwise everyone not denied can.
+#[derive(Debug)]
+pub struct AccessList {
+    path: PathBuf,
+    rules: RwLock<AccessRules>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AccessRules {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    pub fn load(path: PathBuf) -> Result<Self, Error> {
+        let rules = read_rules(&path)?;
+        Ok(AccessList {
+            path,
+            rules: RwLock::new(rules),
+        })
+    }
+
+    /// Reads the file again. If that fails the previous rules stay active.
+    pub fn reload(&self) -> Result<(), Error> {
+        let rules = read_rules(&self.path)?;
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Expects the canonical IP, so IPv4 clients as IPv4 addresses and not as IPv4-mapped IPv6 addresses
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.rules.read().unwrap().is_allowed(ip)
+    }
+
+    /// The active rules in the format of the file
+    pub fn list(&self) -> Vec<String> {
+        let rules = self.rules.read().unwrap();
+        rules
+            .allow
+            .iter()
+            .map(|net| format!("allow {net}"))
+            .chain(rules.deny.iter().map(|net| format!("deny {net}")))
+            .collect()
+    }
+}
+
+impl AccessRules {
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+impl FromStr for AccessRules {
+    type Err = (usize, String);
+
+    /// On error returns the (1-based) line number and the reason
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut rules = AccessRules::default();
+
+        for (index, line) in input.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = |reason: String| (index + 1, reason);
+            let Some((action, net)) = line.split_once(char::is_whitespace) else {
+                return Err(invalid(format!(
+                    "expected \"allow <cidr>\" or \"deny <cidr>\", got {line:?}"
+                )));
+            };
+            let net = net.trim();
+            let net = parse_ip_range(net).map_err(invalid)?;
+            match action {
+                "allow" => rules.allow.push(net),
+                "deny" => rules.deny.push(net),
+                _ => {
+                    return Err(invalid(format!(
+                        "unknown action {action:?}, expected allow or deny"
+                    )))
+                }
+            }
+        }
+
+        Ok(rules)
+    }
+}
+
+/// Parses a CIDR such as `192.0.2.0/24`, a plain IP address matches only itself
+pub fn parse_ip_range(input: &str) -> Result<IpNet, String> {
+    match input.parse::<IpNet>() {
+        Ok(net) => Ok(net),
+        Err(_) => input
+            .parse::<IpAddr>()
+            .map(IpNet::from)
+            .map_err(|_| format!("invalid IP range {input:?}")),
+    }
+}
+
+fn read_rules(path: &Path) -> Result<AccessRules, Error> {
+    let content = std::fs::read_to_string(path).context(ReadAccessListFileSnafu { path })?;
+    content
+        .parse()
+        .map_err(|(line_number, reason)| Error::ParseAccessList {
+            path: path.to_path_buf(),
+            line_number,
+            reason,
+        })
+}
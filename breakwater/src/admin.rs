@@ -0,0 +1,244 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use breakwater_core::{framebuffer::FrameBuffer, protected_regions::Rect};
+use log::{debug, info, warn};
+use snafu::{ResultExt, Snafu};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    access_list::AccessList,
+    history::{CanvasHistory, TimeLapseFormat},
+    pixel_rate_limit::PixelRateLimiter,
+};
+
+const ADMIN_HELP_TEXT: &str = "\
+Breakwater admin interface. Every command is answered with zero or more lines followed by either `OK` or `ERROR <reason>`.
+Available commands:
+HELP: Show this help
+HISTORY LIST: List the ids (unix timestamps) of all stored canvas snapshots
+HISTORY ROLLBACK <id>: Overwrite the canvas with the given snapshot
+HISTORY EXPORT <gif|apng|png-sequence> <path> [fps]: Export all snapshots as time-lapse. For png-sequence the path is a directory
+PROTECTED LIST: List the protected regions clients can not draw on as `<id> <x>,<y>,<width>,<height>`
+PROTECTED ADD <x>,<y>,<width>,<height>: Protect the given region, prints the id of the region
+PROTECTED REMOVE <id>: Remove the protection of the given region
+ACCESS LIST: List the active rules of the access list as `allow <cidr>` or `deny <cidr>`
+ACCESS RELOAD: Read the access list file again, affects only new connections
+PIXELRATE GET: Print the maximum number of pixels per second every client can draw, or `unlimited`
+PIXELRATE SET <pixels_per_s|unlimited>: Change the maximum number of pixels per second every client can draw
+";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to bind to admin listen address {listen_address:?}"))]
+    BindToAdminListenAddress {
+        source: std::io::Error,
+        listen_address: String,
+    },
+
+    #[snafu(display("Failed to accept new admin connection"))]
+    AcceptNewAdminConnection { source: std::io::Error },
+}
+
+/// Everything that can be changed or queried via the admin interface
+#[derive(Clone)]
+pub struct AdminContext {
+    pub fb: Arc<FrameBuffer>,
+    pub history: Option<Arc<CanvasHistory>>,
+    pub access_list: Option<Arc<AccessList>>,
+    pub pixel_rate_limiter: Arc<PixelRateLimiter>,
+}
+
+/// Line based text protocol to administrate breakwater at runtime.
+/// There is no authentication, so only listen on trusted interfaces!
+pub struct AdminServer {
+    listener: TcpListener,
+    context: AdminContext,
+}
+
+impl AdminServer {
+    pub async fn new(listen_address: &str, context: AdminContext) -> Result<Self, Error> {
+        let listener = TcpListener::bind(listen_address)
+            .await
+            .context(BindToAdminListenAddressSnafu { listen_address })?;
+        info!("Started admin interface on {listen_address}");
+
+        Ok(Self { listener, context })
+    }
+
+    pub async fn start(&self) -> Result<(), Error> {
+        loop {
+            let (socket, socket_addr) = self
+                .listener
+                .accept()
+                .await
+                .context(AcceptNewAdminConnectionSnafu)?;
+
+            let context = self.context.clone();
+            tokio::spawn(async move {
+                if let Err(err) = handle_admin_connection(socket, socket_addr, context).await {
+                    warn!("Admin connection from {socket_addr} failed: {err}");
+                }
+            });
+        }
+    }
+}
+
+async fn handle_admin_connection(
+    stream: TcpStream,
+    socket_addr: SocketAddr,
+    context: AdminContext,
+) -> Result<(), std::io::Error> {
+    info!("Admin connected from {socket_addr}");
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        debug!("Admin command from {socket_addr}: {line}");
+
+        let response = match execute(line, &context).await {
+            Ok(output) => format!("{output}OK\n"),
+            Err(reason) => format!("ERROR {reason}\n"),
+        };
+        writer.write_all(response.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+/// Returns the output lines of the command (each terminated with a newline) or the reason why it failed
+async fn execute(line: &str, context: &AdminContext) -> Result<String, String> {
+    let args = line.split_whitespace().collect::<Vec<_>>();
+
+    match args.as_slice() {
+        ["HELP"] => Ok(ADMIN_HELP_TEXT.to_string()),
+        ["HISTORY", args @ ..] => {
+            let history = context
+                .history
+                .clone()
+                .ok_or("canvas history is not enabled")?;
+            execute_history(args, history).await
+        }
+        ["PROTECTED", args @ ..] => execute_protected(args, &context.fb),
+        ["ACCESS", args @ ..] => {
+            let access_list = context
+                .access_list
+                .as_ref()
+                .ok_or("no access list is configured")?;
+            execute_access(args, access_list)
+        }
+        ["PIXELRATE", args @ ..] => execute_pixel_rate(args, &context.pixel_rate_limiter),
+        _ => Err(format!("unknown command {line:?}, try HELP")),
+    }
+}
+
+fn execute_protected(args: &[&str], fb: &FrameBuffer) -> Result<String, String> {
+    let protected_regions = fb.protected_regions();
+    match args {
+        ["LIST"] => Ok(protected_regions
+            .list()
+            .into_iter()
+            .map(|(id, rect)| format!("{id} {rect}\n"))
+            .collect()),
+        ["ADD", rect] => {
+            let rect = rect.parse::<Rect>().map_err(|err| err.to_string())?;
+            Ok(format!("{}\n", protected_regions.add(rect)))
+        }
+        ["REMOVE", id] => {
+            if protected_regions.remove(parse_arg(id)?) {
+                Ok(String::new())
+            } else {
+                Err(format!("there is no protected region with id {id}"))
+            }
+        }
+        _ => Err("invalid PROTECTED command, try HELP".to_string()),
+    }
+}
+
+fn execute_access(args: &[&str], access_list: &AccessList) -> Result<String, String> {
+    match args {
+        ["LIST"] => Ok(access_list
+            .list()
+            .into_iter()
+            .map(|rule| format!("{rule}\n"))
+            .collect()),
+        ["RELOAD"] => access_list
+            .reload()
+            .map(|()| String::new())
+            .map_err(|err| err.to_string()),
+        _ => Err("invalid ACCESS command, try HELP".to_string()),
+    }
+}
+
+fn execute_pixel_rate(
+    args: &[&str],
+    pixel_rate_limiter: &PixelRateLimiter,
+) -> Result<String, String> {
+    match args {
+        ["GET"] => Ok(match pixel_rate_limiter.pixels_per_s() {
+            Some(pixels_per_s) => format!("{pixels_per_s}\n"),
+            None => "unlimited\n".to_string(),
+        }),
+        ["SET", "unlimited"] => {
+            pixel_rate_limiter.set_pixels_per_s(None);
+            Ok(String::new())
+        }
+        ["SET", pixels_per_s] => {
+            let pixels_per_s: u64 = parse_arg(pixels_per_s)?;
+            if pixels_per_s == 0 {
+                return Err("the limit must be at least 1 pixel per second".to_string());
+            }
+            pixel_rate_limiter.set_pixels_per_s(Some(pixels_per_s));
+            Ok(String::new())
+        }
+        _ => Err("invalid PIXELRATE command, try HELP".to_string()),
+    }
+}
+
+async fn execute_history(args: &[&str], history: Arc<CanvasHistory>) -> Result<String, String> {
+    match args {
+        ["LIST"] => Ok(history
+            .list()
+            .into_iter()
+            .map(|id| format!("{id}\n"))
+            .collect()),
+        ["ROLLBACK", id] => {
+            let id = parse_arg(id)?;
+            run_blocking(move || history.rollback(id)).await
+        }
+        ["EXPORT", format, path, fps @ ..] => {
+            let format = format
+                .parse::<TimeLapseFormat>()
+                .map_err(|err| err.to_string())?;
+            let path = PathBuf::from(path);
+            let fps = match fps {
+                [] => 10,
+                [fps] => parse_arg(fps)?,
+                _ => return Err("too many arguments".to_string()),
+            };
+            run_blocking(move || history.export(format, path, fps)).await
+        }
+        _ => Err("invalid HISTORY command, try HELP".to_string()),
+    }
+}
+
+fn parse_arg<T: std::str::FromStr>(arg: &str) -> Result<T, String> {
+    arg.parse().map_err(|_| format!("invalid argument {arg:?}"))
+}
+
+/// Runs the (potentially slow) operation without blocking the runtime
+async fn run_blocking<E: std::fmt::Display + Send + 'static>(
+    operation: impl FnOnce() -> Result<(), E> + Send + 'static,
+) -> Result<String, String> {
+    match tokio::task::spawn_blocking(operation).await {
+        Ok(Ok(())) => Ok(String::new()),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
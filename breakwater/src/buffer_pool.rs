@@ -0,0 +1,251 @@
+use std::{
+    ops::{Deref, DerefMut},
+    pin::pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::Notify;
+
+/// Size of the buffer every connection starts with
+pub const MIN_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Idle buffers kept per size for the next connections, further ones are freed
+const MAX_IDLE_BUFFERS_PER_SIZE: usize = 32;
+
+/// A connection that used less than a quarter of its buffer this many reads in a row gets a smaller one
+const SHRINK_AFTER_SMALL_READS: u32 = 16;
+
+/// Network buffers shared by all connections of the process.
+///
+/// The buffer sizes double from [`MIN_BUFFER_SIZE`] up to the maximum size, buffers that are no longer needed are kept
+/// for reuse. If there is a memory budget, new connections wait until enough memory is released and buffers don't
+/// grow beyond it.
+pub struct BufferPool {
+    sizes: Vec<usize>,
+    /// `None` is unlimited
+    memory_budget: Option<usize>,
+    state: Mutex<PoolState>,
+    /// Notified whenever a buffer is returned
+    released: Notify,
+    /// Connections waiting for their first buffer
+    waiting: AtomicUsize,
+}
+
+#[derive(Default)]
+struct PoolState {
+    /// Bytes of all buffers, including the idle ones
+    allocated: usize,
+    idle_bytes: usize,
+    /// Idle buffers per size
+    idle: Vec<Vec<Vec<u8>>>,
+}
+
+/// Buffer handed out by the [`BufferPool`], it is returned to the pool when dropped
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    /// Index of the size of the buffer
+    size: usize,
+    pool: Arc<BufferPool>,
+}
+
+/// Network buffer of a single connection. It grows while the client fills it with every read and shrinks again once
+/// the client sends less.
+pub struct ConnectionBuffer {
+    buffer: PooledBuffer,
+    small_reads: u32,
+    grow: bool,
+}
+
+impl BufferPool {
+    pub fn new(max_buffer_size: usize, memory_budget: Option<usize>) -> Self {
+        let mut sizes = Vec::new();
+        let mut size = MIN_BUFFER_SIZE;
+        while size < max_buffer_size {
+            sizes.push(size);
+            size *= 2;
+        }
+        sizes.push(max_buffer_size);
+
+        BufferPool {
+            state: Mutex::new(PoolState {
+                idle: vec![Vec::new(); sizes.len()],
+                ..Default::default()
+            }),
+            sizes,
+            memory_budget,
+            released: Notify::new(),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns a buffer of the smallest size, waiting while the memory budget is exhausted
+    pub async fn acquire(self: &Arc<Self>) -> PooledBuffer {
+        loop {
+            // Registered before checking, so that a buffer released in between is not missed
+            let mut released = pin!(self.released.notified());
+            released.as_mut().enable();
+            if let Some(buffer) = self.try_acquire(0) {
+                return buffer;
+            }
+
+            self.waiting.fetch_add(1, Ordering::Relaxed);
+            let _waiting = WaitingGuard(&self.waiting);
+            released.await;
+        }
+    }
+
+    /// Returns a buffer of the given size index, or `None` if it would exceed the memory budget
+    fn try_acquire(self: &Arc<Self>, size: usize) -> Option<PooledBuffer> {
+        let bytes = self.sizes[size];
+        let mut state = self.state.lock().unwrap();
+        let buffer = match state.idle[size].pop() {
+            Some(buffer) => {
+                state.idle_bytes -= bytes;
+                buffer
+            }
+            None => {
+                if let Some(memory_budget) = self.memory_budget {
+                    // Idle buffers of other sizes make room first
+                    while state.allocated + bytes > memory_budget {
+                        let idle = state.idle.iter_mut().find_map(Vec::pop)?;
+                        state.allocated -= idle.len();
+                        state.idle_bytes -= idle.len();
+                    }
+                }
+                state.allocated += bytes;
+                drop(state);
+                vec![0; bytes]
+            }
+        };
+
+        Some(PooledBuffer {
+            buffer,
+            size,
+            pool: Arc::clone(self),
+        })
+    }
+
+    fn release(&self, buffer: Vec<u8>, size: usize) {
+        let mut state = self.state.lock().unwrap();
+        if state.idle[size].len() < MAX_IDLE_BUFFERS_PER_SIZE {
+            state.idle_bytes += buffer.len();
+            state.idle[size].push(buffer);
+        } else {
+            state.allocated -= buffer.len();
+        }
+        drop(state);
+        self.released.notify_waiters();
+    }
+
+    /// Bytes of all buffers, including the idle ones kept for reuse
+    pub fn allocated_bytes(&self) -> usize {
+        self.state.lock().unwrap().allocated
+    }
+
+    pub fn in_use_bytes(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.allocated - state.idle_bytes
+    }
+
+    pub fn memory_budget(&self) -> Option<usize> {
+        self.memory_budget
+    }
+
+    /// Connections waiting for the memory budget to allow their buffer
+    pub fn waiting_connections(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+}
+
+/// Also counts the connection as no longer waiting if it is closed while waiting
+struct WaitingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool
+            .release(std::mem::take(&mut self.buffer), self.size);
+    }
+}
+
+impl ConnectionBuffer {
+    /// Waits while the memory budget is exhausted, so that the client can't send more data in the meantime
+    pub async fn new(pool: &Arc<BufferPool>) -> Self {
+        ConnectionBuffer {
+            buffer: pool.acquire().await,
+            small_reads: 0,
+            grow: false,
+        }
+    }
+
+    /// Call it after every read with the number of bytes read and the space there was in the buffer
+    pub fn record_read(&mut self, bytes_read: usize, space: usize) {
+        if bytes_read == space {
+            self.grow = true;
+            self.small_reads = 0;
+        } else if bytes_read * 4 < self.buffer.len() {
+            self.small_reads += 1;
+        } else {
+            self.small_reads = 0;
+        }
+    }
+
+    /// Switches to a bigger or smaller buffer according to the previous reads, keeping the first `keep` bytes.
+    /// `keep` must fit into the smallest buffer.
+    pub fn adapt(&mut self, keep: usize) {
+        let size = self.buffer.size;
+        let new_size = if std::mem::take(&mut self.grow) {
+            size + 1
+        } else if self.small_reads >= SHRINK_AFTER_SMALL_READS {
+            self.small_reads = 0;
+            size.saturating_sub(1)
+        } else {
+            return;
+        };
+        if new_size == size || new_size == self.buffer.pool.sizes.len() {
+            return;
+        }
+
+        // The connection keeps its buffer if a bigger one would exceed the memory budget
+        if let Some(mut buffer) = self.buffer.pool.try_acquire(new_size) {
+            buffer[..keep].copy_from_slice(&self.buffer[..keep]);
+            self.buffer = buffer;
+        }
+    }
+}
+
+impl Deref for ConnectionBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for ConnectionBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
@@ -0,0 +1,87 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+use breakwater_core::{framebuffer::FrameBuffer, MAX_CANVAS_NAME_LENGTH};
+use tokio::sync::mpsc;
+
+use crate::{listener::ListenAddress, statistics::StatisticsEvent};
+
+/// What a client connection draws on
+#[derive(Clone)]
+pub struct Canvas {
+    pub fb: Arc<FrameBuffer>,
+    pub statistics_tx: mpsc::Sender<StatisticsEvent>,
+}
+
+/// All canvases of the process by name, clients can switch between them using the `CANVAS` command
+pub type Canvases = HashMap<String, Canvas>;
+
+/// Additional canvas served by the same breakwater process, with its own [`breakwater_core::framebuffer::FrameBuffer`],
+/// statistics and VNC server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CanvasConfig {
+    pub name: String,
+    /// If not set, the canvas can only be reached using the `CANVAS` command
+    pub listen_address: Option<ListenAddress>,
+    pub width: usize,
+    pub height: usize,
+    /// If not set, no VNC server is started for this canvas
+    pub vnc_port: Option<u16>,
+    /// Defaults to `statistics-<name>.json`
+    pub statistics_save_file: Option<String>,
+}
+
+impl FromStr for CanvasConfig {
+    type Err = String;
+
+    /// Parses comma separated `key=value` pairs, e.g.
+    /// `name=kids,listen=[::]:1235,width=640,height=480,vnc-port=5901,statistics-save-file=kids.json`.
+    /// Only `name` is required.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut listen_address = None;
+        let mut width = 1280;
+        let mut height = 720;
+        let mut vnc_port = None;
+        let mut statistics_save_file = None;
+
+        for pair in input.split(',') {
+            let Some((key, value)) = pair.split_once('=') else {
+                return Err(format!(
+                    "invalid canvas setting {pair:?}, expected key=value"
+                ));
+            };
+            let invalid = || format!("invalid value {value:?} for canvas setting {key:?}");
+            match key.trim() {
+                "name" => name = Some(value.to_string()),
+                "listen" => listen_address = Some(value.parse()?),
+                "width" => width = value.parse().map_err(|_| invalid())?,
+                "height" => height = value.parse().map_err(|_| invalid())?,
+                "vnc-port" => vnc_port = Some(value.parse().map_err(|_| invalid())?),
+                "statistics-save-file" => statistics_save_file = Some(value.to_string()),
+                _ => return Err(format!("unknown canvas setting {key:?}")),
+            }
+        }
+
+        let name = name.ok_or("canvas setting \"name\" is missing")?;
+        Ok(CanvasConfig {
+            name: parse_canvas_name(&name)?,
+            listen_address,
+            width,
+            height,
+            vnc_port,
+            statistics_save_file,
+        })
+    }
+}
+
+pub fn parse_canvas_name(name: &str) -> Result<String, String> {
+    if name.is_empty()
+        || name.len() > MAX_CANVAS_NAME_LENGTH
+        || name.contains(|char: char| char.is_whitespace() || char.is_control())
+    {
+        return Err(format!(
+            "invalid canvas name {name:?}, it must consist of 1 to {MAX_CANVAS_NAME_LENGTH} characters without whitespace"
+        ));
+    }
+    Ok(name.to_string())
+}
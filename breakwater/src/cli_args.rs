@@ -1,5 +1,18 @@
+use breakwater_core::{protected_regions::Rect, shared_memory::SharedMemoryLocation};
+
+#[cfg(feature = "vnc")]
+use crate::video_wall::VideoWallLayout;
+use crate::{
+    access_list::parse_ip_range,
+    canvas::{parse_canvas_name, CanvasConfig},
+    listener::ListenAddress,
+    output_buffer::{OutputOverflowPolicy, DEFAULT_OUTPUT_BUFFER_SIZE},
+    pixel_rate_limit::PixelRateLimitMode,
+};
 use clap::Parser;
 use const_format::formatcp;
+use ipnet::IpNet;
+use std::path::PathBuf;
 
 pub const DEFAULT_NETWORK_BUFFER_SIZE: usize = 1024 * 1024;
 pub const DEFAULT_NETWORK_BUFFER_SIZE_STR: &str = formatcp!("{}", DEFAULT_NETWORK_BUFFER_SIZE);
@@ -7,10 +20,108 @@ pub const DEFAULT_NETWORK_BUFFER_SIZE_STR: &str = formatcp!("{}", DEFAULT_NETWOR
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct CliArgs {
-    /// Listen address to bind to.
+    /// Listen address to bind to, can be specified multiple times.
+    /// Use `unix:<path>` for a Unix domain socket. Its clients show up as 127.0.0.1 in the statistics and share the
+    /// pixel ownership, but only count towards `--max-connections` and bypass the access list and pixel rate limit.
+    /// Prefix the address with `<tag>=` (e.g. `wifi=[::]:1234`) to break down the statistics by listener.
     /// The default value will listen on all interfaces for IPv4 and IPv6 packets.
     #[clap(short, long, default_value = "[::]:1234")]
-    pub listen_address: String,
+    pub listen_address: Vec<ListenAddress>,
+
+    /// Listen address to bind to for Pixelflut over UDP, e.g. `[::]:1234`.
+    /// Every datagram contains one or more newline separated commands, which are drawn on the main canvas.
+    #[clap(long)]
+    pub udp_listen_address: Option<String>,
+
+    /// Answer commands such as `SIZE` received over UDP with a datagram.
+    /// As the source address of datagrams can be spoofed, this can be abused for reflection attacks.
+    #[clap(long, requires = "udp_listen_address")]
+    pub udp_send_responses: bool,
+
+    /// Listen address to bind to for Pixelflut over WebSocket, e.g. `[::]:1235`.
+    /// Commands are sent in text or binary messages, every response is sent as a text message.
+    #[clap(long)]
+    pub websocket_listen_address: Option<ListenAddress>,
+
+    /// Listen address to bind to for Pixelflut over TLS, e.g. `[::]:1236`.
+    #[clap(long, requires_all = ["tls_certificate", "tls_private_key"])]
+    pub tls_listen_address: Option<ListenAddress>,
+
+    /// PEM file containing the certificate chain of the TLS listener.
+    #[clap(long, requires = "tls_listen_address")]
+    pub tls_certificate: Option<PathBuf>,
+
+    /// PEM file containing the private key of the TLS listener.
+    #[clap(long, requires = "tls_listen_address")]
+    pub tls_private_key: Option<PathBuf>,
+
+    /// PEM file containing CA certificates. If set, TLS clients need a certificate signed by one of them to connect,
+    /// e.g. to hand out credentials per team.
+    #[clap(long, requires = "tls_listen_address")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Peers (IP address or CIDR) that are trusted to send a PROXY protocol v1 or v2 header, e.g. a load balancer.
+    /// Their connections have to start with the header, the client address in it is used instead of the address of
+    /// the peer. Can be specified multiple times. Not supported by the UDP and io_uring servers.
+    #[clap(long, value_parser = parse_ip_range)]
+    pub proxy_protocol_trusted_source: Vec<IpNet>,
+
+    /// Serve the TCP `--listen-address`es using an io_uring event loop per listen address instead of tokio.
+    /// Output buffer limits, connection timeouts and the PROXY protocol are not supported and pixels exceeding the
+    /// pixel rate limit are always dropped. Unix domain sockets are served as usual.
+    #[cfg(feature = "io-uring")]
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "thread_per_core",
+            "output_buffer_size",
+            "output_overflow_policy",
+            "idle_timeout_s",
+            "min_throughput_bytes_per_s",
+            "max_connection_lifetime_s",
+            "proxy_protocol_trusted_source",
+        ]
+    )]
+    pub io_uring: bool,
+
+    /// Number of connections an io_uring event loop can handle, further connections are closed right away.
+    /// Every connection has a network buffer of `--io-uring-buffer-size` bytes, which is locked in memory, so the
+    /// locked memory limit (`ulimit -l`) has to allow for all of them. At most 16384, as the kernel doesn't allow
+    /// registering more buffers.
+    #[cfg(feature = "io-uring")]
+    #[clap(long, default_value_t = 1024, requires = "io_uring", value_parser = clap::value_parser!(u16).range(1..=16384))]
+    pub io_uring_max_connections: u16,
+
+    /// The size in bytes of the network buffer used for each connection of an io_uring event loop.
+    #[cfg(feature = "io-uring")]
+    #[clap(long, default_value_t = 65536, requires = "io_uring", value_parser = clap::value_parser!(u32).range(4096..1_000_000_000))]
+    pub io_uring_buffer_size: u32,
+
+    /// Serve the TCP `--listen-address`es from one worker thread per core, each pinned to its core with its own
+    /// single-threaded runtime and `SO_REUSEPORT` listener, so that connections never move between cores.
+    /// Unix domain sockets are served as usual.
+    #[clap(long)]
+    pub thread_per_core: bool,
+
+    /// Comma separated list of cores to start a worker on, e.g. `0,1,2,3`. Defaults to all cores.
+    #[clap(long, value_delimiter = ',', requires = "thread_per_core")]
+    pub worker_cores: Vec<usize>,
+
+    /// Priority of the worker threads (0-99), the default priority is used if not set.
+    #[clap(long, requires = "thread_per_core", value_parser = clap::value_parser!(u8).range(0..100))]
+    pub worker_thread_priority: Option<u8>,
+
+    /// Name of the canvas, used e.g. as `canvas` label of the Prometheus metrics.
+    #[clap(long, default_value = "main", value_parser = parse_canvas_name)]
+    pub canvas_name: String,
+
+    /// Additional canvas served by the same process, can be specified multiple times.
+    /// Every canvas has its own drawing surface, statistics and (optionally) listen address and VNC server.
+    /// Clients can switch between all canvases using the `CANVAS <name>` command, regardless of the port they connected to.
+    /// Format is `name=<name>[,listen=<listen_address>][,width=<width>][,height=<height>][,vnc-port=<port>][,statistics-save-file=<file>]`.
+    /// All other features (such as the heatmap or the admin interface) only apply to the main canvas.
+    #[clap(long)]
+    pub canvas: Vec<CanvasConfig>,
 
     /// Width of the drawing surface.
     #[clap(long, default_value_t = 1280)]
@@ -20,15 +131,109 @@ pub struct CliArgs {
     #[clap(long, default_value_t = 720)]
     pub height: usize,
 
+    /// Back the drawing surface with a 20000x20000 pixels buffer, so that writes by clients don't need bounds checks.
+    /// Writes outside of the drawing surface land in the unused padding. This uses a lot more memory, especially
+    /// if clients draw outside of the drawing surface. Can not be combined with pixel ownership or the heatmap.
+    #[clap(long, conflicts_with_all = ["pixel_ownership", "heatmap"])]
+    pub padded_framebuffer: bool,
+
+    /// Store the drawing surface in shared memory, so that other processes can read it without going through VNC.
+    /// Use `shm:<name>` for a named POSIX shared memory segment or a path for a memory-mapped file.
+    /// The frame counter in the header is incremented with the configured fps.
+    #[clap(long, conflicts_with = "padded_framebuffer")]
+    pub shared_memory: Option<SharedMemoryLocation>,
+
     /// Frames per second the server should aim for.
     #[clap(short, long, default_value_t = 30)]
     pub fps: u32,
 
-    /// The size in bytes of the network buffer used for each open TCP connection.
+    /// The maximum size in bytes of the network buffer used for each open TCP connection.
+    /// Connections start with a small buffer, which grows up to this size while the client sends more data.
     /// Please use at least 256 KB (256_000 bytes).
     #[clap(long, default_value = DEFAULT_NETWORK_BUFFER_SIZE_STR, value_parser = 256_000..100_000_000)]
     pub network_buffer_size: i64,
 
+    /// Maximum memory in bytes the network buffers of all connections can use together.
+    /// Once it is used up new connections wait for a buffer and the buffers of heavy clients stop growing.
+    /// Unlimited if not set.
+    #[clap(long)]
+    pub network_buffer_memory_budget: Option<usize>,
+
+    /// Maximum number of response bytes buffered per connection, e.g. for clients reading pixels without reading the
+    /// responses.
+    #[clap(long, default_value_t = DEFAULT_OUTPUT_BUFFER_SIZE)]
+    pub output_buffer_size: usize,
+
+    /// What happens once the output buffer of a connection is full: `drop` discards further responses, `pause` stops
+    /// parsing until the client reads its responses, `disconnect` closes the connection.
+    #[clap(long, default_value = "pause")]
+    pub output_overflow_policy: OutputOverflowPolicy,
+
+    /// Maximum number of client connections in total, further connections are closed right away.
+    #[clap(long)]
+    pub max_connections: Option<u32>,
+
+    /// Maximum number of client connections per IP address.
+    #[clap(long)]
+    pub max_connections_per_ip: Option<u32>,
+
+    /// Maximum number of client connections per IPv6 prefix, see `--connection-limit-ipv6-prefix-length`.
+    #[clap(long)]
+    pub max_connections_per_ipv6_prefix: Option<u32>,
+
+    /// IPv6 clients are grouped by this prefix length for `--max-connections-per-ipv6-prefix`,
+    /// as a single client can usually use all the addresses of its prefix.
+    #[clap(long, default_value_t = 64, value_parser = clap::value_parser!(u8).range(0..=128))]
+    pub connection_limit_ipv6_prefix_length: u8,
+
+    /// Send rejected clients a line telling them which connection limit they hit, e.g.
+    /// `ERROR too many connections from your IP address`.
+    #[clap(long)]
+    pub send_connection_limit_reason: bool,
+
+    /// File with IP ranges that are allowed or denied to connect, one `allow <cidr>` or `deny <cidr>` per line.
+    /// Denied ranges take precedence. If there is at least one allowed range, only IPs within the allowed ranges can
+    /// connect. The file is reloaded on SIGHUP or using the admin interface.
+    #[clap(long)]
+    pub access_list: Option<PathBuf>,
+
+    /// Maximum number of pixels per second every client can draw, shared by all its connections.
+    /// Can be changed at runtime using the admin interface. If not set, clients can draw as fast as they want.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub pixel_rate_limit: Option<u64>,
+
+    /// What happens with pixels exceeding `--pixel-rate-limit`: `drop` parses but doesn't draw them, `throttle`
+    /// stops reading from the connection until the client is allowed to draw again.
+    #[clap(long, default_value = "throttle")]
+    pub pixel_rate_limit_mode: PixelRateLimitMode,
+
+    /// IPv6 clients are grouped by this prefix length for `--pixel-rate-limit`,
+    /// as a single client can usually use all the addresses of its prefix.
+    #[clap(long, default_value_t = 64, value_parser = clap::value_parser!(u8).range(0..=128))]
+    pub pixel_rate_limit_ipv6_prefix_length: u8,
+
+    /// Close connections that did not send anything for this many seconds.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub idle_timeout_s: Option<u64>,
+
+    /// Close connections that send less than this many bytes per second, averaged over
+    /// `--min-throughput-window-s`. Only the time spent waiting for data counts, so connections throttled by
+    /// `--pixel-rate-limit` are not affected.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub min_throughput_bytes_per_s: Option<u64>,
+
+    /// Number of seconds the throughput is averaged over for `--min-throughput-bytes-per-s`.
+    #[clap(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub min_throughput_window_s: u64,
+
+    /// Close connections after they were open for this many seconds.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_connection_lifetime_s: Option<u64>,
+
+    /// On shutdown wait this many seconds for the client connections to finish the data they already sent.
+    #[clap(long, default_value_t = 5)]
+    pub shutdown_timeout_s: u64,
+
     /// Text to display on the screen.
     /// The text will be followed by "on <listen_address>".
     #[clap(short, long, default_value = "Pixelflut server (breakwater)")]
@@ -57,6 +262,77 @@ pub struct CliArgs {
     /// Disable periodical saving of statistics into save file.
     #[clap(long)]
     pub disable_statistics_save_file: bool,
+
+    /// Let the drawing surface fade towards the background color, so that the pixels halve their distance to the
+    /// background color every given number of seconds.
+    /// This forces clients to keep drawing and prevents the canvas from becoming a static wall.
+    /// If not set, pixels stay forever.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub decay_half_life_s: Option<u64>,
+
+    /// Background color (rrggbb) the pixels fade towards when decay is enabled.
+    #[clap(long, default_value = "000000", value_parser = parse_color)]
+    pub decay_background_color: u32,
+
+    /// Interval (in milliseconds) in which the drawing surface is swept to let the pixels decay.
+    #[clap(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(10..))]
+    pub decay_sweep_interval_ms: u64,
+
+    /// Region of the drawing surface clients can not draw on, e.g. for a sponsor logo.
+    /// Format is `x,y,width,height`, can be specified multiple times.
+    /// Protected regions can also be changed at runtime using the admin interface.
+    /// The stats bar of the VNC server is protected automatically.
+    #[clap(long)]
+    pub protected_region: Vec<Rect>,
+
+    /// Track which client last wrote each pixel.
+    /// This enables the `WHO` command and the statistics about the pixels owned per IP.
+    /// Costs 4 bytes of memory per pixel.
+    #[clap(long)]
+    pub pixel_ownership: bool,
+
+    /// IPv6 clients are grouped by this prefix length when tracking pixel ownership,
+    /// as a single client can usually use all the addresses of its prefix.
+    #[clap(long, default_value_t = 64, value_parser = clap::value_parser!(u8).range(0..=128))]
+    pub pixel_ownership_ipv6_prefix_length: u8,
+
+    /// Count the writes to each pixel, so that a heatmap of the drawing surface can be rendered.
+    /// Costs 2 bytes of memory per pixel.
+    #[clap(long)]
+    pub heatmap: bool,
+
+    /// Interval (in seconds) in which the heatmap counters are halved, so that the heatmap shows the recent activity.
+    #[clap(long, default_value_t = 60, requires = "heatmap", value_parser = clap::value_parser!(u64).range(1..))]
+    pub heatmap_half_life_s: u64,
+
+    /// File the rendered heatmap is periodically exported to as PNG.
+    #[clap(long, requires = "heatmap")]
+    pub heatmap_export_file: Option<String>,
+
+    /// Interval (in seconds) in which the heatmap export file should be updated.
+    #[clap(long, default_value_t = 60, requires = "heatmap", value_parser = clap::value_parser!(u64).range(1..))]
+    pub heatmap_export_interval_s: u64,
+
+    /// Interval (in seconds) in which snapshots of the drawing surface are taken for the canvas history.
+    /// The snapshots can be exported as time-lapse or restored using the admin interface.
+    /// If not set, no history is kept.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub history_interval_s: Option<u64>,
+
+    /// Number of snapshots the canvas history keeps. Older snapshots are dropped.
+    #[clap(long, default_value_t = 360)]
+    pub history_size: usize,
+
+    /// Directory the canvas history snapshots are stored in.
+    /// If not set, the snapshots are kept in memory (and are lost on restart).
+    #[clap(long, requires = "history_interval_s")]
+    pub history_directory: Option<String>,
+
+    /// Listen address of the admin interface, which can e.g. be used to export or restore the canvas history.
+    /// There is no authentication, so only listen on trusted interfaces (e.g. `127.0.0.1:1235`)!
+    /// If not set, the admin interface is disabled.
+    #[clap(long)]
+    pub admin_listen_address: Option<String>,
     //
     // /// Enable rtmp streaming to configured address, e.g. `rtmp://127.0.0.1:1935/live/test`
     // #[clap(long)]
@@ -70,4 +346,44 @@ pub struct CliArgs {
     #[cfg(feature = "vnc")]
     #[clap(short, long, default_value_t = 5900)]
     pub vnc_port: u16,
+
+    /// Port of an additional VNC server showing the heatmap.
+    #[cfg(feature = "vnc")]
+    #[clap(long, requires = "heatmap")]
+    pub heatmap_vnc_port: Option<u16>,
+
+    /// Split the drawing surface into a grid of `<columns>x<rows>` screens (e.g. projectors) forming a video wall.
+    /// Every screen gets its own VNC server, row by row starting at the top left, on consecutive ports starting with
+    /// `--video-wall-first-vnc-port`.
+    #[cfg(feature = "vnc")]
+    #[clap(long)]
+    pub video_wall: Option<VideoWallLayout>,
+
+    /// Port of the VNC server showing the top left screen of the video wall.
+    #[cfg(feature = "vnc")]
+    #[clap(long, default_value_t = 5910, requires = "video_wall")]
+    pub video_wall_first_vnc_port: u16,
+
+    /// Number of pixels hidden by the bezels between two columns of the video wall,
+    /// so that lines continue straight across the screens.
+    #[cfg(feature = "vnc")]
+    #[clap(long, default_value_t = 0, requires = "video_wall")]
+    pub video_wall_bezel_width: usize,
+
+    /// Number of pixels hidden by the bezels between two rows of the video wall.
+    #[cfg(feature = "vnc")]
+    #[clap(long, default_value_t = 0, requires = "video_wall")]
+    pub video_wall_bezel_height: usize,
+}
+
+/// Parses `rrggbb` into the format used by the [`breakwater_core::framebuffer::FrameBuffer`]
+fn parse_color(color: &str) -> Result<u32, String> {
+    if color.len() != 6 {
+        return Err(format!("invalid color {color:?}, expected rrggbb"));
+    }
+    let rgb = u32::from_str_radix(color, 16)
+        .map_err(|_| format!("invalid color {color:?}, expected rrggbb"))?;
+
+    // The FrameBuffer stores the colors as little endian rrggbb
+    Ok((rgb >> 16) & 0xff | rgb & 0xff00 | (rgb & 0xff) << 16)
 }
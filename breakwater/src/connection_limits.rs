@@ -0,0 +1,179 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::{IpAddr, Ipv6Addr},
+    sync::{Arc, Mutex},
+};
+
+use breakwater_core::ip::ipv6_prefix;
+use serde::{Deserialize, Serialize};
+
+use crate::statistics::decrement;
+
+/// Why a connection was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionLimit {
+    Total,
+    Ip,
+    Ipv6Prefix,
+    /// The IP address is not allowed by the [`crate::access_list::AccessList`]
+    Denied,
+}
+
+impl ConnectionLimit {
+    /// Used as label of the Prometheus metrics
+    pub fn label(&self) -> &'static str {
+        match self {
+            ConnectionLimit::Total => "total",
+            ConnectionLimit::Ip => "ip",
+            ConnectionLimit::Ipv6Prefix => "ipv6-prefix",
+            ConnectionLimit::Denied => "denied",
+        }
+    }
+}
+
+impl Display for ConnectionLimit {
+    /// Reason sent to rejected clients
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionLimit::Total => write!(f, "too many connections to the server"),
+            ConnectionLimit::Ip => write!(f, "too many connections from your IP address"),
+            ConnectionLimit::Ipv6Prefix => write!(f, "too many connections from your IPv6 prefix"),
+            ConnectionLimit::Denied => write!(f, "your IP address is not allowed to connect"),
+        }
+    }
+}
+
+/// Maximum number of client connections, enforced when accepting them.
+///
+/// The connections are counted here and not by the [`crate::statistics::Statistics`], as these count per canvas
+/// and only after the connection task processed its first events, so a burst of new connections would get through.
+#[derive(Debug, Default)]
+pub struct ConnectionLimits {
+    max_connections: Option<u32>,
+    max_connections_per_ip: Option<u32>,
+    max_connections_per_ipv6_prefix: Option<u32>,
+    ipv6_prefix_length: u8,
+
+    counts: Mutex<ConnectionCounts>,
+}
+
+#[derive(Debug, Default)]
+struct ConnectionCounts {
+    total: u32,
+    for_ip: HashMap<IpAddr, u32>,
+    for_ipv6_prefix: HashMap<Ipv6Addr, u32>,
+}
+
+/// Counts as open connection until dropped
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    limits: Arc<ConnectionLimits>,
+    /// `None` for connections only subject to the total limit
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionLimits {
+    /// IPv6 clients are grouped by the first `ipv6_prefix_length` bits of their address for
+    /// `max_connections_per_ipv6_prefix`. `None` means unlimited.
+    pub fn new(
+        max_connections: Option<u32>,
+        max_connections_per_ip: Option<u32>,
+        max_connections_per_ipv6_prefix: Option<u32>,
+        ipv6_prefix_length: u8,
+    ) -> Self {
+        ConnectionLimits {
+            max_connections,
+            max_connections_per_ip,
+            max_connections_per_ipv6_prefix,
+            ipv6_prefix_length: ipv6_prefix_length.min(128),
+            counts: Mutex::default(),
+        }
+    }
+
+    /// Counts a new connection from the given client, unless this would exceed one of the limits
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionPermit, ConnectionLimit> {
+        let mut counts = self.counts.lock().unwrap();
+
+        if exceeded(self.max_connections, counts.total) {
+            return Err(ConnectionLimit::Total);
+        }
+        if exceeded(
+            self.max_connections_per_ip,
+            counts.for_ip.get(&ip).copied().unwrap_or(0),
+        ) {
+            return Err(ConnectionLimit::Ip);
+        }
+        let prefix = self.ipv6_prefix(ip);
+        if let Some(prefix) = prefix {
+            if exceeded(
+                self.max_connections_per_ipv6_prefix,
+                counts.for_ipv6_prefix.get(&prefix).copied().unwrap_or(0),
+            ) {
+                return Err(ConnectionLimit::Ipv6Prefix);
+            }
+        }
+
+        counts.total += 1;
+        *counts.for_ip.entry(ip).or_insert(0) += 1;
+        if let Some(prefix) = prefix {
+            *counts.for_ipv6_prefix.entry(prefix).or_insert(0) += 1;
+        }
+
+        Ok(ConnectionPermit {
+            limits: Arc::clone(self),
+            ip: Some(ip),
+        })
+    }
+
+    /// Counts a new connection that is only subject to the total limit, e.g. from a Unix domain socket, whose clients
+    /// don't have an IP address of their own
+    pub fn try_acquire_local(self: &Arc<Self>) -> Result<ConnectionPermit, ConnectionLimit> {
+        let mut counts = self.counts.lock().unwrap();
+
+        if exceeded(self.max_connections, counts.total) {
+            return Err(ConnectionLimit::Total);
+        }
+        counts.total += 1;
+
+        Ok(ConnectionPermit {
+            limits: Arc::clone(self),
+            ip: None,
+        })
+    }
+
+    /// Number of currently open connections
+    pub fn connections(&self) -> u32 {
+        self.counts.lock().unwrap().total
+    }
+
+    fn release(&self, ip: Option<IpAddr>) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        let Some(ip) = ip else {
+            return;
+        };
+        decrement(&mut counts.for_ip, ip);
+        if let Some(prefix) = self.ipv6_prefix(ip) {
+            decrement(&mut counts.for_ipv6_prefix, prefix);
+        }
+    }
+
+    fn ipv6_prefix(&self, ip: IpAddr) -> Option<Ipv6Addr> {
+        match ip {
+            IpAddr::V4(_) => None,
+            IpAddr::V6(v6) => Some(ipv6_prefix(v6, self.ipv6_prefix_length)),
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limits.release(self.ip);
+    }
+}
+
+fn exceeded(limit: Option<u32>, connections: u32) -> bool {
+    limit.is_some_and(|limit| connections >= limit)
+}
@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+/// Why a connection was closed by the server
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionTimeout {
+    /// The client did not send anything for too long
+    Idle,
+    /// The client sent less than the minimum throughput
+    TooSlow,
+    /// The connection was open for too long
+    MaxLifetime,
+}
+
+impl ConnectionTimeout {
+    /// Used as label of the Prometheus metrics
+    pub fn label(&self) -> &'static str {
+        match self {
+            ConnectionTimeout::Idle => "idle",
+            ConnectionTimeout::TooSlow => "too-slow",
+            ConnectionTimeout::MaxLifetime => "max-lifetime",
+        }
+    }
+}
+
+/// Limits that keep idle or trickling connections from holding their network buffer forever.
+/// `None` disables the respective limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectionTimeouts {
+    /// Maximum time to wait for new data
+    pub idle: Option<Duration>,
+    /// Minimum number of bytes per second the client has to send, averaged over `min_throughput_window`
+    pub min_bytes_per_s: Option<u64>,
+    pub min_throughput_window: Duration,
+    pub max_lifetime: Option<Duration>,
+}
+
+/// Applies the [`ConnectionTimeouts`] to a single connection.
+///
+/// The throughput is only measured while waiting for data, so connections throttled by e.g. the
+/// [`crate::pixel_rate_limit::PixelRateLimiter`] are not considered slow.
+pub struct ConnectionTimeoutTracker {
+    timeouts: ConnectionTimeouts,
+    connected: Instant,
+    /// Time spent waiting for data in the current throughput window
+    window_waited: Duration,
+    window_bytes: u64,
+}
+
+impl ConnectionTimeoutTracker {
+    pub fn new(timeouts: ConnectionTimeouts) -> Self {
+        ConnectionTimeoutTracker {
+            timeouts,
+            connected: Instant::now(),
+            window_waited: Duration::ZERO,
+            window_bytes: 0,
+        }
+    }
+
+    /// Until when a read started at `read_start` may wait, `None` means forever
+    pub fn read_deadline(&self, read_start: Instant) -> Option<Instant> {
+        let idle = self.timeouts.idle.map(|idle| read_start + idle);
+        let lifetime = self
+            .timeouts
+            .max_lifetime
+            .map(|max_lifetime| self.connected + max_lifetime);
+        let window = self.timeouts.min_bytes_per_s.map(|_| {
+            read_start
+                + self
+                    .timeouts
+                    .min_throughput_window
+                    .saturating_sub(self.window_waited)
+        });
+
+        [idle, lifetime, window].into_iter().flatten().min()
+    }
+
+    /// Until when the connection may wait for something else than data from the client, e.g. for the pixel rate
+    /// limit, and which timeout applies then. Only the maximum lifetime counts, with `idle` set also the idle timeout
+    /// from `wait_start` on. `None` means forever.
+    pub fn wait_deadline(
+        &self,
+        wait_start: Instant,
+        idle: bool,
+    ) -> Option<(Instant, ConnectionTimeout)> {
+        let lifetime = self.timeouts.max_lifetime.map(|max_lifetime| {
+            (
+                self.connected + max_lifetime,
+                ConnectionTimeout::MaxLifetime,
+            )
+        });
+        let idle = self
+            .timeouts
+            .idle
+            .filter(|_| idle)
+            .map(|idle| (wait_start + idle, ConnectionTimeout::Idle));
+
+        [lifetime, idle]
+            .into_iter()
+            .flatten()
+            .min_by_key(|(deadline, _)| *deadline)
+    }
+
+    /// Call after every read that started at `read_start`, with `bytes` being `0` if the read hit the deadline
+    pub fn check(&mut self, read_start: Instant, bytes: usize) -> Result<(), ConnectionTimeout> {
+        let now = Instant::now();
+        if let Some(max_lifetime) = self.timeouts.max_lifetime {
+            if now >= self.connected + max_lifetime {
+                return Err(ConnectionTimeout::MaxLifetime);
+            }
+        }
+        if let Some(idle) = self.timeouts.idle {
+            if bytes == 0 && now >= read_start + idle {
+                return Err(ConnectionTimeout::Idle);
+            }
+        }
+        if let Some(min_bytes_per_s) = self.timeouts.min_bytes_per_s {
+            self.window_waited += now - read_start;
+            self.window_bytes += bytes as u64;
+            if self.window_waited >= self.timeouts.min_throughput_window {
+                let min_bytes =
+                    min_bytes_per_s as f64 * self.timeouts.min_throughput_window.as_secs_f64();
+                if (self.window_bytes as f64) < min_bytes {
+                    return Err(ConnectionTimeout::TooSlow);
+                }
+                self.window_waited = Duration::ZERO;
+                self.window_bytes = 0;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use std::{sync::Arc, time::Duration};
+
+use breakwater_core::framebuffer::FrameBuffer;
+use tokio::time::{interval, MissedTickBehavior};
+
+/// Number of pixels processed before giving other tasks on the runtime the chance to run
+const SWEEP_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Lets all pixels fade towards a background color, so that clients need to keep drawing.
+///
+/// The [`FrameBuffer`] is swept periodically and the distance of every color channel to the background color is
+/// reduced, so that it halves every `half_life`.
+/// We don't take any locks, so a pixel drawn by a client in the very moment we fade it might get lost.
+/// Clients need to keep drawing anyway, so that's fine.
+pub struct CanvasDecay {
+    fb: Arc<FrameBuffer>,
+    /// In the [`FrameBuffer`] format
+    background_color: u32,
+    sweep_interval: Duration,
+    /// Fixed point factor (16 bit fractional part) the distance to the background color is multiplied with on every sweep
+    factor: u32,
+}
+
+impl CanvasDecay {
+    pub fn new(
+        fb: Arc<FrameBuffer>,
+        background_color: u32,
+        half_life: Duration,
+        sweep_interval: Duration,
+    ) -> Self {
+        let factor = 0.5_f64.powf(sweep_interval.as_secs_f64() / half_life.as_secs_f64());
+
+        Self {
+            fb,
+            background_color,
+            sweep_interval,
+            factor: (factor * 65536.0) as u32,
+        }
+    }
+
+    pub async fn run(&self) {
+        let mut interval = interval(self.sweep_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let height = self.fb.get_height();
+            let rows_per_chunk = (SWEEP_CHUNK_SIZE / self.fb.get_width().max(1)).max(1);
+            for chunk_start in (0..height).step_by(rows_per_chunk) {
+                self.sweep(chunk_start..(chunk_start + rows_per_chunk).min(height));
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+
+    /// Fades all visible pixels in the given rows by one step
+    pub fn sweep(&self, rows: std::ops::Range<usize>) {
+        let buffer = unsafe { &mut *self.fb.get_buffer() };
+        let protected_regions = self.fb.protected_regions();
+        let (width, stride) = (self.fb.get_width(), self.fb.get_stride());
+
+        for y in rows {
+            let row_start = y * stride;
+            for (x, pixel) in buffer[row_start..row_start + width].iter_mut().enumerate() {
+                if protected_regions.is_protected_unchecked(x, y) {
+                    continue;
+                }
+
+                if *pixel != self.background_color {
+                    *pixel = self.fade(*pixel);
+                }
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn fade(&self, pixel: u32) -> u32 {
+        let mut faded = 0;
+        for shift in [0, 8, 16] {
+            let channel = (pixel >> shift) & 0xff;
+            let background = (self.background_color >> shift) & 0xff;
+            // Truncating moves every channel at least one step towards the background, so we always reach it eventually
+            let channel = if channel > background {
+                background + (((channel - background) * self.factor) >> 16)
+            } else {
+                background - (((background - channel) * self.factor) >> 16)
+            };
+            faded |= channel << shift;
+        }
+
+        faded
+    }
+}
@@ -0,0 +1,118 @@
+use std::{fs::File, io::BufWriter, sync::Arc, time::Duration};
+
+use breakwater_core::framebuffer::FrameBuffer;
+use log::info;
+use snafu::{ResultExt, Snafu};
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+use crate::image_encoding;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to create heatmap export file {export_file}"))]
+    CreateHeatmapExportFile {
+        source: std::io::Error,
+        export_file: String,
+    },
+
+    #[snafu(display("Failed to encode heatmap as PNG"))]
+    EncodeHeatmapPng { source: image_encoding::Error },
+
+    #[snafu(display("Failed to move heatmap export file to {export_file}"))]
+    MoveHeatmapExportFile {
+        source: std::io::Error,
+        export_file: String,
+    },
+}
+
+pub struct HeatmapExportConfig {
+    pub export_file: String,
+    pub interval: Duration,
+}
+
+/// Periodically decays the [`breakwater_core::heatmap::Heatmap`] of the [`FrameBuffer`] and optionally exports it as PNG.
+pub struct HeatmapUpdater {
+    fb: Arc<FrameBuffer>,
+    half_life: Duration,
+    export_config: Option<HeatmapExportConfig>,
+}
+
+impl HeatmapUpdater {
+    pub fn new(
+        fb: Arc<FrameBuffer>,
+        half_life: Duration,
+        export_config: Option<HeatmapExportConfig>,
+    ) -> Self {
+        Self {
+            fb,
+            half_life,
+            export_config,
+        }
+    }
+
+    pub async fn run(&self) -> Result<(), Error> {
+        let Some(heatmap) = self.fb.heatmap() else {
+            return Ok(());
+        };
+
+        let mut decay_interval = interval(self.half_life);
+        decay_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately, we don't want to decay or export an empty heatmap
+        decay_interval.tick().await;
+
+        let mut export_interval = self
+            .export_config
+            .as_ref()
+            .map(|export_config| interval(export_config.interval));
+        if let Some(export_interval) = &mut export_interval {
+            export_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            export_interval.tick().await;
+        }
+
+        loop {
+            tokio::select! {
+                _ = decay_interval.tick() => heatmap.decay(),
+                _ = tick_if_enabled(&mut export_interval) => {
+                    if let Some(export_config) = &self.export_config {
+                        self.export(&export_config.export_file)?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Renders the heatmap and writes it as PNG to the given file.
+    /// The PNG is written into a temporary file first, so readers never see a half written file.
+    pub fn export(&self, export_file: &str) -> Result<(), Error> {
+        let Some(heatmap) = self.fb.heatmap() else {
+            return Ok(());
+        };
+
+        let tmp_file = format!("{export_file}.tmp");
+        let file = File::create(&tmp_file).context(CreateHeatmapExportFileSnafu {
+            export_file: tmp_file.clone(),
+        })?;
+        image_encoding::write_png(
+            BufWriter::new(file),
+            self.fb.get_width(),
+            self.fb.get_height(),
+            &heatmap.render(),
+        )
+        .context(EncodeHeatmapPngSnafu)?;
+        std::fs::rename(&tmp_file, export_file).context(MoveHeatmapExportFileSnafu {
+            export_file: export_file.to_string(),
+        })?;
+        info!("Exported heatmap to {export_file}");
+
+        Ok(())
+    }
+}
+
+async fn tick_if_enabled(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::BufWriter,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use breakwater_core::framebuffer::FrameBuffer;
+use log::{debug, info, warn};
+use snafu::{OptionExt, ResultExt, Snafu};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::image_encoding::{self, ApngWriter, GifWriter};
+
+const SNAPSHOT_FILE_PREFIX: &str = "snapshot-";
+const SNAPSHOT_FILE_SUFFIX: &str = ".png";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to create history directory {directory:?}"))]
+    CreateHistoryDirectory {
+        source: std::io::Error,
+        directory: PathBuf,
+    },
+
+    #[snafu(display("Failed to list history directory {directory:?}"))]
+    ListHistoryDirectory {
+        source: std::io::Error,
+        directory: PathBuf,
+    },
+
+    #[snafu(display("Failed to encode snapshot"))]
+    EncodeSnapshot { source: image_encoding::Error },
+
+    #[snafu(display("Failed to decode snapshot {id}"))]
+    DecodeSnapshot {
+        source: image_encoding::Error,
+        id: u64,
+    },
+
+    #[snafu(display("Failed to write snapshot file {file:?}"))]
+    WriteSnapshotFile {
+        source: std::io::Error,
+        file: PathBuf,
+    },
+
+    #[snafu(display("Failed to read snapshot file {file:?}"))]
+    ReadSnapshotFile {
+        source: std::io::Error,
+        file: PathBuf,
+    },
+
+    #[snafu(display("There is no snapshot with id {id}"))]
+    UnknownSnapshot { id: u64 },
+
+    #[snafu(display("There are no snapshots to export"))]
+    NoSnapshots {},
+
+    #[snafu(display("Failed to create export file {file:?}"))]
+    CreateExportFile {
+        source: std::io::Error,
+        file: PathBuf,
+    },
+
+    #[snafu(display("Failed to encode time-lapse"))]
+    EncodeTimeLapse { source: image_encoding::Error },
+
+    #[snafu(display("Unknown time-lapse format {format:?}, supported are \"gif\", \"apng\" and \"png-sequence\""))]
+    UnknownTimeLapseFormat { format: String },
+}
+
+/// Where the snapshots of the [`CanvasHistory`] are kept
+pub enum HistoryStorage {
+    Memory,
+    /// Snapshots in the directory are picked up again after a restart
+    Disk {
+        directory: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TimeLapseFormat {
+    Gif,
+    Apng,
+    /// One PNG per snapshot in the given directory
+    PngSequence,
+}
+
+impl FromStr for TimeLapseFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "gif" => Ok(Self::Gif),
+            "apng" => Ok(Self::Apng),
+            "png-sequence" => Ok(Self::PngSequence),
+            _ => UnknownTimeLapseFormatSnafu { format }.fail(),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum SnapshotData {
+    /// PNG encoded
+    Memory(Arc<Vec<u8>>),
+    /// Path of the PNG file
+    Disk(PathBuf),
+}
+
+#[derive(Clone)]
+pub struct Snapshot {
+    /// Unix timestamp (in seconds) the snapshot was taken at. Also identifies the snapshot.
+    pub id: u64,
+    data: SnapshotData,
+}
+
+impl Snapshot {
+    fn read_png(&self) -> Result<Arc<Vec<u8>>, Error> {
+        match &self.data {
+            SnapshotData::Memory(png) => Ok(Arc::clone(png)),
+            SnapshotData::Disk(file) => std::fs::read(file)
+                .map(Arc::new)
+                .context(ReadSnapshotFileSnafu { file: file.clone() }),
+        }
+    }
+}
+
+/// Ring buffer of periodic PNG compressed snapshots of the [`FrameBuffer`].
+/// The snapshots can be exported as time-lapse or restored into the [`FrameBuffer`].
+pub struct CanvasHistory {
+    fb: Arc<FrameBuffer>,
+    interval: Duration,
+    capacity: usize,
+    storage: HistoryStorage,
+    snapshots: Mutex<VecDeque<Snapshot>>,
+}
+
+impl CanvasHistory {
+    pub fn new(
+        fb: Arc<FrameBuffer>,
+        interval: Duration,
+        capacity: usize,
+        storage: HistoryStorage,
+    ) -> Result<Self, Error> {
+        let mut snapshots = VecDeque::with_capacity(capacity);
+
+        if let HistoryStorage::Disk { directory } = &storage {
+            std::fs::create_dir_all(directory).context(CreateHistoryDirectorySnafu {
+                directory: directory.clone(),
+            })?;
+
+            let mut existing_snapshots = std::fs::read_dir(directory)
+                .context(ListHistoryDirectorySnafu {
+                    directory: directory.clone(),
+                })?
+                .filter_map(|entry| {
+                    let file = entry.ok()?.path();
+                    let id = file
+                        .file_name()?
+                        .to_str()?
+                        .strip_prefix(SNAPSHOT_FILE_PREFIX)?
+                        .strip_suffix(SNAPSHOT_FILE_SUFFIX)?
+                        .parse()
+                        .ok()?;
+                    Some(Snapshot {
+                        id,
+                        data: SnapshotData::Disk(file),
+                    })
+                })
+                .collect::<Vec<_>>();
+            existing_snapshots.sort_by_key(|snapshot| snapshot.id);
+
+            let skip = existing_snapshots.len().saturating_sub(capacity);
+            snapshots.extend(existing_snapshots.into_iter().skip(skip));
+            info!(
+                "Loaded {} existing snapshots from {directory:?}",
+                snapshots.len()
+            );
+        }
+
+        Ok(Self {
+            fb,
+            interval,
+            capacity,
+            storage,
+            snapshots: Mutex::new(snapshots),
+        })
+    }
+
+    pub async fn run(self: Arc<Self>) -> Result<(), Error> {
+        let mut interval = interval(self.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            // Compressing the snapshot takes some time, so let's not block the runtime
+            let history = Arc::clone(&self);
+            match tokio::task::spawn_blocking(move || history.take_snapshot()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!("Failed to take snapshot of the canvas: {err}"),
+                Err(err) => warn!("Snapshot task failed: {err}"),
+            }
+        }
+    }
+
+    pub fn take_snapshot(&self) -> Result<(), Error> {
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut pixels = vec![0; self.fb.get_size()];
+        self.fb.copy_visible_into(&mut pixels);
+        let mut png = Vec::new();
+        image_encoding::write_png(&mut png, self.fb.get_width(), self.fb.get_height(), &pixels)
+            .context(EncodeSnapshotSnafu)?;
+
+        let data = match &self.storage {
+            HistoryStorage::Memory => SnapshotData::Memory(Arc::new(png)),
+            HistoryStorage::Disk { directory } => {
+                let file =
+                    directory.join(format!("{SNAPSHOT_FILE_PREFIX}{id}{SNAPSHOT_FILE_SUFFIX}"));
+                std::fs::write(&file, png)
+                    .context(WriteSnapshotFileSnafu { file: file.clone() })?;
+                SnapshotData::Disk(file)
+            }
+        };
+
+        let evicted = {
+            let mut snapshots = self.snapshots.lock().unwrap();
+            // Two snapshots within the same second would have the same id, the newer one wins
+            if snapshots.back().is_some_and(|last| last.id == id) {
+                snapshots.pop_back();
+            }
+            snapshots.push_back(Snapshot { id, data });
+            let evict = snapshots.len().saturating_sub(self.capacity);
+            snapshots.drain(..evict).collect::<Vec<_>>()
+        };
+        for snapshot in evicted {
+            if let SnapshotData::Disk(file) = snapshot.data {
+                if let Err(err) = std::fs::remove_file(&file) {
+                    warn!("Failed to remove old snapshot file {file:?}: {err}");
+                }
+            }
+        }
+        debug!("Took snapshot {id} of the canvas");
+
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<u64> {
+        self.snapshots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|snapshot| snapshot.id)
+            .collect()
+    }
+
+    fn get(&self, id: u64) -> Result<Snapshot, Error> {
+        self.snapshots
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|snapshot| snapshot.id == id)
+            .cloned()
+            .context(UnknownSnapshotSnafu { id })
+    }
+
+    /// Overwrites the live [`FrameBuffer`] with the given snapshot
+    pub fn rollback(&self, id: u64) -> Result<(), Error> {
+        let png = self.get(id)?.read_png()?;
+        let pixels = image_encoding::read_png(&png, self.fb.get_width(), self.fb.get_height())
+            .context(DecodeSnapshotSnafu { id })?;
+        self.fb.overwrite(&pixels);
+        info!("Rolled back canvas to snapshot {id}");
+
+        Ok(())
+    }
+
+    /// Exports all snapshots as time-lapse. This takes a while, so better not call it from an async context.
+    pub fn export(&self, format: TimeLapseFormat, path: PathBuf, fps: u16) -> Result<(), Error> {
+        // Copy the list, so that we don't block taking new snapshots while exporting
+        let snapshots = self.snapshots.lock().unwrap().clone();
+        if snapshots.is_empty() {
+            return NoSnapshotsSnafu.fail();
+        }
+        let (width, height) = (self.fb.get_width(), self.fb.get_height());
+        let decode = |snapshot: &Snapshot| {
+            image_encoding::read_png(&snapshot.read_png()?, width, height)
+                .context(DecodeSnapshotSnafu { id: snapshot.id })
+        };
+
+        match format {
+            TimeLapseFormat::Gif => {
+                let file =
+                    File::create(&path).context(CreateExportFileSnafu { file: path.clone() })?;
+                let mut writer = GifWriter::new(BufWriter::new(file), width, height, fps)
+                    .context(EncodeTimeLapseSnafu)?;
+                for snapshot in &snapshots {
+                    writer
+                        .write_frame(&decode(snapshot)?)
+                        .context(EncodeTimeLapseSnafu)?;
+                }
+            }
+            TimeLapseFormat::Apng => {
+                let file =
+                    File::create(&path).context(CreateExportFileSnafu { file: path.clone() })?;
+                let mut writer =
+                    ApngWriter::new(BufWriter::new(file), width, height, snapshots.len(), fps)
+                        .context(EncodeTimeLapseSnafu)?;
+                for snapshot in &snapshots {
+                    writer
+                        .write_frame(&decode(snapshot)?)
+                        .context(EncodeTimeLapseSnafu)?;
+                }
+                writer.finish().context(EncodeTimeLapseSnafu)?;
+            }
+            TimeLapseFormat::PngSequence => {
+                std::fs::create_dir_all(&path).context(CreateHistoryDirectorySnafu {
+                    directory: path.clone(),
+                })?;
+                // The snapshots already are PNGs, no need to re-encode them
+                for (frame, snapshot) in snapshots.iter().enumerate() {
+                    let file = path.join(format!("frame-{frame:06}.png"));
+                    std::fs::write(&file, snapshot.read_png()?.as_slice())
+                        .context(WriteSnapshotFileSnafu { file })?;
+                }
+            }
+        }
+        info!(
+            "Exported {} snapshots as {format:?} to {path:?}",
+            snapshots.len()
+        );
+
+        Ok(())
+    }
+}
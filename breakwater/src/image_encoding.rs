@@ -0,0 +1,181 @@
+//! Conversion between the pixels of the [`breakwater_core::framebuffer::FrameBuffer`] and common image formats.
+//! The FrameBuffer stores the colors as little endian rrggbb, the highest byte is unused.
+
+use std::io::Write;
+
+use snafu::{ensure, ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to encode PNG"))]
+    EncodePng { source: png::EncodingError },
+
+    #[snafu(display("Failed to decode PNG"))]
+    DecodePng { source: png::DecodingError },
+
+    #[snafu(display("Failed to encode GIF"))]
+    EncodeGif { source: gif::EncodingError },
+
+    #[snafu(display(
+        "Image has unexpected size {width}x{height}, expected {expected_width}x{expected_height}"
+    ))]
+    UnexpectedImageSize {
+        width: usize,
+        height: usize,
+        expected_width: usize,
+        expected_height: usize,
+    },
+
+    #[snafu(display(
+        "Image has unsupported color type {color_type:?}, only 8 bit RGB is supported"
+    ))]
+    UnsupportedColorType {
+        color_type: (png::ColorType, png::BitDepth),
+    },
+
+    #[snafu(display("Image is too large for GIF, which only supports up to 65535x65535 pixels"))]
+    ImageTooLargeForGif {},
+}
+
+pub fn pixels_to_rgb(pixels: &[u32]) -> Vec<u8> {
+    pixels
+        .iter()
+        .flat_map(|pixel| {
+            let [r, g, b, _] = pixel.to_le_bytes();
+            [r, g, b]
+        })
+        .collect()
+}
+
+pub fn rgb_to_pixels(rgb: &[u8]) -> Vec<u32> {
+    rgb.chunks_exact(3)
+        .map(|rgb| u32::from_le_bytes([rgb[0], rgb[1], rgb[2], 0]))
+        .collect()
+}
+
+/// Writes pixels in the [`breakwater_core::framebuffer::FrameBuffer`] format as RGB PNG
+pub fn write_png(
+    writer: impl Write,
+    width: usize,
+    height: usize,
+    pixels: &[u32],
+) -> Result<(), Error> {
+    let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().context(EncodePngSnafu)?;
+    writer
+        .write_image_data(&pixels_to_rgb(pixels))
+        .context(EncodePngSnafu)?;
+
+    Ok(())
+}
+
+/// Reads a PNG as written by [`write_png`] back into the [`breakwater_core::framebuffer::FrameBuffer`] format
+pub fn read_png(
+    png: &[u8],
+    expected_width: usize,
+    expected_height: usize,
+) -> Result<Vec<u32>, Error> {
+    let mut reader = png::Decoder::new(png).read_info().context(DecodePngSnafu)?;
+
+    let info = reader.info();
+    let (width, height) = (info.width as usize, info.height as usize);
+    ensure!(
+        width == expected_width && height == expected_height,
+        UnexpectedImageSizeSnafu {
+            width,
+            height,
+            expected_width,
+            expected_height,
+        }
+    );
+    let color_type = reader.output_color_type();
+    ensure!(
+        color_type == (png::ColorType::Rgb, png::BitDepth::Eight),
+        UnsupportedColorTypeSnafu { color_type }
+    );
+
+    let mut rgb = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut rgb).context(DecodePngSnafu)?;
+
+    Ok(rgb_to_pixels(&rgb))
+}
+
+/// Writes frames as animated PNG, which - in contrast to GIF - keeps all the colors
+pub struct ApngWriter<W: Write> {
+    writer: png::Writer<W>,
+}
+
+impl<W: Write> ApngWriter<W> {
+    pub fn new(
+        writer: W,
+        width: usize,
+        height: usize,
+        frames: usize,
+        fps: u16,
+    ) -> Result<Self, Error> {
+        let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .set_animated(frames as u32, 0 /* loop forever */)
+            .context(EncodePngSnafu)?;
+        encoder
+            .set_frame_delay(1, fps.max(1))
+            .context(EncodePngSnafu)?;
+
+        Ok(Self {
+            writer: encoder.write_header().context(EncodePngSnafu)?,
+        })
+    }
+
+    /// Exactly the number of frames passed in [`ApngWriter::new`] must be written
+    pub fn write_frame(&mut self, pixels: &[u32]) -> Result<(), Error> {
+        self.writer
+            .write_image_data(&pixels_to_rgb(pixels))
+            .context(EncodePngSnafu)
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        self.writer.finish().context(EncodePngSnafu)
+    }
+}
+
+/// Writes frames as animated GIF.
+/// GIF only supports 256 colors per frame, so the colors get quantized, which takes quite some time.
+pub struct GifWriter<W: Write> {
+    encoder: gif::Encoder<W>,
+    width: u16,
+    height: u16,
+    // In units of 10 ms
+    delay: u16,
+}
+
+impl<W: Write> GifWriter<W> {
+    pub fn new(writer: W, width: usize, height: usize, fps: u16) -> Result<Self, Error> {
+        let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+            return ImageTooLargeForGifSnafu.fail();
+        };
+
+        let mut encoder = gif::Encoder::new(writer, width, height, &[]).context(EncodeGifSnafu)?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .context(EncodeGifSnafu)?;
+
+        Ok(Self {
+            encoder,
+            width,
+            height,
+            delay: 100 / fps.max(1),
+        })
+    }
+
+    pub fn write_frame(&mut self, pixels: &[u32]) -> Result<(), Error> {
+        // Speed 10 is the recommended tradeoff between quality and speed
+        let mut frame =
+            gif::Frame::from_rgb_speed(self.width, self.height, &pixels_to_rgb(pixels), 10);
+        frame.delay = self.delay;
+        self.encoder.write_frame(&frame).context(EncodeGifSnafu)
+    }
+}
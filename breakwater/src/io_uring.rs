@@ -0,0 +1,642 @@
+use std::{
+    cmp::min,
+    collections::HashMap,
+    io::{self, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    os::fd::{AsRawFd, FromRawFd},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use breakwater_core::framebuffer::FrameBuffer;
+use breakwater_parser::{implementations::SimpleParser, Parser, ParserError};
+use futures_util::FutureExt;
+use io_uring::{opcode, types, IoUring};
+use log::{debug, info, warn};
+use snafu::{OptionExt, ResultExt, Snafu};
+use tokio::sync::{mpsc, watch};
+
+use crate::{
+    accept_backoff::{AcceptBackoff, AcceptError},
+    access_list::AccessList,
+    canvas::{Canvas, Canvases},
+    connection_limits::{ConnectionLimit, ConnectionLimits, ConnectionPermit},
+    listener::{ListenAddress, ListenEndpoint},
+    pixel_rate_limit::{max_pixels, PixelRateLimiter},
+    server::{ip_to_canonical, new_parser, STATISTICS_REPORT_INTERVAL},
+    statistics::StatisticsEvent,
+};
+
+/// How often the event loop checks for the shutdown signal if there is nothing to do
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const ACCEPT_USER_DATA: u64 = u64::MAX;
+
+/// Processes with this capability can lock as much memory as they want, see `capabilities(7)`
+const CAP_IPC_LOCK: u32 = 14;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to bind to listen address {listen_address:?}"))]
+    BindToListenAddress {
+        source: io::Error,
+        listen_address: String,
+    },
+
+    #[snafu(display(
+        "The io_uring backend can only listen on TCP addresses, not on {listen_address:?}"
+    ))]
+    UnsupportedListenAddress { listen_address: String },
+
+    #[snafu(display("Failed to read the locked memory limit"))]
+    ReadLockedMemoryLimit { source: io::Error },
+
+    #[snafu(display(
+        "The io_uring network buffers need {required} bytes of locked memory, but the limit is {limit} bytes. \
+        Raise it (e.g. using `ulimit -l`) or lower `--io-uring-max-connections` or `--io-uring-buffer-size`"
+    ))]
+    LockedMemoryLimitTooLow {
+        required: libc::rlim_t,
+        limit: libc::rlim_t,
+    },
+
+    #[snafu(display("Failed to set up io_uring"))]
+    SetupIoUring { source: io::Error },
+
+    #[snafu(display("Failed to register network buffers with io_uring"))]
+    RegisterBuffers { source: io::Error },
+
+    #[snafu(display("Failed to submit to io_uring"))]
+    SubmitToIoUring { source: io::Error },
+
+    #[snafu(display("Failed to accept new client connection"))]
+    AcceptNewClientConnection { source: io::Error },
+
+    #[snafu(display("Failed to write to statistics channel"))]
+    WriteToStatisticsChannel {
+        source: mpsc::error::SendError<StatisticsEvent>,
+    },
+
+    #[snafu(display("Failed to parse Pixelflut commands"))]
+    ParsePixelflutCommands { source: ParserError },
+
+    #[snafu(display("There is no canvas {name:?}"))]
+    UnknownCanvas { name: String },
+}
+
+/// Alternative to the tokio based [`crate::server::Server`] for TCP listeners, running the connections of a listener
+/// in a single io_uring event loop on a dedicated thread.
+///
+/// Every connection gets a slot with a network buffer registered with io_uring, so the kernel reads directly into
+/// the buffer the parser works on, so the buffers are not taken from the shared [`crate::buffer_pool::BufferPool`] and
+/// don't count towards its memory budget. The reads and writes of all connections are submitted together once per loop.
+/// Connection timeouts and throttling are not supported, pixels exceeding the pixel rate limit are always dropped.
+pub struct IoUringServer {
+    listener: TcpListener,
+    tag: Option<Arc<str>>,
+    /// Canvas new connections start on
+    canvas: String,
+    canvases: Arc<Canvases>,
+    connection_limits: Arc<ConnectionLimits>,
+    send_connection_limit_reason: bool,
+    access_list: Option<Arc<AccessList>>,
+    pixel_rate_limiter: Arc<PixelRateLimiter>,
+    /// Number of connection slots, connections exceeding it are rejected as if they hit the total connection limit
+    max_connections: usize,
+    buffer_size: usize,
+}
+
+struct Connection {
+    socket: TcpStream,
+    ip: IpAddr,
+    _connection_permit: ConnectionPermit,
+
+    canvas_name: String,
+    fb: Arc<FrameBuffer>,
+    statistics_tx: mpsc::Sender<StatisticsEvent>,
+    parser: SimpleParser,
+    /// Parsers of the canvases the connection switched away from
+    inactive_parsers: HashMap<String, SimpleParser>,
+    /// Number of bytes left over at the start of the buffer from the previous read
+    leftover_bytes_in_buffer: usize,
+
+    /// Responses are written before reading again
+    response: Vec<u8>,
+    response_written: usize,
+
+    last_statistics: Instant,
+    statistics_bytes_read: u64,
+}
+
+impl IoUringServer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        listen_address: &ListenAddress,
+        canvas: String,
+        canvases: Arc<Canvases>,
+        connection_limits: Arc<ConnectionLimits>,
+        send_connection_limit_reason: bool,
+        access_list: Option<Arc<AccessList>>,
+        pixel_rate_limiter: Arc<PixelRateLimiter>,
+        max_connections: usize,
+        buffer_size: usize,
+    ) -> Result<Self, Error> {
+        let ListenEndpoint::Tcp(address) = &listen_address.endpoint else {
+            return UnsupportedListenAddressSnafu {
+                listen_address: listen_address.to_string(),
+            }
+            .fail();
+        };
+        let listener = TcpListener::bind(address).context(BindToListenAddressSnafu {
+            listen_address: listen_address.to_string(),
+        })?;
+        info!("Started Pixelflut io_uring server on {listen_address}");
+
+        Ok(Self {
+            listener,
+            tag: listen_address.tag.as_deref().map(Arc::from),
+            canvas,
+            canvases,
+            connection_limits,
+            send_connection_limit_reason,
+            access_list,
+            pixel_rate_limiter,
+            max_connections,
+            buffer_size,
+        })
+    }
+
+    /// Bytes of memory the network buffers of an event loop lock, they count towards `RLIMIT_MEMLOCK`
+    pub fn locked_memory(max_connections: usize, buffer_size: usize) -> libc::rlim_t {
+        (max_connections * (buffer_size + SimpleParser::parser_lookahead())) as libc::rlim_t
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.listener.local_addr().unwrap()
+    }
+
+    /// Runs the event loop until `shutdown` is set, blocking the current thread. Open connections are closed once the
+    /// data already read is parsed.
+    pub fn run(&self, shutdown: watch::Receiver<bool>) -> Result<(), Error> {
+        let parser_lookahead = SimpleParser::parser_lookahead();
+        let slot_size = self.buffer_size + parser_lookahead;
+        // Needs to outlive the ring, as the kernel might still write into it until the ring is dropped
+        let mut buffers = vec![0u8; self.max_connections * slot_size];
+        let ring_entries = (2 * self.max_connections + 1).next_power_of_two() as u32;
+        let mut ring = IoUring::new(ring_entries).context(SetupIoUringSnafu)?;
+        let iovecs: Vec<_> = buffers
+            .chunks_exact_mut(slot_size)
+            .map(|slot| libc::iovec {
+                iov_base: slot.as_mut_ptr().cast(),
+                iov_len: slot.len(),
+            })
+            .collect();
+        // SAFETY: The buffers outlive the ring and are only accessed by us while no read into them is in flight
+        unsafe { ring.submitter().register_buffers(&iovecs) }.context(RegisterBuffersSnafu)?;
+
+        let mut connections: Vec<Option<Connection>> =
+            (0..self.max_connections).map(|_| None).collect();
+        let mut free_slots: Vec<usize> = (0..self.max_connections).rev().collect();
+        let mut completions = Vec::new();
+        let mut backoff = AcceptBackoff::default();
+        // Set while waiting to accept again after a failed accept
+        let mut accept_again_at: Option<Instant> = None;
+
+        let listener_fd = types::Fd(self.listener.as_raw_fd());
+        let accept = opcode::Accept::new(listener_fd, std::ptr::null_mut(), std::ptr::null_mut())
+            .build()
+            .user_data(ACCEPT_USER_DATA);
+        push(&mut ring, &accept)?;
+
+        while !*shutdown.borrow() {
+            let mut timeout = SHUTDOWN_POLL_INTERVAL;
+            if let Some(at) = accept_again_at {
+                match at.checked_duration_since(Instant::now()) {
+                    Some(remaining) => timeout = timeout.min(remaining),
+                    None => {
+                        accept_again_at = None;
+                        push(&mut ring, &accept)?;
+                    }
+                }
+            }
+            let timeout = types::Timespec::from(timeout);
+            let args = types::SubmitArgs::new().timespec(&timeout);
+            match ring.submitter().submit_with_args(1, &args) {
+                Ok(_) => {}
+                Err(err) if matches!(err.raw_os_error(), Some(libc::ETIME | libc::EINTR)) => {}
+                Err(err) => return Err(err).context(SubmitToIoUringSnafu),
+            }
+
+            completions.extend(
+                ring.completion()
+                    .map(|completion| (completion.user_data(), completion.result())),
+            );
+            for (user_data, result) in completions.drain(..) {
+                if user_data == ACCEPT_USER_DATA {
+                    if result >= 0 {
+                        let was_at_file_descriptor_limit = backoff.file_descriptor_limit_reached();
+                        if backoff.succeeded() {
+                            info!("Accepting new client connections again");
+                        }
+                        if was_at_file_descriptor_limit {
+                            self.send_statistics(StatisticsEvent::FileDescriptorLimitRecovered)?;
+                        }
+                        // SAFETY: The kernel just handed us the file descriptor of the accepted connection
+                        let socket = unsafe { TcpStream::from_raw_fd(result) };
+                        if let Some((slot, connection)) = self.accept(socket, &mut free_slots)? {
+                            let connection = connections[slot].insert(connection);
+                            push(&mut ring, &read(connection, &iovecs, slot))?;
+                        }
+                    } else {
+                        let err = io::Error::from_raw_os_error(-result);
+                        let Some(error) = AcceptError::from_io_error(&err) else {
+                            return Err(err).context(AcceptNewClientConnectionSnafu);
+                        };
+                        let was_backing_off = backoff.is_backing_off();
+                        let was_at_file_descriptor_limit = backoff.file_descriptor_limit_reached();
+                        let delay = backoff.failed(error);
+                        if !delay.is_zero() && !was_backing_off {
+                            warn!("Failed to accept new client connection, backing off: {err}");
+                        } else {
+                            debug!("Failed to accept new client connection: {err}");
+                        }
+                        self.send_statistics(StatisticsEvent::AcceptFailed { error })?;
+                        if !was_at_file_descriptor_limit && backoff.file_descriptor_limit_reached()
+                        {
+                            self.send_statistics(StatisticsEvent::FileDescriptorLimitReached)?;
+                        }
+                        if !delay.is_zero() {
+                            accept_again_at = Some(Instant::now() + delay);
+                            continue;
+                        }
+                    }
+                    push(&mut ring, &accept)?;
+                    continue;
+                }
+
+                let slot = (user_data / 2) as usize;
+                let Some(connection) = connections[slot].as_mut() else {
+                    continue;
+                };
+                let next = if user_data % 2 == 0 {
+                    // SAFETY: The read into the buffer completed and there is no other operation on this slot
+                    let buffer = &mut buffers[slot * slot_size..(slot + 1) * slot_size];
+                    self.handle_read(connection, buffer, result)?
+                } else {
+                    handle_write(connection, result)
+                };
+                match next {
+                    Some(Operation::Read) => push(&mut ring, &read(connection, &iovecs, slot))?,
+                    Some(Operation::Write) => push(&mut ring, &write(connection, slot))?,
+                    None => {
+                        if let Some(connection) = connections[slot].take() {
+                            close(connection, &self.tag)?;
+                        }
+                        free_slots.push(slot);
+                    }
+                }
+            }
+        }
+
+        for connection in connections.into_iter().flatten() {
+            close(connection, &self.tag)?;
+        }
+        drop(ring);
+        drop(buffers);
+        Ok(())
+    }
+
+    /// Events that don't belong to a connection are accounted for on the initial canvas
+    fn send_statistics(&self, event: StatisticsEvent) -> Result<(), Error> {
+        if let Some(canvas) = self.canvases.get(&self.canvas) {
+            canvas
+                .statistics_tx
+                .blocking_send(event)
+                .context(WriteToStatisticsChannelSnafu)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the slot for the new connection, or `None` if it was rejected
+    fn accept(
+        &self,
+        socket: TcpStream,
+        free_slots: &mut Vec<usize>,
+    ) -> Result<Option<(usize, Connection)>, Error> {
+        let Ok(peer_addr) = socket.peer_addr() else {
+            return Ok(None);
+        };
+        let ip = ip_to_canonical(peer_addr.ip());
+
+        let connection_permit = match self.access_list.as_ref() {
+            Some(access_list) if !access_list.is_allowed(ip) => Err(ConnectionLimit::Denied),
+            _ if free_slots.is_empty() => Err(ConnectionLimit::Total),
+            _ => self.connection_limits.try_acquire(ip),
+        };
+        let connection_permit = match connection_permit {
+            Ok(connection_permit) => connection_permit,
+            Err(limit) => {
+                debug!("Rejected connection from {ip}: {limit}");
+                if self.send_connection_limit_reason {
+                    // The socket was just accepted, so the send buffer is empty and this doesn't block
+                    let _ = (&socket).write(format!("ERROR {limit}\n").as_bytes());
+                }
+                self.send_statistics(StatisticsEvent::ConnectionRejected { limit })?;
+                return Ok(None);
+            }
+        };
+
+        let Canvas { fb, statistics_tx } =
+            self.canvases
+                .get(&self.canvas)
+                .cloned()
+                .context(UnknownCanvasSnafu {
+                    name: self.canvas.clone(),
+                })?;
+        statistics_tx
+            .blocking_send(StatisticsEvent::ConnectionCreated {
+                ip,
+                listener: self.tag.clone(),
+            })
+            .context(WriteToStatisticsChannelSnafu)?;
+
+        let slot = free_slots.pop().expect("checked above");
+        Ok(Some((
+            slot,
+            Connection {
+                socket,
+                ip,
+                _connection_permit: connection_permit,
+                canvas_name: self.canvas.clone(),
+                parser: new_parser(&fb, ip),
+                fb,
+                statistics_tx,
+                inactive_parsers: HashMap::new(),
+                leftover_bytes_in_buffer: 0,
+                response: Vec::new(),
+                response_written: 0,
+                last_statistics: Instant::now(),
+                statistics_bytes_read: 0,
+            },
+        )))
+    }
+
+    /// Parses the data that was read, just like [`crate::server::handle_connection_to_canvases`] does
+    fn handle_read(
+        &self,
+        connection: &mut Connection,
+        buffer: &mut [u8],
+        result: i32,
+    ) -> Result<Option<Operation>, Error> {
+        // Errors and the client closing the connection
+        if result <= 0 {
+            return Ok(None);
+        }
+        let bytes_read = result as usize;
+        let parser_lookahead = SimpleParser::parser_lookahead();
+        let ip = connection.ip;
+
+        connection.statistics_bytes_read += bytes_read as u64;
+        if connection.last_statistics.elapsed() > STATISTICS_REPORT_INTERVAL {
+            connection
+                .statistics_tx
+                .blocking_send(StatisticsEvent::BytesRead {
+                    ip,
+                    listener: self.tag.clone(),
+                    bytes: connection.statistics_bytes_read,
+                })
+                .context(WriteToStatisticsChannelSnafu)?;
+            connection.last_statistics = Instant::now();
+            connection.statistics_bytes_read = 0;
+        }
+
+        let data_end = connection.leftover_bytes_in_buffer + bytes_read;
+        // Zero the lookahead, so the parser does not detect any command left over from a previous read
+        buffer[data_end..data_end + parser_lookahead].fill(0);
+
+        let mut parse_start = 0;
+        let parsed_end = loop {
+            connection.parser.set_pixel_budget(
+                self.pixel_rate_limiter
+                    .take(ip, max_pixels(data_end - parse_start)),
+                false,
+            );
+            connection
+                .parser
+                .parse(
+                    &buffer[parse_start..data_end + parser_lookahead],
+                    &connection.fb,
+                    &mut connection.response,
+                )
+                // Writing to a Vec never has to wait
+                .now_or_never()
+                .expect("parsing into a buffer completes immediately")
+                .context(ParsePixelflutCommandsSnafu)?;
+            self.pixel_rate_limiter
+                .give_back(ip, connection.parser.pixel_budget());
+            let Some(requested_canvas) = connection.parser.take_requested_canvas() else {
+                break parse_start + connection.parser.resume_offset();
+            };
+            parse_start += connection.parser.resume_offset();
+            if requested_canvas != connection.canvas_name {
+                self.switch_canvas(connection, requested_canvas)?;
+            }
+        };
+
+        let leftover_bytes_in_buffer = data_end.saturating_sub(parsed_end);
+        connection.leftover_bytes_in_buffer = min(leftover_bytes_in_buffer, parser_lookahead);
+        if connection.leftover_bytes_in_buffer > 0 {
+            buffer.copy_within(
+                parsed_end..parsed_end + connection.leftover_bytes_in_buffer,
+                0,
+            );
+        }
+
+        Ok(Some(if connection.response.is_empty() {
+            Operation::Read
+        } else {
+            Operation::Write
+        }))
+    }
+
+    fn switch_canvas(
+        &self,
+        connection: &mut Connection,
+        requested_canvas: String,
+    ) -> Result<(), Error> {
+        let Some(canvas) = self.canvases.get(&requested_canvas) else {
+            connection
+                .response
+                .extend_from_slice(format!("CANVAS {requested_canvas} not found\n").as_bytes());
+            return Ok(());
+        };
+
+        // Move the connection over to the statistics of the new canvas
+        let ip = connection.ip;
+        for event in [
+            StatisticsEvent::BytesRead {
+                ip,
+                listener: self.tag.clone(),
+                bytes: std::mem::take(&mut connection.statistics_bytes_read),
+            },
+            StatisticsEvent::ConnectionClosed {
+                ip,
+                listener: self.tag.clone(),
+            },
+        ] {
+            connection
+                .statistics_tx
+                .blocking_send(event)
+                .context(WriteToStatisticsChannelSnafu)?;
+        }
+        canvas
+            .statistics_tx
+            .blocking_send(StatisticsEvent::ConnectionCreated {
+                ip,
+                listener: self.tag.clone(),
+            })
+            .context(WriteToStatisticsChannelSnafu)?;
+
+        let new_parser = connection
+            .inactive_parsers
+            .remove(&requested_canvas)
+            .unwrap_or_else(|| new_parser(&canvas.fb, ip));
+        let previous_canvas = std::mem::replace(&mut connection.canvas_name, requested_canvas);
+        let previous_parser = std::mem::replace(&mut connection.parser, new_parser);
+        connection
+            .inactive_parsers
+            .insert(previous_canvas, previous_parser);
+        connection.fb = Arc::clone(&canvas.fb);
+        connection.statistics_tx = canvas.statistics_tx.clone();
+        Ok(())
+    }
+}
+
+enum Operation {
+    Read,
+    Write,
+}
+
+fn handle_write(connection: &mut Connection, result: i32) -> Option<Operation> {
+    if result < 0 {
+        return None;
+    }
+    connection.response_written += result as usize;
+    if connection.response_written < connection.response.len() {
+        return Some(Operation::Write);
+    }
+    connection.response.clear();
+    connection.response_written = 0;
+    Some(Operation::Read)
+}
+
+/// Reads are tagged with an even and writes with an odd user data, so we know which slot and operation completed
+fn read(connection: &Connection, iovecs: &[libc::iovec], slot: usize) -> io_uring::squeue::Entry {
+    let parser_lookahead = SimpleParser::parser_lookahead();
+    let iovec = &iovecs[slot];
+    let leftover = connection.leftover_bytes_in_buffer;
+    // SAFETY: Stays within the registered buffer of the slot
+    let buf = unsafe { iovec.iov_base.cast::<u8>().add(leftover) };
+    let len = iovec.iov_len - parser_lookahead - leftover;
+    opcode::ReadFixed::new(
+        types::Fd(connection.socket.as_raw_fd()),
+        buf,
+        len as u32,
+        slot as u16,
+    )
+    .build()
+    .user_data(slot as u64 * 2)
+}
+
+fn write(connection: &Connection, slot: usize) -> io_uring::squeue::Entry {
+    let remaining = &connection.response[connection.response_written..];
+    opcode::Send::new(
+        types::Fd(connection.socket.as_raw_fd()),
+        remaining.as_ptr(),
+        remaining.len() as u32,
+    )
+    .build()
+    .user_data(slot as u64 * 2 + 1)
+}
+
+fn close(connection: Connection, tag: &Option<Arc<str>>) -> Result<(), Error> {
+    for event in [
+        StatisticsEvent::BytesRead {
+            ip: connection.ip,
+            listener: tag.clone(),
+            bytes: connection.statistics_bytes_read,
+        },
+        StatisticsEvent::ConnectionClosed {
+            ip: connection.ip,
+            listener: tag.clone(),
+        },
+    ] {
+        connection
+            .statistics_tx
+            .blocking_send(event)
+            .context(WriteToStatisticsChannelSnafu)?;
+    }
+    Ok(())
+}
+
+/// Submits the queued entries if the submission queue is full
+fn push(ring: &mut IoUring, entry: &io_uring::squeue::Entry) -> Result<(), Error> {
+    loop {
+        // SAFETY: All buffers referenced by the entries outlive the ring
+        if unsafe { ring.submission().push(entry) }.is_ok() {
+            return Ok(());
+        }
+        ring.submit().context(SubmitToIoUringSnafu)?;
+    }
+}
+
+/// Makes sure `required` bytes of memory can be locked for the network buffers, raising the soft limit of locked
+/// memory if needed. Without this the buffers can't be registered, as the default limit is often only 8 MiB.
+pub fn ensure_locked_memory_limit(required: libc::rlim_t) -> Result<(), Error> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid rlimit to write into
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error()).context(ReadLockedMemoryLimitSnafu);
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY
+        || limit.rlim_cur >= required
+        || has_capability(CAP_IPC_LOCK)
+    {
+        return Ok(());
+    }
+
+    let previous = limit.rlim_cur;
+    limit.rlim_cur = required;
+    // Raising the hard limit only works with CAP_SYS_RESOURCE
+    if limit.rlim_max != libc::RLIM_INFINITY && limit.rlim_max < required {
+        limit.rlim_max = required;
+    }
+    // SAFETY: `limit` is a valid rlimit, the kernel checks that we are allowed to set it
+    if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &limit) } != 0 {
+        return LockedMemoryLimitTooLowSnafu {
+            required,
+            limit: previous,
+        }
+        .fail();
+    }
+    info!("Raised the locked memory limit from {previous} to {required} bytes for the io_uring network buffers");
+
+    Ok(())
+}
+
+/// Checks the effective capabilities of the process
+fn has_capability(capability: u32) -> bool {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("CapEff:"))
+                .and_then(|capabilities| u64::from_str_radix(capabilities.trim(), 16).ok())
+        })
+        .is_some_and(|capabilities| capabilities & (1 << capability) != 0)
+}
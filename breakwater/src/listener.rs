@@ -0,0 +1,165 @@
+use std::{
+    fmt::Display,
+    io,
+    net::{IpAddr, Ipv4Addr},
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream, UnixListener, UnixStream};
+
+use crate::server::{ip_to_canonical, ClientStream};
+
+/// IP clients connected through a Unix domain socket are accounted for
+pub const UNIX_SOCKET_CLIENT_IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// Same as the backlog of [`TcpListener::bind`]
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// Address a server listens on, optionally tagged so that the statistics can tell the listeners apart
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenAddress {
+    pub tag: Option<String>,
+    pub endpoint: ListenEndpoint,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenEndpoint {
+    /// Anything [`TcpListener::bind`] accepts, e.g. `[::]:1234`
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    /// Parses `[<tag>=]<address>`, where the address is either a TCP address such as `[::]:1234` or `unix:<path>`,
+    /// e.g. `wifi=[::]:1234` or `art=unix:/run/breakwater.sock`
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (tag, address) = match input.split_once('=') {
+            Some((tag, address)) if is_valid_tag(tag) => (Some(tag.to_string()), address),
+            _ => (None, input),
+        };
+        let endpoint = match address.strip_prefix("unix:") {
+            Some("") => {
+                return Err(format!(
+                    "invalid listen address {input:?}, the path is missing"
+                ))
+            }
+            Some(path) => ListenEndpoint::Unix(path.into()),
+            None if address.is_empty() => {
+                return Err(format!(
+                    "invalid listen address {input:?}, the address is missing"
+                ))
+            }
+            None => ListenEndpoint::Tcp(address.to_string()),
+        };
+
+        Ok(ListenAddress { tag, endpoint })
+    }
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(tag) = &self.tag {
+            write!(f, "{tag}=")?;
+        }
+        match &self.endpoint {
+            ListenEndpoint::Tcp(address) => write!(f, "{address}"),
+            ListenEndpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+fn is_valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_')
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// The socket file is removed once the listener is dropped
+    Unix(UnixListener, PathBuf),
+}
+
+/// A freshly accepted client connection
+pub enum ClientSocket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    /// A leftover socket file (e.g. from a crash) at the path of a Unix domain socket is replaced.
+    ///
+    /// With `reuse_port` multiple TCP listeners can bind the same address and the kernel distributes the connections
+    /// between them, Unix domain sockets don't support this.
+    pub async fn bind(endpoint: &ListenEndpoint, reuse_port: bool) -> io::Result<Self> {
+        match endpoint {
+            ListenEndpoint::Tcp(address) if reuse_port => {
+                let socket_addr = lookup_host(address).await?.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+                })?;
+                let socket = if socket_addr.is_ipv4() {
+                    TcpSocket::new_v4()?
+                } else {
+                    TcpSocket::new_v6()?
+                };
+                // Same as TcpListener::bind does
+                socket.set_reuseaddr(true)?;
+                socket.set_reuseport(true)?;
+                socket.bind(socket_addr)?;
+                Ok(Listener::Tcp(socket.listen(LISTEN_BACKLOG)?))
+            }
+            ListenEndpoint::Tcp(address) => Ok(Listener::Tcp(TcpListener::bind(address).await?)),
+            ListenEndpoint::Unix(path) => {
+                if std::fs::symlink_metadata(path)
+                    .is_ok_and(|metadata| metadata.file_type().is_socket())
+                {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+        }
+    }
+
+    /// Returns the client socket and the canonical IP of the client
+    pub async fn accept(&self) -> io::Result<(ClientSocket, IpAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, socket_addr) = listener.accept().await?;
+                // If you connect via IPv4 you often show up as embedded inside an IPv6 address
+                // Extracting the embedded information here, so we get the real (TM) address
+                Ok((ClientSocket::Tcp(socket), ip_to_canonical(socket_addr.ip())))
+            }
+            Listener::Unix(listener, _) => {
+                let (socket, _) = listener.accept().await?;
+                Ok((ClientSocket::Unix(socket), UNIX_SOCKET_CLIENT_IP))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl ClientSocket {
+    /// Access to Unix domain sockets is controlled by file permissions instead of the client IP
+    pub fn is_unix(&self) -> bool {
+        matches!(self, ClientSocket::Unix(_))
+    }
+
+    pub fn into_stream(self) -> Box<dyn ClientStream> {
+        match self {
+            ClientSocket::Tcp(socket) => Box::new(socket),
+            ClientSocket::Unix(socket) => Box::new(socket),
+        }
+    }
+}
@@ -1,51 +1,165 @@
-use std::{num::TryFromIntError, sync::Arc};
+use std::{num::TryFromIntError, sync::Arc, time::Duration};
 
 use breakwater_core::framebuffer::FrameBuffer;
 use clap::Parser;
 use env_logger::Env;
+use ipnet::IpNet;
+use log::{info, warn};
+use number_prefix::NumberPrefix;
 use prometheus_exporter::PrometheusExporter;
-use snafu::{ResultExt, Snafu};
-use tokio::sync::{broadcast, mpsc};
+use snafu::{ensure, ResultExt, Snafu};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, mpsc, watch},
+    time::Instant,
+};
 
 use crate::{
+    access_list::AccessList,
+    admin::{AdminContext, AdminServer},
+    buffer_pool::BufferPool,
+    canvas::{Canvas, Canvases},
     cli_args::CliArgs,
-    server::Server,
+    connection_limits::ConnectionLimits,
+    connection_timeouts::ConnectionTimeouts,
+    decay::CanvasDecay,
+    heatmap::{HeatmapExportConfig, HeatmapUpdater},
+    history::{CanvasHistory, HistoryStorage},
+    listener::ListenEndpoint,
+    output_buffer::OutputLimit,
+    pixel_rate_limit::PixelRateLimiter,
+    server::{Server, Transport},
+    shared_memory::SharedMemoryFrameCounter,
     statistics::{Statistics, StatisticsEvent, StatisticsInformationEvent, StatisticsSaveMode},
+    thread_per_core::ThreadPerCoreServer,
+    udp_server::UdpServer,
 };
 
+#[cfg(feature = "io-uring")]
+use crate::io_uring::IoUringServer;
+
 #[cfg(feature = "vnc")]
 use {
-    crate::sinks::vnc::{self, VncServer},
+    crate::sinks::vnc::{self, VncServer, VncView},
+    snafu::OptionExt,
+    std::thread::JoinHandle,
     thread_priority::{ThreadBuilderExt, ThreadPriority},
     tokio::sync::oneshot,
 };
 
+mod accept_backoff;
+mod access_list;
+mod admin;
+mod buffer_pool;
+mod canvas;
 mod cli_args;
+mod connection_limits;
+mod connection_timeouts;
+mod decay;
+mod heatmap;
+mod history;
+mod image_encoding;
+#[cfg(feature = "io-uring")]
+mod io_uring;
+mod listener;
+mod output_buffer;
+mod pixel_rate_limit;
 mod prometheus_exporter;
+mod proxy_protocol;
 mod server;
+mod shared_memory;
 mod sinks;
 mod statistics;
+mod thread_per_core;
+mod tls;
+mod udp_server;
+#[cfg(feature = "vnc")]
+mod video_wall;
+mod websocket;
 
 #[cfg(test)]
 mod tests;
 
+/// Worker threads of the main runtime if the client connections are handled by the thread-per-core workers
+const HOUSEKEEPING_WORKER_THREADS: usize = 2;
+
 #[derive(Debug, Snafu)]
 pub enum Error {
+    #[snafu(display("Failed to build tokio runtime"))]
+    BuildRuntime { source: std::io::Error },
+
     #[snafu(display("Failed to start Pixelflut server"))]
     StartPixelflutServer { source: server::Error },
 
+    #[snafu(display("Failed to start Pixelflut WebSocket server"))]
+    StartWebSocketServer { source: server::Error },
+
+    #[cfg(feature = "io-uring")]
+    #[snafu(display("Failed to start Pixelflut io_uring server"))]
+    StartIoUringServer { source: io_uring::Error },
+
+    #[cfg(feature = "io-uring")]
+    #[snafu(display("Failed to spawn io_uring server thread"))]
+    SpawnIoUringServerThread { source: std::io::Error },
+
+    #[snafu(display("Failed to start thread-per-core workers"))]
+    StartThreadPerCoreWorkers { source: thread_per_core::Error },
+
+    #[snafu(display("Failed to load TLS certificate"))]
+    LoadTlsCertificate { source: tls::Error },
+
+    #[snafu(display("Failed to start Pixelflut TLS server"))]
+    StartTlsServer { source: server::Error },
+
+    #[snafu(display("Failed to start Pixelflut UDP server"))]
+    StartUdpServer { source: udp_server::Error },
+
     #[snafu(display("Failed to wait for CTRL + C signal"))]
     WaitForCtrlCSignal { source: std::io::Error },
 
+    #[snafu(display("Failed to listen for SIGTERM signal"))]
+    ListenForTerminateSignal { source: std::io::Error },
+
     #[snafu(display("Failed to start Prometheus exporter"))]
     StartPrometheusExporter { source: prometheus_exporter::Error },
 
+    #[snafu(display("Failed to start admin interface"))]
+    StartAdminServer { source: admin::Error },
+
+    #[snafu(display("Failed to create shared memory for the framebuffer"))]
+    CreateSharedMemory {
+        source: breakwater_core::shared_memory::Error,
+    },
+
+    #[snafu(display("Failed to load access list"))]
+    LoadAccessList { source: access_list::Error },
+
+    #[snafu(display("Failed to listen for SIGHUP signal"))]
+    ListenForHangupSignal { source: std::io::Error },
+
+    #[snafu(display("Failed to set up canvas history"))]
+    SetupCanvasHistory { source: history::Error },
+
+    #[snafu(display("Canvas name {name:?} is used multiple times"))]
+    DuplicateCanvasName { name: String },
+
+    #[snafu(display("Failed to start Pixelflut server of canvas {name:?}"))]
+    StartCanvasServer { source: server::Error, name: String },
+
     #[snafu(display("Invalid network buffer size {network_buffer_size:?}"))]
     InvalidNetworkBufferSize {
         source: TryFromIntError,
         network_buffer_size: i64,
     },
 
+    #[snafu(display(
+        "The network buffer memory budget of {memory_budget} bytes needs to fit at least one network buffer of {network_buffer_size} bytes"
+    ))]
+    NetworkBufferMemoryBudgetTooSmall {
+        memory_budget: usize,
+        network_buffer_size: usize,
+    },
+
     #[cfg(feature = "vnc")]
     #[snafu(display("Failed to spawn VNC server thread"))]
     SpawnVncServerThread { source: std::io::Error },
@@ -62,17 +176,79 @@ pub enum Error {
     #[snafu(display("Failed to start VNC server"))]
     StartVncServer { source: vnc::Error },
 
+    #[cfg(feature = "vnc")]
+    #[snafu(display(
+        "The video wall has more screens than pixels, use a smaller layout or smaller bezels"
+    ))]
+    InvalidVideoWall {},
+
+    #[cfg(feature = "vnc")]
+    #[snafu(display("Video wall with {screens} screens needs more VNC ports than available starting with {first_port}"))]
+    VideoWallPortsExhausted { screens: usize, first_port: u16 },
+
     #[cfg(feature = "vnc")]
     #[snafu(display("Failed to get cross-platform ThreadPriority. Please report this error message together with your operating system: {message}"))]
     GetThreadPriority { message: String },
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
+fn main() -> Result<(), Error> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     let args = CliArgs::parse();
 
-    let fb = Arc::new(FrameBuffer::new(args.width, args.height));
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if args.thread_per_core {
+        // The workers have their own runtimes, this one only does the housekeeping
+        runtime.worker_threads(HOUSEKEEPING_WORKER_THREADS);
+    }
+    runtime
+        .enable_all()
+        .build()
+        .context(BuildRuntimeSnafu)?
+        .block_on(run(args))
+}
+
+async fn run(args: CliArgs) -> Result<(), Error> {
+    // Every client connection needs a file descriptor, the default soft limit is often as low as 1024
+    match accept_backoff::raise_open_files_limit() {
+        Ok((previous, limit)) if previous < limit => {
+            info!("Raised the open files limit from {previous} to {limit}")
+        }
+        Ok((_, limit)) => info!("The open files limit is {limit}"),
+        Err(err) => warn!("Failed to raise the open files limit: {err}"),
+    }
+
+    let mut fb = if args.padded_framebuffer {
+        FrameBuffer::new_padded(args.width, args.height)
+    } else if let Some(shared_memory) = &args.shared_memory {
+        FrameBuffer::new_in_shared_memory(args.width, args.height, shared_memory.clone())
+            .context(CreateSharedMemorySnafu)?
+    } else {
+        FrameBuffer::new(args.width, args.height)
+    };
+    if args.pixel_ownership {
+        fb = fb.with_pixel_ownership(args.pixel_ownership_ipv6_prefix_length);
+    }
+    if args.heatmap {
+        fb = fb.with_heatmap();
+    }
+    for rect in &args.protected_region {
+        fb.protected_regions().add(*rect);
+    }
+    let allocated = match NumberPrefix::binary(fb.allocated_bytes() as f64) {
+        NumberPrefix::Prefixed(prefix, n) => format!("{n:.1} {prefix}B"),
+        NumberPrefix::Standalone(n) => format!("{n} B"),
+    };
+    if args.padded_framebuffer {
+        info!("Allocated {allocated} for the padded framebuffer, the padding only uses memory once clients draw on it");
+    } else if let Some(shared_memory) = fb.shared_memory() {
+        info!(
+            "Allocated {allocated} for the framebuffer, the pixels are stored in shared memory {}",
+            shared_memory.location()
+        );
+    } else {
+        info!("Allocated {allocated} for the framebuffer");
+    }
+    let fb = Arc::new(fb);
 
     // If we make the channel to big, stats will start to lag behind
     // TODO: Check performance impact in real-world scenario. Maybe the statistics thread blocks the other threads
@@ -80,10 +256,62 @@ async fn main() -> Result<(), Error> {
     let (statistics_information_tx, statistics_information_rx_for_prometheus_exporter) =
         broadcast::channel::<StatisticsInformationEvent>(2);
 
-    #[cfg(feature = "vnc")]
-    let (vnc_terminate_signal_tx, vnc_terminate_signal_rx) = oneshot::channel();
     #[cfg(feature = "vnc")]
     let statistics_information_rx_for_vnc_server = statistics_information_tx.subscribe();
+    #[cfg(feature = "vnc")]
+    let statistics_information_rx_for_heatmap_vnc_server = statistics_information_tx.subscribe();
+    #[cfg(feature = "vnc")]
+    let video_wall_tiles = match args.video_wall {
+        Some(video_wall) => video_wall
+            .tiles(
+                args.width,
+                args.height,
+                args.video_wall_bezel_width,
+                args.video_wall_bezel_height,
+            )
+            .context(InvalidVideoWallSnafu)?,
+        None => Vec::new(),
+    };
+    #[cfg(feature = "vnc")]
+    ensure!(
+        args.video_wall_first_vnc_port as usize + video_wall_tiles.len() <= u16::MAX as usize + 1,
+        VideoWallPortsExhaustedSnafu {
+            screens: video_wall_tiles.len(),
+            first_port: args.video_wall_first_vnc_port,
+        }
+    );
+    #[cfg(feature = "vnc")]
+    let video_wall_tiles: Vec<_> = video_wall_tiles
+        .into_iter()
+        .map(|tile| (tile, statistics_information_tx.subscribe()))
+        .collect();
+
+    let pixel_rate_limiter = Arc::new(PixelRateLimiter::new(
+        args.pixel_rate_limit.unwrap_or(0),
+        args.pixel_rate_limit_mode,
+        args.pixel_rate_limit_ipv6_prefix_length,
+    ));
+
+    let network_buffer_size = args
+        .network_buffer_size
+        .try_into()
+        // This should never happen as clap checks the range for us
+        .context(InvalidNetworkBufferSizeSnafu {
+            network_buffer_size: args.network_buffer_size,
+        })?;
+    if let Some(memory_budget) = args.network_buffer_memory_budget {
+        ensure!(
+            memory_budget >= network_buffer_size,
+            NetworkBufferMemoryBudgetTooSmallSnafu {
+                memory_budget,
+                network_buffer_size,
+            }
+        );
+    }
+    let buffer_pool = Arc::new(BufferPool::new(
+        network_buffer_size,
+        args.network_buffer_memory_budget,
+    ));
 
     let statistics_save_mode = if args.disable_statistics_save_file {
         StatisticsSaveMode::Disabled
@@ -97,79 +325,621 @@ async fn main() -> Result<(), Error> {
         statistics_rx,
         statistics_information_tx,
         statistics_save_mode,
+        Arc::clone(&fb),
+        Arc::clone(&pixel_rate_limiter),
+        Some(Arc::clone(&buffer_pool)),
     );
 
-    let server = Server::new(
-        &args.listen_address,
-        Arc::clone(&fb),
-        statistics_tx.clone(),
-        args.network_buffer_size
-            .try_into()
-            // This should never happen as clap checks the range for us
-            .context(InvalidNetworkBufferSizeSnafu {
-                network_buffer_size: args.network_buffer_size,
-            })?,
-    )
-    .await
-    .context(StartPixelflutServerSnafu)?;
-    let mut prometheus_exporter = PrometheusExporter::new(
-        &args.prometheus_listen_address,
-        statistics_information_rx_for_prometheus_exporter,
-    )
-    .context(StartPrometheusExporterSnafu)?;
+    let prometheus_exporter = PrometheusExporter::new(&args.prometheus_listen_address)
+        .context(StartPrometheusExporterSnafu)?;
 
-    let server_listener_thread = tokio::spawn(async move { server.start().await });
-    let statistics_thread = tokio::spawn(async move { statistics.start().await });
-    let prometheus_exporter_thread = tokio::spawn(async move { prometheus_exporter.run().await });
+    // Servers and statistics are stopped separately, so that the statistics still see the closed connections
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (statistics_shutdown_tx, statistics_shutdown_rx) = watch::channel(false);
+    // The thread-per-core workers keep running the draining connections until this is set
+    let (workers_stop_tx, workers_stop_rx) = watch::channel(false);
 
+    let mut statistics_threads = vec![{
+        let statistics_shutdown_rx = statistics_shutdown_rx.clone();
+        tokio::spawn(async move { statistics.start(statistics_shutdown_rx).await })
+    }];
+    let prometheus_exporter_thread = {
+        let prometheus_exporter = prometheus_exporter.clone();
+        let canvas_name = args.canvas_name.clone();
+        tokio::spawn(async move {
+            prometheus_exporter
+                .run(
+                    &canvas_name,
+                    statistics_information_rx_for_prometheus_exporter,
+                )
+                .await
+        })
+    };
+
+    // Additional canvases only get the basics: server, statistics and VNC server
+    let mut canvases = Canvases::from([(
+        args.canvas_name.clone(),
+        Canvas {
+            fb: Arc::clone(&fb),
+            statistics_tx: statistics_tx.clone(),
+        },
+    )]);
+    let mut canvas_threads = Vec::new();
     #[cfg(feature = "vnc")]
-    let vnc_server_thread = {
-        let fb_for_vnc_server = Arc::clone(&fb);
-        let mut vnc_server = VncServer::new(
-            fb_for_vnc_server,
-            args.vnc_port,
-            args.fps,
-            statistics_tx,
-            statistics_information_rx_for_vnc_server,
-            vnc_terminate_signal_rx,
-            args.text,
-            args.font,
-        )
-        .context(StartVncServerSnafu)?;
-
-        // TODO Use tokio::spawn instead of std::thread::spawn
-        // I was not able to get to work with async closure
-        // We than also need to think about setting a priority
-        std::thread::Builder::new()
-            .name("breakwater vnc server thread".to_owned())
-            .spawn_with_priority(
-                ThreadPriority::Crossplatform(70.try_into().map_err(|err: &str| {
-                    Error::GetThreadPriority {
-                        message: err.to_string(),
+    let mut canvas_vnc_servers = Vec::new();
+    for canvas in &args.canvas {
+        ensure!(
+            !canvases.contains_key(&canvas.name),
+            DuplicateCanvasNameSnafu {
+                name: canvas.name.clone()
+            }
+        );
+
+        let canvas_fb = Arc::new(FrameBuffer::new(canvas.width, canvas.height));
+        let (canvas_statistics_tx, canvas_statistics_rx) = mpsc::channel::<StatisticsEvent>(100);
+        let (canvas_statistics_information_tx, canvas_statistics_information_rx) =
+            broadcast::channel::<StatisticsInformationEvent>(2);
+        #[cfg(feature = "vnc")]
+        let canvas_statistics_information_rx_for_vnc_server =
+            canvas_statistics_information_tx.subscribe();
+
+        let statistics_save_mode = if args.disable_statistics_save_file {
+            StatisticsSaveMode::Disabled
+        } else {
+            StatisticsSaveMode::Enabled {
+                save_file: canvas
+                    .statistics_save_file
+                    .clone()
+                    .unwrap_or_else(|| format!("statistics-{}.json", canvas.name)),
+                interval_s: args.statistics_save_interval_s,
+            }
+        };
+        let mut canvas_statistics = Statistics::new(
+            canvas_statistics_rx,
+            canvas_statistics_information_tx,
+            statistics_save_mode,
+            Arc::clone(&canvas_fb),
+            Arc::clone(&pixel_rate_limiter),
+            // The network buffers are reported by the statistics of the initial canvas
+            None,
+        );
+        canvases.insert(
+            canvas.name.clone(),
+            Canvas {
+                fb: Arc::clone(&canvas_fb),
+                statistics_tx: canvas_statistics_tx.clone(),
+            },
+        );
+        info!(
+            "Created canvas {:?} ({}x{})",
+            canvas.name, canvas.width, canvas.height
+        );
+
+        let statistics_shutdown_rx = statistics_shutdown_rx.clone();
+        statistics_threads.push(tokio::spawn(async move {
+            canvas_statistics.start(statistics_shutdown_rx).await
+        }));
+        let prometheus_exporter = prometheus_exporter.clone();
+        let canvas_name = canvas.name.clone();
+        canvas_threads.push(
+            tokio::spawn(async move {
+                prometheus_exporter
+                    .run(&canvas_name, canvas_statistics_information_rx)
+                    .await
+            })
+            .abort_handle(),
+        );
+
+        #[cfg(feature = "vnc")]
+        if let Some(vnc_port) = canvas.vnc_port {
+            canvas_vnc_servers.push(spawn_vnc_server(
+                canvas_fb,
+                vnc_port,
+                args.fps,
+                VncView::Canvas,
+                canvas_statistics_tx,
+                canvas_statistics_information_rx_for_vnc_server,
+                format!("{} ({})", args.text, canvas.name),
+                args.font.clone(),
+            )?);
+        }
+    }
+
+    // All servers need to know all canvases, so that clients can switch between them
+    let canvases = Arc::new(canvases);
+    let connection_limits = Arc::new(ConnectionLimits::new(
+        args.max_connections,
+        args.max_connections_per_ip,
+        args.max_connections_per_ipv6_prefix,
+        args.connection_limit_ipv6_prefix_length,
+    ));
+    let access_list = match &args.access_list {
+        Some(path) => Some(Arc::new(
+            AccessList::load(path.clone()).context(LoadAccessListSnafu)?,
+        )),
+        None => None,
+    };
+    let access_list_reload_thread = match &access_list {
+        Some(access_list) => {
+            let access_list = Arc::clone(access_list);
+            let mut hangup = signal(SignalKind::hangup()).context(ListenForHangupSignalSnafu)?;
+            Some(tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    match access_list.reload() {
+                        Ok(()) => info!("Reloaded access list from {:?}", access_list.path()),
+                        Err(err) => {
+                            warn!("Failed to reload access list, keeping the previous one: {err}")
+                        }
                     }
-                })?),
-                move |_| vnc_server.run().context(StartVncServerSnafu),
+                }
+            }))
+        }
+        None => None,
+    };
+    let connection_timeouts = ConnectionTimeouts {
+        idle: args.idle_timeout_s.map(Duration::from_secs),
+        min_bytes_per_s: args.min_throughput_bytes_per_s,
+        min_throughput_window: Duration::from_secs(args.min_throughput_window_s),
+        max_lifetime: args.max_connection_lifetime_s.map(Duration::from_secs),
+    };
+    let output_limit = OutputLimit {
+        max_size: args.output_buffer_size,
+        overflow_policy: args.output_overflow_policy,
+    };
+    let trusted_proxies: Arc<[IpNet]> = args.proxy_protocol_trusted_source.clone().into();
+    let mut server_listener_threads = Vec::new();
+    let mut thread_per_core_listen_addresses = Vec::new();
+    #[cfg(feature = "io-uring")]
+    let mut io_uring_server_threads = Vec::new();
+    #[cfg(feature = "io-uring")]
+    if args.io_uring {
+        let io_uring_listen_addresses = args
+            .listen_address
+            .iter()
+            .filter(|listen_address| matches!(listen_address.endpoint, ListenEndpoint::Tcp(_)))
+            .count();
+        io_uring::ensure_locked_memory_limit(
+            io_uring_listen_addresses as libc::rlim_t
+                * IoUringServer::locked_memory(
+                    args.io_uring_max_connections.into(),
+                    args.io_uring_buffer_size as usize,
+                ),
+        )
+        .context(StartIoUringServerSnafu)?;
+    }
+    for listen_address in &args.listen_address {
+        #[cfg(feature = "io-uring")]
+        if args.io_uring && matches!(listen_address.endpoint, ListenEndpoint::Tcp(_)) {
+            let io_uring_server = IoUringServer::new(
+                listen_address,
+                args.canvas_name.clone(),
+                Arc::clone(&canvases),
+                Arc::clone(&connection_limits),
+                args.send_connection_limit_reason,
+                access_list.clone(),
+                Arc::clone(&pixel_rate_limiter),
+                args.io_uring_max_connections.into(),
+                args.io_uring_buffer_size as usize,
+            )
+            .context(StartIoUringServerSnafu)?;
+            let shutdown_rx = shutdown_rx.clone();
+            io_uring_server_threads.push(
+                std::thread::Builder::new()
+                    .name(format!("breakwater io_uring {listen_address}"))
+                    .spawn(move || io_uring_server.run(shutdown_rx))
+                    .context(SpawnIoUringServerThreadSnafu)?,
+            );
+            continue;
+        }
+        if args.thread_per_core && matches!(listen_address.endpoint, ListenEndpoint::Tcp(_)) {
+            thread_per_core_listen_addresses.push(listen_address.clone());
+            continue;
+        }
+        let server = Server::new(
+            listen_address,
+            args.canvas_name.clone(),
+            Arc::clone(&canvases),
+            Arc::clone(&buffer_pool),
+            Arc::clone(&connection_limits),
+            args.send_connection_limit_reason,
+            access_list.clone(),
+            Arc::clone(&pixel_rate_limiter),
+            connection_timeouts,
+            output_limit,
+            Transport::Plain,
+            Arc::clone(&trusted_proxies),
+            false,
+        )
+        .await
+        .context(StartPixelflutServerSnafu)?;
+        let shutdown_rx = shutdown_rx.clone();
+        server_listener_threads.push(tokio::spawn(async move { server.start(shutdown_rx).await }));
+    }
+    let thread_per_core_workers = if thread_per_core_listen_addresses.is_empty() {
+        Vec::new()
+    } else {
+        ThreadPerCoreServer::new(
+            thread_per_core_listen_addresses,
+            args.canvas_name.clone(),
+            Arc::clone(&canvases),
+            Arc::clone(&buffer_pool),
+            Arc::clone(&connection_limits),
+            args.send_connection_limit_reason,
+            access_list.clone(),
+            Arc::clone(&pixel_rate_limiter),
+            connection_timeouts,
+            output_limit,
+            Arc::clone(&trusted_proxies),
+            args.worker_cores.clone(),
+            args.worker_thread_priority,
+        )
+        .start(shutdown_rx.clone(), workers_stop_rx)
+        .await
+        .context(StartThreadPerCoreWorkersSnafu)?
+    };
+    let websocket_server_thread = match &args.websocket_listen_address {
+        Some(websocket_listen_address) => {
+            let websocket_server = Server::new(
+                websocket_listen_address,
+                args.canvas_name.clone(),
+                Arc::clone(&canvases),
+                Arc::clone(&buffer_pool),
+                Arc::clone(&connection_limits),
+                args.send_connection_limit_reason,
+                access_list.clone(),
+                Arc::clone(&pixel_rate_limiter),
+                connection_timeouts,
+                output_limit,
+                Transport::WebSocket,
+                Arc::clone(&trusted_proxies),
+                false,
+            )
+            .await
+            .context(StartWebSocketServerSnafu)?;
+            let shutdown_rx = shutdown_rx.clone();
+            Some(tokio::spawn(async move {
+                websocket_server.start(shutdown_rx).await
+            }))
+        }
+        None => None,
+    };
+    let tls_server_thread = match (
+        &args.tls_listen_address,
+        &args.tls_certificate,
+        &args.tls_private_key,
+    ) {
+        (Some(tls_listen_address), Some(tls_certificate), Some(tls_private_key)) => {
+            let tls_acceptor = tls::load_acceptor(
+                tls_certificate,
+                tls_private_key,
+                args.tls_client_ca.as_deref(),
+            )
+            .context(LoadTlsCertificateSnafu)?;
+            let tls_server = Server::new(
+                tls_listen_address,
+                args.canvas_name.clone(),
+                Arc::clone(&canvases),
+                Arc::clone(&buffer_pool),
+                Arc::clone(&connection_limits),
+                args.send_connection_limit_reason,
+                access_list.clone(),
+                Arc::clone(&pixel_rate_limiter),
+                connection_timeouts,
+                output_limit,
+                Transport::Tls(tls_acceptor),
+                Arc::clone(&trusted_proxies),
+                false,
+            )
+            .await
+            .context(StartTlsServerSnafu)?;
+            let shutdown_rx = shutdown_rx.clone();
+            Some(tokio::spawn(
+                async move { tls_server.start(shutdown_rx).await },
+            ))
+        }
+        // clap makes sure the certificate and private key are set together with the listen address
+        _ => None,
+    };
+    let udp_server_thread = match &args.udp_listen_address {
+        Some(udp_listen_address) => {
+            let udp_server = UdpServer::new(
+                udp_listen_address,
+                Arc::clone(&fb),
+                statistics_tx.clone(),
+                args.udp_send_responses,
+                access_list.clone(),
+                Arc::clone(&pixel_rate_limiter),
             )
+            .await
+            .context(StartUdpServerSnafu)?;
+            let shutdown_rx = shutdown_rx.clone();
+            Some(tokio::spawn(
+                async move { udp_server.start(shutdown_rx).await },
+            ))
+        }
+        None => None,
+    };
+    for canvas in &args.canvas {
+        if let Some(listen_address) = &canvas.listen_address {
+            let canvas_server = Server::new(
+                listen_address,
+                canvas.name.clone(),
+                Arc::clone(&canvases),
+                Arc::clone(&buffer_pool),
+                Arc::clone(&connection_limits),
+                args.send_connection_limit_reason,
+                access_list.clone(),
+                Arc::clone(&pixel_rate_limiter),
+                connection_timeouts,
+                output_limit,
+                Transport::Plain,
+                Arc::clone(&trusted_proxies),
+                false,
+            )
+            .await
+            .context(StartCanvasServerSnafu {
+                name: canvas.name.clone(),
+            })?;
+            let shutdown_rx = shutdown_rx.clone();
+            canvas_threads.push(
+                tokio::spawn(async move { canvas_server.start(shutdown_rx).await }).abort_handle(),
+            );
+        }
     }
-    .context(SpawnVncServerThreadSnafu)?;
 
-    tokio::signal::ctrl_c()
-        .await
-        .context(WaitForCtrlCSignalSnafu)?;
+    let heatmap_updater = HeatmapUpdater::new(
+        Arc::clone(&fb),
+        Duration::from_secs(args.heatmap_half_life_s),
+        args.heatmap_export_file
+            .clone()
+            .map(|export_file| HeatmapExportConfig {
+                export_file,
+                interval: Duration::from_secs(args.heatmap_export_interval_s),
+            }),
+    );
+    let heatmap_updater_thread = tokio::spawn(async move { heatmap_updater.run().await });
 
-    prometheus_exporter_thread.abort();
-    server_listener_thread.abort();
-    statistics_thread.abort();
+    let shared_memory_frame_counter_thread = fb.shared_memory().is_some().then(|| {
+        let frame_counter = SharedMemoryFrameCounter::new(Arc::clone(&fb), args.fps);
+        tokio::spawn(async move { frame_counter.run().await })
+    });
+
+    let decay_thread = args.decay_half_life_s.map(|decay_half_life_s| {
+        let decay = CanvasDecay::new(
+            Arc::clone(&fb),
+            args.decay_background_color,
+            Duration::from_secs(decay_half_life_s),
+            Duration::from_millis(args.decay_sweep_interval_ms),
+        );
+        tokio::spawn(async move { decay.run().await })
+    });
+
+    let history = match args.history_interval_s {
+        Some(history_interval_s) => Some(Arc::new(
+            CanvasHistory::new(
+                Arc::clone(&fb),
+                Duration::from_secs(history_interval_s),
+                args.history_size,
+                match &args.history_directory {
+                    Some(directory) => HistoryStorage::Disk {
+                        directory: directory.into(),
+                    },
+                    None => HistoryStorage::Memory,
+                },
+            )
+            .context(SetupCanvasHistorySnafu)?,
+        )),
+        None => None,
+    };
+    let history_thread = history
+        .clone()
+        .map(|history| tokio::spawn(async move { history.run().await }));
+
+    let admin_server_thread = match &args.admin_listen_address {
+        Some(admin_listen_address) => {
+            let admin_server = AdminServer::new(
+                admin_listen_address,
+                AdminContext {
+                    fb: Arc::clone(&fb),
+                    history: history.clone(),
+                    access_list,
+                    pixel_rate_limiter,
+                },
+            )
+            .await
+            .context(StartAdminServerSnafu)?;
+            Some(tokio::spawn(async move { admin_server.start().await }))
+        }
+        None => None,
+    };
 
     #[cfg(feature = "vnc")]
+    let mut vnc_servers = vec![spawn_vnc_server(
+        Arc::clone(&fb),
+        args.vnc_port,
+        args.fps,
+        VncView::Canvas,
+        statistics_tx.clone(),
+        statistics_information_rx_for_vnc_server,
+        args.text.clone(),
+        args.font.clone(),
+    )?];
+    #[cfg(feature = "vnc")]
+    if let Some(heatmap_vnc_port) = args.heatmap_vnc_port {
+        vnc_servers.push(spawn_vnc_server(
+            Arc::clone(&fb),
+            heatmap_vnc_port,
+            args.fps,
+            VncView::Heatmap,
+            statistics_tx.clone(),
+            statistics_information_rx_for_heatmap_vnc_server,
+            format!("{} (heatmap)", args.text),
+            args.font.clone(),
+        )?);
+    }
+    #[cfg(feature = "vnc")]
+    for (index, (tile, statistics_information_rx)) in video_wall_tiles.into_iter().enumerate() {
+        vnc_servers.push(spawn_vnc_server(
+            Arc::clone(&fb),
+            args.video_wall_first_vnc_port + index as u16,
+            args.fps,
+            VncView::Tile(tile),
+            statistics_tx.clone(),
+            statistics_information_rx,
+            args.text.clone(),
+            args.font.clone(),
+        )?);
+    }
+
+    let mut terminate = signal(SignalKind::terminate()).context(ListenForTerminateSignalSnafu)?;
+    tokio::select! {
+        ctrl_c = tokio::signal::ctrl_c() => ctrl_c.context(WaitForCtrlCSignalSnafu)?,
+        _ = terminate.recv() => {}
+    }
+
+    // Drain: stop accepting and let the connections finish what they are parsing
+    info!("Shutting down, waiting for client connections to close");
+    let _ = shutdown_tx.send(true);
+    for server_listener_thread in server_listener_threads {
+        let _ = server_listener_thread.await;
+    }
+    #[cfg(feature = "io-uring")]
+    for io_uring_server_thread in io_uring_server_threads {
+        if let Ok(Ok(Err(err))) =
+            tokio::task::spawn_blocking(move || io_uring_server_thread.join()).await
+        {
+            warn!("io_uring server failed: {err}");
+        }
+    }
+    if let Some(websocket_server_thread) = websocket_server_thread {
+        let _ = websocket_server_thread.await;
+    }
+    if let Some(tls_server_thread) = tls_server_thread {
+        let _ = tls_server_thread.await;
+    }
+    if let Some(udp_server_thread) = udp_server_thread {
+        let _ = udp_server_thread.await;
+    }
+    let drain_deadline = Instant::now() + Duration::from_secs(args.shutdown_timeout_s);
+    while connection_limits.connections() > 0 && Instant::now() < drain_deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let open_connections = connection_limits.connections();
+    if open_connections > 0 {
+        warn!("{open_connections} client connections did not close in time, dropping them");
+    }
+    let _ = workers_stop_tx.send(true);
+    for thread_per_core_worker in thread_per_core_workers {
+        if let Ok(Ok(Err(err))) =
+            tokio::task::spawn_blocking(move || thread_per_core_worker.join()).await
+        {
+            warn!("Thread-per-core worker failed: {err}");
+        }
+    }
+
+    // Flush the statistics save files and the canvas history
+    let _ = statistics_shutdown_tx.send(true);
+    for statistics_thread in statistics_threads {
+        match statistics_thread.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => warn!("Failed to save statistics: {err}"),
+            Err(err) => warn!("Statistics task failed: {err}"),
+        }
+    }
+    if let Some(history_thread) = history_thread {
+        history_thread.abort();
+    }
+    if let Some(history) = history {
+        match tokio::task::spawn_blocking(move || history.take_snapshot()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => warn!("Failed to take snapshot of the canvas: {err}"),
+            Err(err) => warn!("Snapshot task failed: {err}"),
+        }
+    }
+
+    // Stop the sinks: the VNC servers first, then everything else
+    #[cfg(feature = "vnc")]
+    for (vnc_terminate_signal_tx, vnc_server_thread) in
+        vnc_servers.into_iter().chain(canvas_vnc_servers)
     {
-        vnc_terminate_signal_tx
+        // A VNC server that failed already must not keep the remaining ones and the other sinks running
+        if vnc_terminate_signal_tx
             .send("bye bye vnc".to_string())
-            .map_err(|_| Error::SendVncServerShutdownSignal {})?;
-        vnc_server_thread
-            .join()
-            .map_err(|_| Error::StopVncServerThread {})??;
+            .is_err()
+        {
+            warn!("{}", Error::SendVncServerShutdownSignal {});
+        }
+        match vnc_server_thread.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => warn!("VNC server failed: {err}"),
+            Err(_) => warn!("{}", Error::StopVncServerThread {}),
+        }
+    }
+
+    prometheus_exporter_thread.abort();
+    heatmap_updater_thread.abort();
+    for canvas_thread in canvas_threads {
+        canvas_thread.abort();
+    }
+    if let Some(shared_memory_frame_counter_thread) = shared_memory_frame_counter_thread {
+        shared_memory_frame_counter_thread.abort();
+    }
+    if let Some(decay_thread) = decay_thread {
+        decay_thread.abort();
+    }
+    if let Some(access_list_reload_thread) = access_list_reload_thread {
+        access_list_reload_thread.abort();
+    }
+    if let Some(admin_server_thread) = admin_server_thread {
+        admin_server_thread.abort();
     }
 
     Ok(())
 }
+
+#[cfg(feature = "vnc")]
+type VncServerHandle = (oneshot::Sender<String>, JoinHandle<Result<(), Error>>);
+
+#[cfg(feature = "vnc")]
+#[allow(clippy::too_many_arguments)]
+fn spawn_vnc_server(
+    fb: Arc<FrameBuffer>,
+    port: u16,
+    fps: u32,
+    view: VncView,
+    statistics_tx: mpsc::Sender<StatisticsEvent>,
+    statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
+    text: String,
+    font: String,
+) -> Result<VncServerHandle, Error> {
+    let (vnc_terminate_signal_tx, vnc_terminate_signal_rx) = oneshot::channel();
+    let mut vnc_server = VncServer::new(
+        fb,
+        port,
+        fps,
+        view,
+        statistics_tx,
+        statistics_information_rx,
+        vnc_terminate_signal_rx,
+        text,
+        font,
+    )
+    .context(StartVncServerSnafu)?;
+
+    // TODO Use tokio::spawn instead of std::thread::spawn
+    // I was not able to get to work with async closure
+    // We than also need to think about setting a priority
+    let vnc_server_thread = std::thread::Builder::new()
+        .name(format!("breakwater vnc server thread (port {port})"))
+        .spawn_with_priority(
+            ThreadPriority::Crossplatform(70.try_into().map_err(|err: &str| {
+                Error::GetThreadPriority {
+                    message: err.to_string(),
+                }
+            })?),
+            move |_| vnc_server.run().context(StartVncServerSnafu),
+        )
+        .context(SpawnVncServerThreadSnafu)?;
+
+    Ok((vnc_terminate_signal_tx, vnc_server_thread))
+}
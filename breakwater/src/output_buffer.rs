@@ -0,0 +1,189 @@
+use std::{
+    fmt::Display,
+    future::poll_fn,
+    io,
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+};
+
+use futures_util::FutureExt;
+use tokio::io::AsyncWrite;
+
+pub const DEFAULT_OUTPUT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// What happens with responses once the output buffer of a connection is full, as the client doesn't read them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputOverflowPolicy {
+    /// Responses that don't fit are dropped, the client keeps drawing
+    Drop,
+    /// Parsing stops until the client has read enough responses
+    Pause,
+    /// The connection is closed
+    Disconnect,
+}
+
+impl FromStr for OutputOverflowPolicy {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "drop" => Ok(OutputOverflowPolicy::Drop),
+            "pause" => Ok(OutputOverflowPolicy::Pause),
+            "disconnect" => Ok(OutputOverflowPolicy::Disconnect),
+            _ => Err(format!(
+                "Unknown output overflow policy {input:?}, expected \"drop\", \"pause\" or \"disconnect\""
+            )),
+        }
+    }
+}
+
+impl Display for OutputOverflowPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OutputOverflowPolicy::Drop => "drop",
+            OutputOverflowPolicy::Pause => "pause",
+            OutputOverflowPolicy::Disconnect => "disconnect",
+        })
+    }
+}
+
+/// Limits the responses buffered per connection
+#[derive(Clone, Copy, Debug)]
+pub struct OutputLimit {
+    pub max_size: usize,
+    pub overflow_policy: OutputOverflowPolicy,
+}
+
+impl Default for OutputLimit {
+    fn default() -> Self {
+        OutputLimit {
+            max_size: DEFAULT_OUTPUT_BUFFER_SIZE,
+            overflow_policy: OutputOverflowPolicy::Pause,
+        }
+    }
+}
+
+/// Collects the responses of a connection and writes them to the client in bulk.
+///
+/// At most `max_size` bytes are buffered, once that is exceeded the [`OutputOverflowPolicy`] applies. For
+/// [`OutputOverflowPolicy::Disconnect`] writing fails and [`BoundedOutput::overflowed`] is set.
+pub struct BoundedOutput<W> {
+    writer: W,
+    buffer: Vec<u8>,
+    /// Bytes at the start of the buffer that were already written
+    written: usize,
+    max_size: usize,
+    policy: OutputOverflowPolicy,
+    dropped_responses: u64,
+    overflowed: bool,
+}
+
+impl<W: AsyncWrite + Unpin> BoundedOutput<W> {
+    pub fn new(writer: W, limit: OutputLimit) -> Self {
+        BoundedOutput {
+            writer,
+            buffer: Vec::new(),
+            written: 0,
+            max_size: limit.max_size,
+            policy: limit.overflow_policy,
+            dropped_responses: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Number of buffered bytes not written to the client yet
+    pub fn pending(&self) -> usize {
+        self.buffer.len() - self.written
+    }
+
+    /// Whether writing failed, as the buffer was full and the policy is [`OutputOverflowPolicy::Disconnect`]
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// Returns the number of responses dropped since the last call
+    pub fn take_dropped_responses(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped_responses)
+    }
+
+    /// Discards the buffered responses, e.g. before closing the connection of a client that doesn't read them
+    pub fn discard(&mut self) {
+        self.buffer.clear();
+        self.written = 0;
+    }
+
+    /// Writes as much of the buffered responses as possible without waiting for the client
+    pub fn write_pending(&mut self) -> io::Result<()> {
+        self.drain().now_or_never().unwrap_or(Ok(()))
+    }
+
+    /// Waits until all buffered responses are written
+    pub async fn drain(&mut self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_drain(cx)).await
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.buffer.len() {
+            let written =
+                ready!(Pin::new(&mut self.writer).poll_write(cx, &self.buffer[self.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += written;
+        }
+        self.discard();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for BoundedOutput<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.pending() + buf.len() > this.max_size {
+            // Make room if the client read something in the meantime. If it didn't, we are woken up once it does.
+            if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+                return Poll::Ready(Err(err));
+            }
+        }
+
+        // A single response bigger than the buffer still fits into an empty one
+        if this.pending() == 0 || this.pending() + buf.len() <= this.max_size {
+            if this.written > 0 && this.buffer.len() + buf.len() > this.max_size {
+                this.buffer.drain(..this.written);
+                this.written = 0;
+            }
+            this.buffer.extend_from_slice(buf);
+            return Poll::Ready(Ok(buf.len()));
+        }
+        match this.policy {
+            OutputOverflowPolicy::Drop => {
+                this.dropped_responses += 1;
+                Poll::Ready(Ok(buf.len()))
+            }
+            OutputOverflowPolicy::Pause => Poll::Pending,
+            OutputOverflowPolicy::Disconnect => {
+                this.overflowed = true;
+                Poll::Ready(Err(io::Error::other(
+                    "the client does not read its responses",
+                )))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.writer).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use breakwater_core::ip::client_address;
+
+/// Shortest time a throttled connection waits for new pixels, so that it doesn't wake up for every single pixel
+const MIN_THROTTLE_DURATION: Duration = Duration::from_millis(10);
+
+/// Shortest command drawing a pixel: `PX 0 0 gg\n`
+const MIN_PIXEL_COMMAND_LENGTH: usize = 10;
+
+/// Upper bound of the pixels parsing `bytes` bytes of Pixelflut commands can draw
+pub fn max_pixels(bytes: usize) -> u64 {
+    (bytes / MIN_PIXEL_COMMAND_LENGTH + 1) as u64
+}
+
+/// What happens with pixels exceeding the rate limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelRateLimitMode {
+    /// The pixels are parsed but not drawn
+    Drop,
+    /// The connection is not read from until there are new pixels available
+    Throttle,
+}
+
+impl FromStr for PixelRateLimitMode {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "drop" => Ok(PixelRateLimitMode::Drop),
+            "throttle" => Ok(PixelRateLimitMode::Throttle),
+            _ => Err(format!(
+                "invalid pixel rate limit mode {input:?}, expected drop or throttle"
+            )),
+        }
+    }
+}
+
+/// Token bucket limiting the pixels per second every client can draw, shared by all connections of the client.
+///
+/// Connections take the pixels they can draw at most (see [`max_pixels`]) from the bucket before parsing and give back
+/// what they did not use, so that the parser itself only needs to count down. Other connections of the client can use
+/// the rest of the bucket in the meantime.
+pub struct PixelRateLimiter {
+    /// `0` means unlimited
+    pixels_per_s: AtomicU64,
+    mode: PixelRateLimitMode,
+    ipv6_prefix_length: u8,
+    /// Clients without a bucket have a full one
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+struct TokenBucket {
+    pixels: f64,
+    last_refill: Instant,
+}
+
+impl PixelRateLimiter {
+    /// IPv6 clients are grouped by the first `ipv6_prefix_length` bits of their address,
+    /// as a single client can usually use all the addresses of e.g. a /64.
+    pub fn new(pixels_per_s: u64, mode: PixelRateLimitMode, ipv6_prefix_length: u8) -> Self {
+        PixelRateLimiter {
+            pixels_per_s: AtomicU64::new(pixels_per_s),
+            mode,
+            ipv6_prefix_length: ipv6_prefix_length.min(128),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// `None` means unlimited
+    pub fn pixels_per_s(&self) -> Option<u64> {
+        match self.pixels_per_s.load(Ordering::Relaxed) {
+            0 => None,
+            pixels_per_s => Some(pixels_per_s),
+        }
+    }
+
+    /// Changes the limit for all clients, `None` means unlimited
+    pub fn set_pixels_per_s(&self, pixels_per_s: Option<u64>) {
+        self.pixels_per_s
+            .store(pixels_per_s.unwrap_or(0), Ordering::Relaxed);
+        self.buckets.lock().unwrap().clear();
+    }
+
+    pub fn mode(&self) -> PixelRateLimitMode {
+        self.mode
+    }
+
+    /// Takes up to `max` of the pixels available to the client. The bucket holds at most one second worth of pixels.
+    pub fn take(&self, ip: IpAddr, max: u64) -> u64 {
+        let Some(pixels_per_s) = self.pixels_per_s() else {
+            return u64::MAX;
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(self.client(ip)).or_insert(TokenBucket {
+            pixels: pixels_per_s as f64,
+            last_refill: Instant::now(),
+        });
+        bucket.refill(pixels_per_s);
+
+        let pixels = bucket.pixels.floor().min(max as f64);
+        bucket.pixels -= pixels;
+        pixels as u64
+    }
+
+    /// Returns the pixels the client took but did not draw
+    pub fn give_back(&self, ip: IpAddr, pixels: u64) {
+        let Some(pixels_per_s) = self.pixels_per_s() else {
+            return;
+        };
+
+        let client = self.client(ip);
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get_mut(&client) {
+            bucket.refill(pixels_per_s);
+            bucket.pixels += pixels as f64;
+            // Full buckets are not needed, this keeps the map from growing with every client ever seen
+            if bucket.pixels >= pixels_per_s as f64 {
+                buckets.remove(&client);
+            }
+        }
+    }
+
+    /// Waits until new pixels are available to the client
+    pub async fn wait_for_pixels(&self, ip: IpAddr) {
+        loop {
+            let pixels = self.take(ip, 1);
+            if pixels > 0 {
+                self.give_back(ip, pixels);
+                return;
+            }
+
+            // The limit can change while waiting, so we don't calculate the exact time
+            let pixels_per_s = self.pixels_per_s().unwrap_or(u64::MAX);
+            tokio::time::sleep(
+                Duration::from_secs_f64(1.0 / pixels_per_s as f64).max(MIN_THROTTLE_DURATION),
+            )
+            .await;
+        }
+    }
+
+    fn client(&self, ip: IpAddr) -> IpAddr {
+        client_address(ip, self.ipv6_prefix_length)
+    }
+}
+
+impl TokenBucket {
+    fn refill(&mut self, pixels_per_s: u64) {
+        let now = Instant::now();
+        self.pixels = (self.pixels
+            + now.duration_since(self.last_refill).as_secs_f64() * pixels_per_s as f64)
+            .min(pixels_per_s as f64);
+        self.last_refill = now;
+    }
+}
@@ -1,8 +1,8 @@
-use std::net::AddrParseError;
+use std::{collections::HashMap, fmt::Display, hash::Hash, net::AddrParseError};
 
 use prometheus_exporter::{
     self,
-    prometheus::{register_int_gauge, register_int_gauge_vec, IntGauge, IntGaugeVec},
+    prometheus::{register_int_gauge_vec, IntGaugeVec},
 };
 use snafu::{ResultExt, Snafu};
 use tokio::sync::broadcast;
@@ -27,24 +27,35 @@ pub enum Error {
     },
 }
 
+/// Exports the statistics of all canvases, every metric has a `canvas` label
+#[derive(Clone)]
 pub struct PrometheusExporter {
-    statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
-
     // Prometheus metrics
-    metric_ips: IntGauge,
-    metric_legacy_ips: IntGauge,
-    metric_frame: IntGauge,
-    metric_statistic_events: IntGauge,
+    metric_ips: IntGaugeVec,
+    metric_legacy_ips: IntGaugeVec,
+    metric_frame: IntGaugeVec,
+    metric_statistic_events: IntGaugeVec,
+    metric_rejected_connections: IntGaugeVec,
+    metric_timed_out_connections: IntGaugeVec,
+    metric_failed_accepts: IntGaugeVec,
+    metric_file_descriptor_limit_reached: IntGaugeVec,
+    metric_output_overflow_disconnects: IntGaugeVec,
+    metric_dropped_responses: IntGaugeVec,
+    metric_failed_datagram_receives: IntGaugeVec,
+    metric_network_buffer_bytes: IntGaugeVec,
+    metric_network_buffer_bytes_in_use: IntGaugeVec,
+    metric_network_buffer_memory_budget: IntGaugeVec,
+    metric_connections_waiting_for_network_buffer: IntGaugeVec,
 
     metric_connections_for_ip: IntGaugeVec,
     metric_bytes_for_ip: IntGaugeVec,
+    metric_pixels_for_ip: IntGaugeVec,
+    metric_connections_for_listener: IntGaugeVec,
+    metric_bytes_for_listener: IntGaugeVec,
 }
 
 impl PrometheusExporter {
-    pub fn new(
-        listen_addr: &str,
-        statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
-    ) -> Result<Self, Error> {
+    pub fn new(listen_addr: &str) -> Result<Self, Error> {
         let listen_addr = listen_addr.parse().context(ParseListenAddressSnafu {
             listen_address: listen_addr.to_string(),
         })?;
@@ -52,66 +63,228 @@ impl PrometheusExporter {
         prometheus_exporter::start(listen_addr).context(StartPrometheusServerSnafu)?;
 
         Ok(PrometheusExporter {
-            statistics_information_rx,
-            metric_legacy_ips: register_int_gauge(
+            metric_legacy_ips: register_int_gauge_vec(
                 "breakwater_ips",
                 "Total number of IPs connected",
+                &["canvas"],
             )?,
-            metric_ips: register_int_gauge(
+            metric_ips: register_int_gauge_vec(
                 "breakwater_legacy_ips",
                 "Total number of legacy (v4) IPs connected",
+                &["canvas"],
+            )?,
+            metric_frame: register_int_gauge_vec(
+                "breakwater_frame",
+                "Frame number of the VNC server",
+                &["canvas"],
             )?,
-            metric_frame: register_int_gauge("breakwater_frame", "Frame number of the VNC server")?,
-            metric_statistic_events: register_int_gauge(
+            metric_statistic_events: register_int_gauge_vec(
                 "breakwater_statistic_events",
                 "Number of statistics events send internally",
+                &["canvas"],
+            )?,
+            metric_rejected_connections: register_int_gauge_vec(
+                "breakwater_rejected_connections",
+                "Number of client connections rejected because of a connection limit",
+                &["canvas", "limit"],
+            )?,
+            metric_timed_out_connections: register_int_gauge_vec(
+                "breakwater_timed_out_connections",
+                "Number of client connections closed by the server because they were idle, too slow or open for too long",
+                &["canvas", "reason"],
+            )?,
+            metric_failed_accepts: register_int_gauge_vec(
+                "breakwater_failed_accepts",
+                "Number of times accepting a new client connection failed",
+                &["canvas", "reason"],
+            )?,
+            metric_file_descriptor_limit_reached: register_int_gauge_vec(
+                "breakwater_file_descriptor_limit_reached",
+                "1 while no new client connections can be accepted, as the process is out of file descriptors",
+                &["canvas"],
+            )?,
+            metric_output_overflow_disconnects: register_int_gauge_vec(
+                "breakwater_output_overflow_disconnects",
+                "Number of client connections closed by the server because the client did not read its responses",
+                &["canvas"],
+            )?,
+            metric_dropped_responses: register_int_gauge_vec(
+                "breakwater_dropped_responses",
+                "Number of responses not sent because the client did not read the previous ones",
+                &["canvas"],
+            )?,
+            metric_failed_datagram_receives: register_int_gauge_vec(
+                "breakwater_failed_datagram_receives",
+                "Number of times receiving a Pixelflut UDP datagram failed",
+                &["canvas"],
+            )?,
+            metric_network_buffer_bytes: register_int_gauge_vec(
+                "breakwater_network_buffer_bytes",
+                "Memory of the network buffers of all connections, including the idle buffers kept for reuse",
+                &["canvas"],
+            )?,
+            metric_network_buffer_bytes_in_use: register_int_gauge_vec(
+                "breakwater_network_buffer_bytes_in_use",
+                "Memory of the network buffers currently used by connections",
+                &["canvas"],
+            )?,
+            metric_network_buffer_memory_budget: register_int_gauge_vec(
+                "breakwater_network_buffer_memory_budget_bytes",
+                "Maximum memory of the network buffers of all connections. Only set if there is a budget",
+                &["canvas"],
+            )?,
+            metric_connections_waiting_for_network_buffer: register_int_gauge_vec(
+                "breakwater_connections_waiting_for_network_buffer",
+                "Number of client connections waiting for a network buffer, as the memory budget is used up",
+                &["canvas"],
             )?,
             metric_connections_for_ip: register_int_gauge_vec(
                 "breakwater_connections",
                 "Number of client connections per IP address",
-                &["ip"],
+                &["canvas", "ip"],
             )?,
             metric_bytes_for_ip: register_int_gauge_vec(
                 "breakwater_bytes",
                 "Number of bytes received per IP address",
-                &["ip"],
+                &["canvas", "ip"],
+            )?,
+            metric_pixels_for_ip: register_int_gauge_vec(
+                "breakwater_pixels_owned",
+                "Number of pixels currently owned per IP address (or IPv6 prefix). Only filled if pixel ownership tracking is enabled",
+                &["canvas", "ip"],
+            )?,
+            metric_connections_for_listener: register_int_gauge_vec(
+                "breakwater_listener_connections",
+                "Number of client connections per tagged listener",
+                &["canvas", "listener"],
+            )?,
+            metric_bytes_for_listener: register_int_gauge_vec(
+                "breakwater_listener_bytes",
+                "Number of bytes received per tagged listener",
+                &["canvas", "listener"],
             )?,
         })
     }
 
-    pub async fn run(&mut self) {
-        while let Ok(event) = self.statistics_information_rx.recv().await {
-            self.metric_ips.set(event.ips as i64);
-            self.metric_legacy_ips.set(event.legacy_ips as i64);
-            self.metric_frame.set(event.frame as i64);
+    /// Exports the statistics of the given canvas. Call it once per canvas.
+    pub async fn run(
+        &self,
+        canvas: &str,
+        mut statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
+    ) {
+        let mut previous_event = StatisticsInformationEvent::default();
+
+        while let Ok(event) = statistics_information_rx.recv().await {
+            self.metric_ips
+                .with_label_values(&[canvas])
+                .set(event.ips as i64);
+            self.metric_legacy_ips
+                .with_label_values(&[canvas])
+                .set(event.legacy_ips as i64);
+            self.metric_frame
+                .with_label_values(&[canvas])
+                .set(event.frame as i64);
             self.metric_statistic_events
+                .with_label_values(&[canvas])
                 .set(event.statistic_events as i64);
+            for (limit, rejected_connections) in &event.rejected_connections {
+                self.metric_rejected_connections
+                    .with_label_values(&[canvas, limit.label()])
+                    .set(*rejected_connections as i64);
+            }
+            for (timeout, timed_out_connections) in &event.timed_out_connections {
+                self.metric_timed_out_connections
+                    .with_label_values(&[canvas, timeout.label()])
+                    .set(*timed_out_connections as i64);
+            }
+            for (error, failed_accepts) in &event.failed_accepts {
+                self.metric_failed_accepts
+                    .with_label_values(&[canvas, error.label()])
+                    .set(*failed_accepts as i64);
+            }
+            self.metric_file_descriptor_limit_reached
+                .with_label_values(&[canvas])
+                .set(event.file_descriptor_limit_reached as i64);
+            self.metric_output_overflow_disconnects
+                .with_label_values(&[canvas])
+                .set(event.output_overflow_disconnects as i64);
+            self.metric_dropped_responses
+                .with_label_values(&[canvas])
+                .set(event.dropped_responses as i64);
+            self.metric_failed_datagram_receives
+                .with_label_values(&[canvas])
+                .set(event.failed_datagram_receives as i64);
+            if let Some(network_buffers) = &event.network_buffers {
+                self.metric_network_buffer_bytes
+                    .with_label_values(&[canvas])
+                    .set(network_buffers.bytes as i64);
+                self.metric_network_buffer_bytes_in_use
+                    .with_label_values(&[canvas])
+                    .set(network_buffers.bytes_in_use as i64);
+                if let Some(memory_budget) = network_buffers.memory_budget {
+                    self.metric_network_buffer_memory_budget
+                        .with_label_values(&[canvas])
+                        .set(memory_budget as i64);
+                }
+                self.metric_connections_waiting_for_network_buffer
+                    .with_label_values(&[canvas])
+                    .set(network_buffers.waiting_connections as i64);
+            }
 
-            // When clients drop a connection the item will be missing in `event.connections_for_ip,
-            // but would stay forever in the Prometheus metric
-            self.metric_connections_for_ip.reset();
-            event
-                .connections_for_ip
-                .iter()
-                .for_each(|(ip, connections)| {
-                    self.metric_connections_for_ip
-                        .with_label_values(&[&ip.to_string()])
-                        .set(*connections as i64)
-                });
-            self.metric_bytes_for_ip.reset();
-            event.bytes_for_ip.iter().for_each(|(ip, bytes)| {
-                self.metric_bytes_for_ip
-                    .with_label_values(&[&ip.to_string()])
-                    .set(*bytes as i64)
-            });
+            set_per_label(
+                &self.metric_connections_for_ip,
+                canvas,
+                &previous_event.connections_for_ip,
+                &event.connections_for_ip,
+            );
+            set_per_label(
+                &self.metric_bytes_for_ip,
+                canvas,
+                &previous_event.bytes_for_ip,
+                &event.bytes_for_ip,
+            );
+            set_per_label(
+                &self.metric_pixels_for_ip,
+                canvas,
+                &previous_event.pixels_for_ip,
+                &event.pixels_for_ip,
+            );
+            set_per_label(
+                &self.metric_connections_for_listener,
+                canvas,
+                &previous_event.connections_for_listener,
+                &event.connections_for_listener,
+            );
+            set_per_label(
+                &self.metric_bytes_for_listener,
+                canvas,
+                &previous_event.bytes_for_listener,
+                &event.bytes_for_listener,
+            );
+
+            previous_event = event;
         }
     }
 }
 
-fn register_int_gauge(name: &str, description: &str) -> Result<IntGauge, Error> {
-    register_int_gauge!(name, description).context(RegisterPrometheusGaugeSnafu {
-        name: name.to_string(),
-    })
+/// When clients drop a connection the item will be missing in e.g. `event.connections_for_ip`,
+/// but would stay forever in the Prometheus metric. We can't reset the whole metric, as it also contains the
+/// values of the other canvases.
+fn set_per_label<K: Display + Eq + Hash, T: Copy + Into<u64>>(
+    metric: &IntGaugeVec,
+    canvas: &str,
+    previous: &HashMap<K, T>,
+    current: &HashMap<K, T>,
+) {
+    for key in previous.keys().filter(|key| !current.contains_key(key)) {
+        // The label might not exist, e.g. on the first event after a restart, so we don't care about errors
+        let _ = metric.remove_label_values(&[canvas, &key.to_string()]);
+    }
+    for (key, value) in current {
+        metric
+            .with_label_values(&[canvas, &key.to_string()])
+            .set((*value).into() as i64);
+    }
 }
 
 fn register_int_gauge_vec(
@@ -0,0 +1,140 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// `PROXY UNKNOWN\r\n`
+const V1_MIN_LENGTH: usize = 15;
+/// Longest possible v1 header, including the `\r\n`
+const V1_MAX_LENGTH: usize = 107;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Signature, version and command, address family and length of the addresses
+const V2_HEADER_LENGTH: usize = 16;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to read PROXY protocol header"))]
+    ReadFromClient { source: std::io::Error },
+
+    #[snafu(display("The connection does not start with a PROXY protocol header"))]
+    MissingHeader {},
+
+    #[snafu(display("Invalid PROXY protocol v1 header {header:?}"))]
+    InvalidV1Header { header: String },
+
+    #[snafu(display("Invalid PROXY protocol v2 header: {reason}"))]
+    InvalidV2Header { reason: &'static str },
+}
+
+/// Reads a PROXY protocol v1 or v2 header from the start of the stream, without reading any data after it.
+///
+/// Returns the source address of the proxied connection, or `None` if the proxy did not tell it (e.g. for its own
+/// health checks).
+pub async fn read_header(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<IpAddr>, Error> {
+    // Both versions are at least this long, so we can't read too much
+    let mut header = vec![0; V1_MIN_LENGTH];
+    stream
+        .read_exact(&mut header)
+        .await
+        .context(ReadFromClientSnafu)?;
+
+    if header.starts_with(V1_PREFIX) {
+        // The header ends with the first `\r\n`, so we can only read it byte by byte
+        while !header.ends_with(b"\r\n") {
+            ensure!(
+                header.len() < V1_MAX_LENGTH,
+                InvalidV1HeaderSnafu {
+                    header: String::from_utf8_lossy(&header)
+                }
+            );
+            header.push(stream.read_u8().await.context(ReadFromClientSnafu)?);
+        }
+        parse_v1(&header)
+    } else if header.starts_with(V2_SIGNATURE) {
+        header.resize(V2_HEADER_LENGTH, 0);
+        stream
+            .read_exact(&mut header[V1_MIN_LENGTH..])
+            .await
+            .context(ReadFromClientSnafu)?;
+        let mut addresses = vec![0; u16::from_be_bytes([header[14], header[15]]) as usize];
+        stream
+            .read_exact(&mut addresses)
+            .await
+            .context(ReadFromClientSnafu)?;
+        parse_v2(header[12], header[13], &addresses)
+    } else {
+        MissingHeaderSnafu.fail()
+    }
+}
+
+/// E.g. `PROXY TCP4 192.0.2.1 198.51.100.1 56324 1234\r\n`
+fn parse_v1(header: &[u8]) -> Result<Option<IpAddr>, Error> {
+    let invalid = || InvalidV1HeaderSnafu {
+        header: String::from_utf8_lossy(header),
+    };
+    let line = std::str::from_utf8(&header[V1_PREFIX.len()..header.len() - 2])
+        .ok()
+        .with_context(invalid)?;
+    let mut fields = line.split(' ');
+
+    match fields.next() {
+        Some("UNKNOWN") => Ok(None),
+        Some(protocol @ ("TCP4" | "TCP6")) => {
+            let source: IpAddr = fields
+                .next()
+                .and_then(|source| source.parse().ok())
+                .with_context(invalid)?;
+            ensure!(
+                source.is_ipv4() == (protocol == "TCP4") && fields.count() == 3,
+                invalid()
+            );
+            Ok(Some(source))
+        }
+        _ => invalid().fail(),
+    }
+}
+
+fn parse_v2(
+    version_and_command: u8,
+    family_and_protocol: u8,
+    addresses: &[u8],
+) -> Result<Option<IpAddr>, Error> {
+    ensure!(
+        version_and_command >> 4 == 2,
+        InvalidV2HeaderSnafu {
+            reason: "unsupported version"
+        }
+    );
+    match version_and_command & 0x0f {
+        // LOCAL, e.g. health checks of the proxy itself
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => {
+            return InvalidV2HeaderSnafu {
+                reason: "unknown command",
+            }
+            .fail()
+        }
+    }
+
+    let truncated = InvalidV2HeaderSnafu {
+        reason: "addresses are truncated",
+    };
+    match family_and_protocol >> 4 {
+        // AF_INET
+        0x1 => {
+            let source: [u8; 4] = addresses.get(..4).context(truncated)?.try_into().unwrap();
+            Ok(Some(Ipv4Addr::from(source).into()))
+        }
+        // AF_INET6
+        0x2 => {
+            let source: [u8; 16] = addresses.get(..16).context(truncated)?.try_into().unwrap();
+            Ok(Some(Ipv6Addr::from(source).into()))
+        }
+        // AF_UNSPEC and AF_UNIX don't carry an IP address
+        _ => Ok(None),
+    }
+}
@@ -1,25 +1,108 @@
 use std::{
     cmp::min,
+    collections::HashMap,
+    future::Future,
     net::{IpAddr, Ipv4Addr},
     sync::Arc,
     time::Duration,
 };
 
-use breakwater_core::framebuffer::FrameBuffer;
+use breakwater_core::{framebuffer::FrameBuffer, pixel_ownership::NO_OWNER};
 use breakwater_parser::{implementations::SimpleParser, Parser, ParserError};
-use log::{debug, info};
-use snafu::{ResultExt, Snafu};
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use snafu::{OptionExt, ResultExt, Snafu};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpListener,
-    sync::mpsc,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, watch},
     time::Instant,
 };
+use tokio_rustls::TlsAcceptor;
 
-use crate::statistics::StatisticsEvent;
+use crate::{
+    accept_backoff::{AcceptBackoff, AcceptError},
+    access_list::AccessList,
+    buffer_pool::{BufferPool, ConnectionBuffer},
+    canvas::{Canvas, Canvases},
+    connection_limits::{ConnectionLimit, ConnectionLimits},
+    connection_timeouts::{ConnectionTimeout, ConnectionTimeoutTracker, ConnectionTimeouts},
+    listener::{ClientSocket, ListenAddress, Listener},
+    output_buffer::{BoundedOutput, OutputLimit},
+    pixel_rate_limit::{max_pixels, PixelRateLimitMode, PixelRateLimiter},
+    proxy_protocol,
+    statistics::StatisticsEvent,
+    websocket::WebSocketAdapter,
+};
 
 // Every client connection spawns a new thread, so we need to limit the number of stat events we send
-const STATISTICS_REPORT_INTERVAL: Duration = Duration::from_millis(250);
+pub(crate) const STATISTICS_REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Clients not completing the WebSocket or TLS handshake in time are disconnected, so they don't hold a connection
+/// permit
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a closing connection waits for the client to read the remaining responses
+const OUTPUT_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Any stream a client can be connected through
+pub trait ClientStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> ClientStream for T {}
+
+/// How clients speak Pixelflut with a server
+#[derive(Clone)]
+pub enum Transport {
+    /// Directly over TCP or a Unix domain socket
+    Plain,
+    WebSocket,
+    Tls(TlsAcceptor),
+}
+
+impl Transport {
+    fn server_name(&self) -> &'static str {
+        match self {
+            Transport::Plain => "Pixelflut server",
+            Transport::WebSocket => "Pixelflut WebSocket server",
+            Transport::Tls(_) => "Pixelflut TLS server",
+        }
+    }
+
+    /// Returns `None` if the client did not complete the handshake
+    async fn handshake(
+        &self,
+        stream: Box<dyn ClientStream>,
+        ip: IpAddr,
+    ) -> Option<Box<dyn ClientStream>> {
+        let handshake = async {
+            match self {
+                Transport::Plain => Ok(stream),
+                Transport::WebSocket => tokio_tungstenite::accept_async(stream)
+                    .await
+                    .map(|stream| Box::new(WebSocketAdapter::new(stream)) as Box<dyn ClientStream>)
+                    .map_err(|err| err.to_string()),
+                Transport::Tls(acceptor) => acceptor
+                    .accept(stream)
+                    .await
+                    .map(|stream| Box::new(stream) as Box<dyn ClientStream>)
+                    .map_err(|err| err.to_string()),
+            }
+        };
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+            Ok(Ok(stream)) => Some(stream),
+            Ok(Err(err)) => {
+                debug!(
+                    "Handshake of {} with {ip} failed: {err}",
+                    self.server_name()
+                );
+                None
+            }
+            Err(_) => {
+                debug!("Handshake of {} with {ip} timed out", self.server_name());
+                None
+            }
+        }
+    }
+}
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -39,103 +122,395 @@ pub enum Error {
 
     #[snafu(display("Failed to parse Pixelflut commands"))]
     ParsePixelflutCommands { source: ParserError },
+
+    #[snafu(display("Failed to write to client"))]
+    WriteToClient { source: std::io::Error },
+
+    #[snafu(display("There is no canvas {name:?}"))]
+    UnknownCanvas { name: String },
 }
 
 pub struct Server {
-    // listen_address: String,
-    listener: TcpListener,
-    fb: Arc<FrameBuffer>,
-    statistics_tx: mpsc::Sender<StatisticsEvent>,
-    network_buffer_size: usize,
+    listener: Listener,
+    /// Shared with the connections, which might outlive the listener
+    clients: Arc<ClientHandler>,
+}
+
+/// Everything needed to handle the clients of a [`Server`]
+struct ClientHandler {
+    /// Tag of the listen address, connections are counted per tag in the statistics
+    tag: Option<Arc<str>>,
+    /// Canvas new connections start on
+    canvas: String,
+    canvases: Arc<Canvases>,
+    /// Shared between all servers of the process
+    buffer_pool: Arc<BufferPool>,
+    /// Shared between all servers of the process
+    connection_limits: Arc<ConnectionLimits>,
+    /// Whether rejected clients get a line telling them which limit they hit
+    send_connection_limit_reason: bool,
+    /// Shared between all servers of the process, `None` allows everyone
+    access_list: Option<Arc<AccessList>>,
+    pixel_rate_limiter: Arc<PixelRateLimiter>,
+    connection_timeouts: ConnectionTimeouts,
+    output_limit: OutputLimit,
+    transport: Transport,
+    /// Peers allowed to send a PROXY protocol header with the address of the actual client
+    trusted_proxies: Arc<[IpNet]>,
 }
 
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
-        listen_address: &str,
-        fb: Arc<FrameBuffer>,
-        statistics_tx: mpsc::Sender<StatisticsEvent>,
-        network_buffer_size: usize,
+        listen_address: &ListenAddress,
+        canvas: String,
+        canvases: Arc<Canvases>,
+        buffer_pool: Arc<BufferPool>,
+        connection_limits: Arc<ConnectionLimits>,
+        send_connection_limit_reason: bool,
+        access_list: Option<Arc<AccessList>>,
+        pixel_rate_limiter: Arc<PixelRateLimiter>,
+        connection_timeouts: ConnectionTimeouts,
+        output_limit: OutputLimit,
+        transport: Transport,
+        trusted_proxies: Arc<[IpNet]>,
+        reuse_port: bool,
     ) -> Result<Self, Error> {
-        let listener = TcpListener::bind(listen_address)
+        let listener = Listener::bind(&listen_address.endpoint, reuse_port)
             .await
-            .context(BindToListenAddressSnafu { listen_address })?;
-        info!("Started Pixelflut server on {listen_address}");
+            .context(BindToListenAddressSnafu {
+                listen_address: listen_address.to_string(),
+            })?;
+        info!("Started {} on {listen_address}", transport.server_name());
 
         Ok(Self {
             listener,
-            fb,
-            statistics_tx,
-            network_buffer_size,
+            clients: Arc::new(ClientHandler {
+                tag: listen_address.tag.as_deref().map(Arc::from),
+                canvas,
+                canvases,
+                buffer_pool,
+                connection_limits,
+                send_connection_limit_reason,
+                access_list,
+                pixel_rate_limiter,
+                connection_timeouts,
+                output_limit,
+                transport,
+                trusted_proxies,
+            }),
         })
     }
 
-    pub async fn start(&self) -> Result<(), Error> {
+    #[cfg(test)]
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr().unwrap(),
+            Listener::Unix(..) => panic!("Unix domain sockets have no socket address"),
+        }
+    }
+
+    /// Accepts connections until `shutdown` is set. Open connections finish the data they are parsing and are closed
+    /// as well, the listening socket is closed once this returns.
+    ///
+    /// Failing to accept a connection is only fatal if the listening socket is broken. Otherwise, e.g. when the process
+    /// runs out of file descriptors, the error is counted and accepting is retried with an exponential backoff.
+    pub async fn start(&self, mut shutdown: watch::Receiver<bool>) -> Result<(), Error> {
+        let mut backoff = AcceptBackoff::default();
         loop {
-            let (socket, socket_addr) = self
-                .listener
-                .accept()
-                .await
-                .context(AcceptNewClientConnectionSnafu)?;
-            // If you connect via IPv4 you often show up as embedded inside an IPv6 address
-            // Extracting the embedded information here, so we get the real (TM) address
-            let ip = ip_to_canonical(socket_addr.ip());
-
-            let fb_for_thread = Arc::clone(&self.fb);
-            let statistics_tx_for_thread = self.statistics_tx.clone();
-            let network_buffer_size = self.network_buffer_size;
-            tokio::spawn(async move {
-                handle_connection(
-                    socket,
-                    ip,
-                    fb_for_thread,
-                    statistics_tx_for_thread,
-                    network_buffer_size,
-                )
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                _ = shutdown.wait_for(|shutdown| *shutdown) => return Ok(()),
+            };
+            let (socket, ip) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    let Some(error) = AcceptError::from_io_error(&err) else {
+                        return Err(err).context(AcceptNewClientConnectionSnafu);
+                    };
+                    let was_backing_off = backoff.is_backing_off();
+                    let was_at_file_descriptor_limit = backoff.file_descriptor_limit_reached();
+                    let delay = backoff.failed(error);
+                    // Only log once per streak of failures, a connection flood would fill the log otherwise
+                    if !delay.is_zero() && !was_backing_off {
+                        warn!("Failed to accept new client connection, backing off: {err}");
+                    } else {
+                        debug!("Failed to accept new client connection: {err}");
+                    }
+                    self.clients
+                        .send_statistics(StatisticsEvent::AcceptFailed { error })
+                        .await?;
+                    if !was_at_file_descriptor_limit && backoff.file_descriptor_limit_reached() {
+                        self.clients
+                            .send_statistics(StatisticsEvent::FileDescriptorLimitReached)
+                            .await?;
+                    }
+
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => continue,
+                        _ = shutdown.wait_for(|shutdown| *shutdown) => return Ok(()),
+                    }
+                }
+            };
+            let was_at_file_descriptor_limit = backoff.file_descriptor_limit_reached();
+            if backoff.succeeded() {
+                info!("Accepting new client connections again");
+            }
+            if was_at_file_descriptor_limit {
+                self.clients
+                    .send_statistics(StatisticsEvent::FileDescriptorLimitRecovered)
+                    .await?;
+            }
+
+            let clients = Arc::clone(&self.clients);
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move { clients.handle_client(socket, ip, shutdown).await });
+        }
+    }
+}
+
+impl ClientHandler {
+    /// Reads the PROXY protocol header if the peer is a trusted proxy and checks the access list and connection limits
+    /// before handing the connection over to [`handle_connection_to_canvases`]
+    async fn handle_client(
+        &self,
+        socket: ClientSocket,
+        peer_ip: IpAddr,
+        shutdown: watch::Receiver<bool>,
+    ) -> Result<(), Error> {
+        let is_unix = socket.is_unix();
+        let mut stream = socket.into_stream();
+        let proxied_ip = if self
+            .trusted_proxies
+            .iter()
+            .any(|net| net.contains(&peer_ip))
+        {
+            let header = proxy_protocol::read_header(&mut stream);
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, header).await {
+                Ok(Ok(client_ip)) => client_ip.map(ip_to_canonical),
+                Ok(Err(err)) => {
+                    debug!("Invalid PROXY protocol header from {peer_ip}: {err}");
+                    return Ok(());
+                }
+                Err(_) => {
+                    debug!("PROXY protocol header from {peer_ip} timed out");
+                    return Ok(());
+                }
+            }
+        } else {
+            None
+        };
+        // E.g. health checks of the proxy don't carry a client address
+        let ip = proxied_ip.unwrap_or(peer_ip);
+
+        // Access to Unix domain sockets is controlled by file permissions, unless a proxy tells us the client IP.
+        // All their clients share the same IP address, so the per-IP limits don't apply to them.
+        let local = is_unix && proxied_ip.is_none();
+        let connection_permit = match self.access_list.as_ref() {
+            Some(access_list) if !local && !access_list.is_allowed(ip) => {
+                Err(ConnectionLimit::Denied)
+            }
+            _ if local => self.connection_limits.try_acquire_local(),
+            _ => self.connection_limits.try_acquire(ip),
+        };
+        // Released once the connection is closed
+        let _connection_permit = match connection_permit {
+            Ok(connection_permit) => connection_permit,
+            Err(limit) => {
+                debug!("Rejected connection from {ip}: {limit}");
+                // WebSocket and TLS clients would not understand the reason before the handshake
+                if self.send_connection_limit_reason && matches!(self.transport, Transport::Plain) {
+                    // The connection was just accepted, so the send buffer is empty and this doesn't block.
+                    // If it fails the client doesn't get the reason, which is fine.
+                    let _ = stream
+                        .write_all(format!("ERROR {limit}\n").as_bytes())
+                        .await;
+                }
+                self.send_statistics(StatisticsEvent::ConnectionRejected { limit })
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        let Some(stream) = self.transport.handshake(stream, ip).await else {
+            return Ok(());
+        };
+        let pixel_rate_limiter = if local {
+            Arc::new(PixelRateLimiter::new(0, self.pixel_rate_limiter.mode(), 0))
+        } else {
+            Arc::clone(&self.pixel_rate_limiter)
+        };
+        handle_connection_to_canvases(
+            stream,
+            ip,
+            self.tag.clone(),
+            Arc::clone(&self.canvases),
+            self.canvas.clone(),
+            Arc::clone(&self.buffer_pool),
+            pixel_rate_limiter,
+            self.connection_timeouts,
+            self.output_limit,
+            shutdown,
+        )
+        .await
+    }
+
+    /// Events that don't belong to a connection on a specific canvas are accounted for on the initial canvas
+    async fn send_statistics(&self, event: StatisticsEvent) -> Result<(), Error> {
+        if let Some(canvas) = self.canvases.get(&self.canvas) {
+            canvas
+                .statistics_tx
+                .send(event)
                 .await
-            });
+                .context(WriteToStatisticsChannelSnafu)?;
         }
+        Ok(())
     }
 }
 
+/// Handles a connection to a single canvas, switching canvases is not possible
+#[cfg(test)]
 pub async fn handle_connection(
-    mut stream: impl AsyncReadExt + AsyncWriteExt + Send + Unpin,
+    stream: impl AsyncReadExt + AsyncWriteExt + Send + Unpin,
     ip: IpAddr,
     fb: Arc<FrameBuffer>,
     statistics_tx: mpsc::Sender<StatisticsEvent>,
     network_buffer_size: usize,
+) -> Result<(), Error> {
+    let canvases = Canvases::from([(String::new(), Canvas { fb, statistics_tx })]);
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    handle_connection_to_canvases(
+        stream,
+        ip,
+        None,
+        Arc::new(canvases),
+        String::new(),
+        Arc::new(BufferPool::new(network_buffer_size, None)),
+        Arc::new(PixelRateLimiter::new(0, PixelRateLimitMode::Drop, 64)),
+        ConnectionTimeouts::default(),
+        OutputLimit::default(),
+        shutdown_rx,
+    )
+    .await
+}
+
+/// Handles a connection starting on the given canvas. The client can switch to any of the `canvases` using the
+/// `CANVAS` command, the selected canvas determines the [`FrameBuffer`] and statistics the connection uses.
+/// The statistics count the connection for the `tag` of the listener it came in on.
+/// The network buffer is taken from the `buffer_pool` and grows with the amount of data the client sends.
+/// The pixels drawn are limited by the `pixel_rate_limiter`, idle or slow connections are closed according to the
+/// `connection_timeouts`. Once `shutdown` is set (or its sender dropped) the connection is closed before reading more
+/// data, so the commands already read are still drawn.
+/// Responses are buffered up to the `output_limit`. While the connection waits for the client to read them, shutdown
+/// and the idle timeout and maximum lifetime still close it.
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_connection_to_canvases(
+    stream: impl AsyncReadExt + AsyncWriteExt + Send + Unpin,
+    ip: IpAddr,
+    tag: Option<Arc<str>>,
+    canvases: Arc<Canvases>,
+    mut canvas_name: String,
+    buffer_pool: Arc<BufferPool>,
+    pixel_rate_limiter: Arc<PixelRateLimiter>,
+    connection_timeouts: ConnectionTimeouts,
+    output_limit: OutputLimit,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<(), Error> {
     debug!("Handling connection from {ip}");
 
+    // The client can't send anything while we wait for the memory budget
+    let mut buffer = tokio::select! {
+        buffer = ConnectionBuffer::new(&buffer_pool) => buffer,
+        _ = shutdown.wait_for(|shutdown| *shutdown) => return Ok(()),
+    };
+
+    let Canvas {
+        mut fb,
+        mut statistics_tx,
+    } = canvases
+        .get(&canvas_name)
+        .cloned()
+        .context(UnknownCanvasSnafu {
+            name: canvas_name.clone(),
+        })?;
+
     statistics_tx
-        .send(StatisticsEvent::ConnectionCreated { ip })
+        .send(StatisticsEvent::ConnectionCreated {
+            ip,
+            listener: tag.clone(),
+        })
         .await
         .context(WriteToStatisticsChannelSnafu)?;
 
-    let mut buffer = vec![0u8; network_buffer_size];
     // Number bytes left over **on the first bytes of the buffer** from the previous loop iteration
     let mut leftover_bytes_in_buffer = 0;
 
-    let mut parser: SimpleParser = SimpleParser::default();
+    let mut parser: SimpleParser = new_parser(&fb, ip);
     // let mut parser: AssemblerParser = AssemblerParser::default();
     let parser_lookahead = SimpleParser::parser_lookahead();
+    // Parsers of the canvases the connection switched away from, so that e.g. the offset is kept per canvas
+    let mut inactive_parsers = HashMap::new();
+    let pause_on_pixel_rate_limit = pixel_rate_limiter.mode() == PixelRateLimitMode::Throttle;
 
     // If we send e.g. an StatisticsEvent::BytesRead for every time we read something from the socket the statistics thread would go crazy.
     // Instead we bulk the statistics and send them pre-aggregated.
     let mut last_statistics = Instant::now();
     let mut statistics_bytes_read: u64 = 0;
 
-    loop {
+    let mut timeout_tracker = ConnectionTimeoutTracker::new(connection_timeouts);
+    let mut timeout = None;
+
+    // Reading and writing are split, so that the buffered responses can be written while waiting for new data
+    let (mut reader, writer) = tokio::io::split(stream);
+    let mut output = BoundedOutput::new(writer, output_limit);
+    let mut output_overflowed = false;
+    // Set if the connection was closed while waiting for the client to read its responses
+    let mut output_blocked = None;
+
+    'connection: loop {
         // Fill the buffer up with new data from the socket
         // If there are any bytes left over from the previous loop iteration leave them as is and but the new data behind
-        let bytes_read = match stream
-            .read(&mut buffer[leftover_bytes_in_buffer..network_buffer_size - parser_lookahead])
-            .await
-        {
-            Ok(bytes_read) => bytes_read,
-            Err(_) => {
+        buffer.adapt(leftover_bytes_in_buffer);
+        let read_end = buffer.len() - parser_lookahead;
+        let read_start = Instant::now();
+        let read = reader.read(&mut buffer[leftover_bytes_in_buffer..read_end]);
+        let deadline = timeout_tracker.read_deadline(read_start);
+        let read_result = tokio::select! {
+            // Prefer the shutdown, otherwise a client sending constantly would keep the connection open
+            biased;
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+            drained = output.drain(), if output.pending() > 0 => match drained {
+                Ok(()) => continue,
+                Err(_) => break,
+            },
+            read_result = async {
+                match deadline {
+                    Some(deadline) => tokio::time::timeout_at(deadline, read).await.ok(),
+                    None => Some(read.await),
+                }
+            } => read_result,
+        };
+        let bytes_read = match read_result {
+            Some(Ok(bytes_read)) => bytes_read,
+            Some(Err(_)) => {
                 break;
             }
+            // The deadline was hit, but it might only have been the end of the throughput window
+            None => match timeout_tracker.check(read_start, 0) {
+                Ok(()) => continue,
+                Err(err) => {
+                    timeout = Some(err);
+                    break;
+                }
+            },
         };
+        buffer.record_read(bytes_read, read_end - leftover_bytes_in_buffer);
+        if bytes_read > 0 {
+            if let Err(err) = timeout_tracker.check(read_start, bytes_read) {
+                timeout = Some(err);
+                break;
+            }
+        }
 
         statistics_bytes_read += bytes_read as u64;
         if last_statistics.elapsed() > STATISTICS_REPORT_INTERVAL {
@@ -145,12 +520,14 @@ pub async fn handle_connection(
                 // As the statistics calculation should be trivial let's wait for it
                 .send(StatisticsEvent::BytesRead {
                     ip,
+                    listener: tag.clone(),
                     bytes: statistics_bytes_read,
                 })
                 .await
                 .context(WriteToStatisticsChannelSnafu)?;
             last_statistics = Instant::now();
             statistics_bytes_read = 0;
+            send_dropped_responses(&mut output, &statistics_tx).await?;
         }
 
         let data_end = leftover_bytes_in_buffer + bytes_read;
@@ -171,14 +548,129 @@ pub async fn handle_connection(
                 *i = 0;
             }
 
-            let last_byte_parsed = parser
-                .parse(&buffer[..data_end + parser_lookahead], &fb, &mut stream)
-                .await
-                .context(ParsePixelflutCommandsSnafu)?;
+            // The parser stops after every CANVAS command, so that we can switch the canvas in between.
+            // When throttled by the pixel rate limit it also stops, we wait for new pixels without reading more data.
+            let mut parse_start = 0;
+            let parsed_end = loop {
+                parser.set_pixel_budget(
+                    pixel_rate_limiter.take(ip, max_pixels(data_end - parse_start)),
+                    pause_on_pixel_rate_limit,
+                );
+                // The parser waits if the client doesn't read its responses and the overflow policy is to pause
+                let parse = parser.parse(
+                    &buffer[parse_start..data_end + parser_lookahead],
+                    &fb,
+                    &mut output,
+                );
+                let deadline = timeout_tracker.wait_deadline(Instant::now(), true);
+                let parsed = match interruptible(parse, &mut shutdown, deadline).await {
+                    Ok(parsed) => parsed,
+                    Err(interrupted) => {
+                        output_blocked = Some(interrupted);
+                        break 'connection;
+                    }
+                };
+                // The parser ignores failed writes of some responses, so check the output itself
+                if output.overflowed() {
+                    output_overflowed = true;
+                    break 'connection;
+                }
+                parsed.context(ParsePixelflutCommandsSnafu)?;
+                let parsed = parser.resume_offset();
+                pixel_rate_limiter.give_back(ip, parser.pixel_budget());
+                if parser.take_pixel_budget_exhausted() {
+                    if output.write_pending().is_err() {
+                        break 'connection;
+                    }
+                    let deadline = timeout_tracker.wait_deadline(Instant::now(), false);
+                    match interruptible(
+                        pixel_rate_limiter.wait_for_pixels(ip),
+                        &mut shutdown,
+                        deadline,
+                    )
+                    .await
+                    {
+                        Ok(()) => {}
+                        Err(Interrupted::Shutdown) => break 'connection,
+                        Err(Interrupted::Timeout(err)) => {
+                            timeout = Some(err);
+                            break 'connection;
+                        }
+                    }
+                    parse_start += parsed;
+                    continue;
+                }
+                let Some(requested_canvas) = parser.take_requested_canvas() else {
+                    break parse_start + parsed;
+                };
+                parse_start += parsed;
+                if requested_canvas == canvas_name {
+                    continue;
+                }
+
+                let Some(canvas) = canvases.get(&requested_canvas) else {
+                    let response = format!("CANVAS {requested_canvas} not found\n");
+                    let deadline = timeout_tracker.wait_deadline(Instant::now(), true);
+                    match interruptible(
+                        output.write_all(response.as_bytes()),
+                        &mut shutdown,
+                        deadline,
+                    )
+                    .await
+                    {
+                        Ok(Ok(())) => {}
+                        Ok(Err(_)) if output.overflowed() => {
+                            output_overflowed = true;
+                            break 'connection;
+                        }
+                        Ok(Err(err)) => return Err(err).context(WriteToClientSnafu),
+                        Err(interrupted) => {
+                            output_blocked = Some(interrupted);
+                            break 'connection;
+                        }
+                    }
+                    continue;
+                };
 
-            // IMPORTANT: We have to subtract 1 here, as e.g. we have "PX 0 0\n" data_end is 7 and parser_state.last_byte_parsed is 6.
-            // This happens, because last_byte_parsed is an index starting at 0, so index 6 is from an array of length 7
-            leftover_bytes_in_buffer = data_end.saturating_sub(last_byte_parsed).saturating_sub(1);
+                // Move the connection over to the statistics of the new canvas
+                statistics_tx
+                    .send(StatisticsEvent::BytesRead {
+                        ip,
+                        listener: tag.clone(),
+                        bytes: statistics_bytes_read,
+                    })
+                    .await
+                    .context(WriteToStatisticsChannelSnafu)?;
+                statistics_bytes_read = 0;
+                send_dropped_responses(&mut output, &statistics_tx).await?;
+                statistics_tx
+                    .send(StatisticsEvent::ConnectionClosed {
+                        ip,
+                        listener: tag.clone(),
+                    })
+                    .await
+                    .context(WriteToStatisticsChannelSnafu)?;
+                canvas
+                    .statistics_tx
+                    .send(StatisticsEvent::ConnectionCreated {
+                        ip,
+                        listener: tag.clone(),
+                    })
+                    .await
+                    .context(WriteToStatisticsChannelSnafu)?;
+
+                let new_parser = inactive_parsers
+                    .remove(&requested_canvas)
+                    .unwrap_or_else(|| new_parser(&canvas.fb, ip));
+                inactive_parsers.insert(
+                    std::mem::replace(&mut canvas_name, requested_canvas),
+                    std::mem::replace(&mut parser, new_parser),
+                );
+                fb = Arc::clone(&canvas.fb);
+                statistics_tx = canvas.statistics_tx.clone();
+            };
+
+            leftover_bytes_in_buffer = data_end.saturating_sub(parsed_end);
 
             // There is no need to leave anything longer than a command can take
             // This prevents malicious clients from sending gibberish and the buffer not getting drained
@@ -186,25 +678,108 @@ pub async fn handle_connection(
 
             if leftover_bytes_in_buffer > 0 {
                 // We need to move the leftover bytes to the beginning of the buffer so that the next loop iteration con work on them
-                buffer.copy_within(
-                    last_byte_parsed + 1..last_byte_parsed + 1 + leftover_bytes_in_buffer,
-                    0,
-                );
+                buffer.copy_within(parsed_end..parsed_end + leftover_bytes_in_buffer, 0);
+            }
+
+            // The rest is written while waiting for new data
+            if output.write_pending().is_err() {
+                break;
             }
         }
     }
 
+    send_dropped_responses(&mut output, &statistics_tx).await?;
+    match output_blocked {
+        Some(Interrupted::Timeout(err)) => timeout = Some(err),
+        // There is no point in waiting for the client to read the remaining responses
+        Some(Interrupted::Shutdown) => output.discard(),
+        None => {}
+    }
+    if output_overflowed {
+        debug!("Closing connection from {ip}: it does not read its responses");
+        statistics_tx
+            .send(StatisticsEvent::OutputOverflowed)
+            .await
+            .context(WriteToStatisticsChannelSnafu)?;
+    } else if let Some(timeout) = timeout {
+        debug!("Closing connection from {ip}: {timeout:?}");
+        // The client might be gone already, we don't care
+        output.discard();
+        let _ = output.shutdown().await;
+        statistics_tx
+            .send(StatisticsEvent::ConnectionTimedOut { timeout })
+            .await
+            .context(WriteToStatisticsChannelSnafu)?;
+    } else {
+        // Give the client the chance to read the last responses, it might be gone already
+        let _ = tokio::time::timeout(OUTPUT_FLUSH_TIMEOUT, output.flush()).await;
+    }
     statistics_tx
-        .send(StatisticsEvent::ConnectionClosed { ip })
+        .send(StatisticsEvent::ConnectionClosed {
+            ip,
+            listener: tag.clone(),
+        })
         .await
         .context(WriteToStatisticsChannelSnafu)?;
 
     Ok(())
 }
 
+/// Why [`interruptible`] stopped waiting
+enum Interrupted {
+    Shutdown,
+    Timeout(ConnectionTimeout),
+}
+
+/// Waits for `future`, unless the server shuts down or the `deadline` is hit first
+async fn interruptible<T>(
+    future: impl Future<Output = T>,
+    shutdown: &mut watch::Receiver<bool>,
+    deadline: Option<(Instant, ConnectionTimeout)>,
+) -> Result<T, Interrupted> {
+    tokio::select! {
+        // A future that is ready right away wins, even if the server is shutting down in the meantime
+        biased;
+        result = future => Ok(result),
+        _ = shutdown.wait_for(|shutdown| *shutdown) => Err(Interrupted::Shutdown),
+        timeout = async {
+            match deadline {
+                Some((deadline, timeout)) => {
+                    tokio::time::sleep_until(deadline).await;
+                    timeout
+                }
+                None => std::future::pending().await,
+            }
+        } => Err(Interrupted::Timeout(timeout)),
+    }
+}
+
+/// Responses dropped because of [`crate::output_buffer::OutputOverflowPolicy::Drop`]
+async fn send_dropped_responses<W: AsyncWrite + Unpin>(
+    output: &mut BoundedOutput<W>,
+    statistics_tx: &mpsc::Sender<StatisticsEvent>,
+) -> Result<(), Error> {
+    let responses = output.take_dropped_responses();
+    if responses > 0 {
+        statistics_tx
+            .send(StatisticsEvent::ResponsesDropped { responses })
+            .await
+            .context(WriteToStatisticsChannelSnafu)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn new_parser(fb: &FrameBuffer, ip: IpAddr) -> SimpleParser {
+    let owner = fb
+        .pixel_ownership()
+        .map(|pixel_ownership| pixel_ownership.register(ip))
+        .unwrap_or(NO_OWNER);
+    SimpleParser::with_owner(owner)
+}
+
 /// TODO: Switch to official ip.to_canonical() method when it is stable. **If** it gets stable sometime ;)
 /// See <https://doc.rust-lang.org/std/net/enum.IpAddr.html#method.to_canonical>
-const fn ip_to_canonical(ip: IpAddr) -> IpAddr {
+pub(crate) const fn ip_to_canonical(ip: IpAddr) -> IpAddr {
     match ip {
         IpAddr::V4(_) => ip,
         IpAddr::V6(v6) => match v6.octets() {
@@ -0,0 +1,30 @@
+use std::{sync::Arc, time::Duration};
+
+use breakwater_core::framebuffer::FrameBuffer;
+use tokio::time::{interval, MissedTickBehavior};
+
+/// Increments the frame counter in the header of the shared memory with the configured fps, so that readers of the
+/// shared memory know when to read the next frame.
+pub struct SharedMemoryFrameCounter {
+    fb: Arc<FrameBuffer>,
+    fps: u32,
+}
+
+impl SharedMemoryFrameCounter {
+    pub fn new(fb: Arc<FrameBuffer>, fps: u32) -> Self {
+        Self { fb, fps }
+    }
+
+    pub async fn run(&self) {
+        let Some(shared_memory) = self.fb.shared_memory() else {
+            return;
+        };
+
+        let mut interval = interval(Duration::from_micros(1_000_000 / self.fps.max(1) as u64));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            shared_memory.increment_frame();
+        }
+    }
+}
@@ -1,15 +1,11 @@
 use std::{sync::Arc, time::Duration};
 
-use breakwater_core::framebuffer::FrameBuffer;
+use breakwater_core::{framebuffer::FrameBuffer, protected_regions::Rect};
 use core::slice;
 use number_prefix::NumberPrefix;
 use rusttype::{point, Font, Scale};
 use snafu::{OptionExt, ResultExt, Snafu};
-use tokio::sync::{
-    broadcast,
-    mpsc::{self, Sender},
-    oneshot,
-};
+use tokio::sync::{broadcast, mpsc::Sender, oneshot};
 use vncserver::{
     rfb_framebuffer_malloc, rfb_get_screen, rfb_init_server, rfb_mark_rect_as_modified,
     rfb_run_event_loop, RfbScreenInfoPtr,
@@ -30,23 +26,46 @@ pub enum Error {
     #[snafu(display("Failed to construct font from font file {font_file}"))]
     ConstructFontFromFontFile { font_file: String },
 
-    #[snafu(display("Failed to write to statistics channel"))]
-    WriteToStatisticsChannel {
-        source: mpsc::error::SendError<StatisticsEvent>,
-    },
-
     #[snafu(display("Failed to read from statistics information channel"))]
     ReadFromStatisticsInformationChannel {
         source: broadcast::error::TryRecvError,
     },
 }
 
+/// What the VNC server shows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VncView {
+    /// The drawing surface
+    Canvas,
+    /// The rendered heatmap of the drawing surface, see [`breakwater_core::heatmap::Heatmap`]
+    Heatmap,
+    /// Part of the drawing surface, e.g. one screen of a video wall. Has no stats bar.
+    Tile(Rect),
+}
+
+impl VncView {
+    /// The part of the drawing surface shown by the VNC server
+    fn viewport(&self, fb: &FrameBuffer) -> Rect {
+        match self {
+            VncView::Canvas | VncView::Heatmap => Rect {
+                x: 0,
+                y: 0,
+                width: fb.get_width(),
+                height: fb.get_height(),
+            },
+            VncView::Tile(tile) => *tile,
+        }
+    }
+}
+
 // Sorry! Help needed :)
 unsafe impl<'a> Send for VncServer<'a> {}
 pub struct VncServer<'a> {
     fb: Arc<FrameBuffer>,
     screen: RfbScreenInfoPtr,
     target_fps: u32,
+    view: VncView,
+    viewport: Rect,
 
     statistics_tx: Sender<StatisticsEvent>,
     statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
@@ -62,6 +81,7 @@ impl<'a> VncServer<'a> {
         fb: Arc<FrameBuffer>,
         port: u16,
         target_fps: u32,
+        view: VncView,
         statistics_tx: Sender<StatisticsEvent>,
         statistics_information_rx: broadcast::Receiver<StatisticsInformationEvent>,
         terminate_signal_tx: oneshot::Receiver<String>,
@@ -87,7 +107,14 @@ impl<'a> VncServer<'a> {
             }
         };
 
-        let screen = rfb_get_screen(fb.get_width() as i32, fb.get_height() as i32, 8, 3, 4);
+        let viewport = view.viewport(&fb);
+        assert!(
+            viewport.x + viewport.width <= fb.get_width()
+                && viewport.y + viewport.height <= fb.get_height(),
+            "viewport {viewport} is outside of the drawing surface"
+        );
+
+        let screen = rfb_get_screen(viewport.width as i32, viewport.height as i32, 8, 3, 4);
         unsafe {
             // We need to set bitsPerPixel and depth to the correct values,
             // otherwise some VNC clients (like gstreamer) won't work
@@ -100,7 +127,21 @@ impl<'a> VncServer<'a> {
             (*screen).ipv6port = port as i32;
         }
 
-        rfb_framebuffer_malloc(screen, (fb.get_size() * 4/* bytes per pixel */) as u64);
+        if view == VncView::Canvas {
+            // The stats bar would overwrite whatever clients draw there, so don't let them.
+            // A line more because the line above the stats is not copied either (see `run`)
+            fb.protected_regions().add(Rect {
+                x: 0,
+                y: fb.get_height() - STATS_HEIGHT - 1,
+                width: fb.get_width(),
+                height: STATS_HEIGHT + 1,
+            });
+        }
+
+        rfb_framebuffer_malloc(
+            screen,
+            (viewport.width * viewport.height * 4/* bytes per pixel */) as u64,
+        );
         rfb_init_server(screen);
         rfb_run_event_loop(screen, 1, 1);
 
@@ -108,6 +149,8 @@ impl<'a> VncServer<'a> {
             fb,
             screen,
             target_fps,
+            view,
+            viewport,
             statistics_tx,
             statistics_information_rx,
             terminate_signal_tx,
@@ -119,14 +162,19 @@ impl<'a> VncServer<'a> {
     pub fn run(&mut self) -> Result<(), Error> {
         let target_loop_duration = Duration::from_micros(1_000_000 / self.target_fps as u64);
 
-        let fb = &self.fb;
         let vnc_fb_slice: &mut [u32] = unsafe {
-            slice::from_raw_parts_mut((*self.screen).frameBuffer as *mut u32, fb.get_size())
+            slice::from_raw_parts_mut(
+                (*self.screen).frameBuffer as *mut u32,
+                self.viewport.width * self.viewport.height,
+            )
+        };
+        // A line less because the (height - STATS_SURFACE_HEIGHT) belongs to the stats and gets refreshed by them.
+        // Tiles don't have a stats bar.
+        let height_up_to_stats_text = match self.view {
+            VncView::Canvas | VncView::Heatmap => self.viewport.height - STATS_HEIGHT - 1,
+            VncView::Tile(_) => self.viewport.height,
         };
-        let fb_slice = unsafe { &*fb.get_buffer() };
-        // A line less because the (height - STATS_SURFACE_HEIGHT) belongs to the stats and gets refreshed by them
-        let height_up_to_stats_text = self.fb.get_height() - STATS_HEIGHT - 1;
-        let fb_size_up_to_stats_text = fb.get_width() * height_up_to_stats_text;
+        let fb_size_up_to_stats_text = self.viewport.width * height_up_to_stats_text;
 
         loop {
             if self.terminate_signal_tx.try_recv().is_ok() {
@@ -134,22 +182,42 @@ impl<'a> VncServer<'a> {
             }
 
             let start = std::time::Instant::now();
-            vnc_fb_slice[0..fb_size_up_to_stats_text]
-                .copy_from_slice(&fb_slice[0..fb_size_up_to_stats_text]);
+            match self.view {
+                VncView::Canvas => self
+                    .fb
+                    .copy_visible_into(&mut vnc_fb_slice[0..fb_size_up_to_stats_text]),
+                VncView::Heatmap => {
+                    if let Some(heatmap) = self.fb.heatmap() {
+                        heatmap.render_into(&mut vnc_fb_slice[0..fb_size_up_to_stats_text]);
+                    }
+                }
+                VncView::Tile(tile) => {
+                    for (y, row) in vnc_fb_slice.chunks_exact_mut(tile.width).enumerate() {
+                        row.copy_from_slice(
+                            &self.fb.get_row(tile.y + y)[tile.x..tile.x + tile.width],
+                        );
+                    }
+                }
+            }
 
             // Only refresh the drawing surface, not the stats surface
             rfb_mark_rect_as_modified(
                 self.screen,
                 0,
                 0,
-                self.fb.get_width() as i32,
+                self.viewport.width as i32,
                 height_up_to_stats_text as i32,
             );
-            self.statistics_tx
-                .blocking_send(StatisticsEvent::FrameRendered)
-                .context(WriteToStatisticsChannelSnafu)?;
+            // Only the canvas counts, otherwise additional views would bump the fps
+            // On shutdown the statistics are stopped before the VNC servers, the last frames are not counted then
+            if self.view == VncView::Canvas {
+                let _ = self
+                    .statistics_tx
+                    .blocking_send(StatisticsEvent::FrameRendered);
+            }
 
-            if !self.statistics_information_rx.is_empty() {
+            if !matches!(self.view, VncView::Tile(_)) && !self.statistics_information_rx.is_empty()
+            {
                 let statistics_information_event = self
                     .statistics_information_rx
                     .try_recv()
@@ -162,6 +230,13 @@ impl<'a> VncServer<'a> {
     }
 
     fn display_stats(&mut self, stats: StatisticsInformationEvent) {
+        let pixel_rate_limit = match stats.pixel_rate_limit {
+            Some(pixels_per_s) => format!(
+                ", max {} pixels/s per client",
+                format_per_s(pixels_per_s as f64)
+            ),
+            None => String::new(),
+        };
         self.draw_rect(
             0,
             self.fb.get_height() - STATS_HEIGHT,
@@ -175,7 +250,7 @@ impl<'a> VncServer<'a> {
             27_f32,
             0x00ff_ffff,
             format!(
-                "{}. {} Bit/s ({}B total) by {} connections from {} IPs ({} legacy)",
+                "{}. {} Bit/s ({}B total) by {} connections from {} IPs ({} legacy){pixel_rate_limit}",
                 self.text,
                 format_per_s(stats.bytes_per_s as f64 * 8.0),
                 format(stats.bytes as f64),
@@ -1,3 +1,4 @@
+use breakwater_core::framebuffer::FrameBuffer;
 use serde::{Deserialize, Serialize};
 use simple_moving_average::{SingleSumSMA, SMA};
 use snafu::{ResultExt, Snafu};
@@ -6,9 +7,15 @@ use std::{
     collections::{hash_map::Entry, HashMap},
     fs::File,
     net::IpAddr,
+    sync::Arc,
     time::{Duration, Instant},
 };
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, watch};
+
+use crate::{
+    accept_backoff::AcceptError, buffer_pool::BufferPool, connection_limits::ConnectionLimit,
+    connection_timeouts::ConnectionTimeout, pixel_rate_limit::PixelRateLimiter,
+};
 
 pub const STATS_REPORT_INTERVAL: Duration = Duration::from_millis(1000);
 pub const STATS_SLIDING_WINDOW_SIZE: usize = 5;
@@ -39,11 +46,47 @@ pub enum Error {
     },
 }
 
+/// `listener` is the tag of the listen address the client connected to, if it has one
 #[derive(Debug)]
 pub enum StatisticsEvent {
-    ConnectionCreated { ip: IpAddr },
-    ConnectionClosed { ip: IpAddr },
-    BytesRead { ip: IpAddr, bytes: u64 },
+    ConnectionCreated {
+        ip: IpAddr,
+        listener: Option<Arc<str>>,
+    },
+    ConnectionClosed {
+        ip: IpAddr,
+        listener: Option<Arc<str>>,
+    },
+    /// The connection was closed right away, as it would have exceeded the given limit
+    ConnectionRejected {
+        limit: ConnectionLimit,
+    },
+    /// The connection is closed by the server, it is followed by a [`StatisticsEvent::ConnectionClosed`]
+    ConnectionTimedOut {
+        timeout: ConnectionTimeout,
+    },
+    /// Accepting a new connection failed, the listener retries
+    AcceptFailed {
+        error: AcceptError,
+    },
+    /// A listener ran out of file descriptors, it is followed by a
+    /// [`StatisticsEvent::FileDescriptorLimitRecovered`] once the listener accepts connections again
+    FileDescriptorLimitReached,
+    FileDescriptorLimitRecovered,
+    /// Receiving a UDP datagram failed, the UDP server retries
+    ReceiveDatagramFailed,
+    /// The connection is closed by the server, as the client didn't read its responses. It is followed by a
+    /// [`StatisticsEvent::ConnectionClosed`]
+    OutputOverflowed,
+    /// Responses not sent, as the client didn't read the previous ones
+    ResponsesDropped {
+        responses: u64,
+    },
+    BytesRead {
+        ip: IpAddr,
+        listener: Option<Arc<str>>,
+        bytes: u64,
+    },
     FrameRendered,
 }
 
@@ -64,10 +107,52 @@ pub struct StatisticsInformationEvent {
 
     pub connections_for_ip: HashMap<IpAddr, u32>,
     pub bytes_for_ip: HashMap<IpAddr, u64>,
+    /// Only filled if pixel ownership tracking is enabled.
+    /// IPv6 clients are grouped by their prefix, the key is the network address of the prefix.
+    #[serde(default)]
+    pub pixels_for_ip: HashMap<IpAddr, u64>,
+    /// Only contains tagged listeners
+    #[serde(default)]
+    pub connections_for_listener: HashMap<String, u32>,
+    #[serde(default)]
+    pub bytes_for_listener: HashMap<String, u64>,
+    #[serde(default)]
+    pub rejected_connections: HashMap<ConnectionLimit, u64>,
+    #[serde(default)]
+    pub timed_out_connections: HashMap<ConnectionTimeout, u64>,
+    #[serde(default)]
+    pub failed_accepts: HashMap<AcceptError, u64>,
+    /// Whether at least one listener can't accept new connections, as the process is out of file descriptors
+    #[serde(default)]
+    pub file_descriptor_limit_reached: bool,
+    /// Connections closed as the client didn't read its responses
+    #[serde(default)]
+    pub output_overflow_disconnects: u64,
+    #[serde(default)]
+    pub dropped_responses: u64,
+    #[serde(default)]
+    pub failed_datagram_receives: u64,
+    /// Pixels per second every client can draw, `None` means unlimited
+    #[serde(default)]
+    pub pixel_rate_limit: Option<u64>,
+    /// The network buffers are shared by all canvases, so only the initial canvas reports them
+    #[serde(default)]
+    pub network_buffers: Option<NetworkBufferStatistics>,
 
     pub statistic_events: u64,
 }
 
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct NetworkBufferStatistics {
+    /// Memory of the network buffers of all connections, including the idle buffers kept for reuse
+    pub bytes: u64,
+    pub bytes_in_use: u64,
+    /// `None` means unlimited
+    pub memory_budget: Option<u64>,
+    /// Connections waiting for a network buffer, as the memory budget is used up
+    pub waiting_connections: u64,
+}
+
 pub struct Statistics {
     statistics_rx: mpsc::Receiver<StatisticsEvent>,
     statistics_information_tx: broadcast::Sender<StatisticsInformationEvent>,
@@ -76,11 +161,26 @@ pub struct Statistics {
     frame: u64,
     connections_for_ip: HashMap<IpAddr, u32>,
     bytes_for_ip: HashMap<IpAddr, u64>,
+    connections_for_listener: HashMap<String, u32>,
+    bytes_for_listener: HashMap<String, u64>,
+    rejected_connections: HashMap<ConnectionLimit, u64>,
+    timed_out_connections: HashMap<ConnectionTimeout, u64>,
+    failed_accepts: HashMap<AcceptError, u64>,
+    /// Listeners that ran out of file descriptors and did not accept a connection since
+    listeners_at_file_descriptor_limit: u32,
+    output_overflow_disconnects: u64,
+    dropped_responses: u64,
+    failed_datagram_receives: u64,
 
     bytes_per_s_window: SingleSumSMA<u64, u64, STATS_SLIDING_WINDOW_SIZE>,
     fps_window: SingleSumSMA<u64, u64, STATS_SLIDING_WINDOW_SIZE>,
 
     statistics_save_mode: StatisticsSaveMode,
+
+    fb: Arc<FrameBuffer>,
+    pixel_rate_limiter: Arc<PixelRateLimiter>,
+    /// Only set for the initial canvas, see [`StatisticsInformationEvent::network_buffers`]
+    buffer_pool: Option<Arc<BufferPool>>,
 }
 
 impl StatisticsInformationEvent {
@@ -108,6 +208,9 @@ impl Statistics {
         statistics_rx: mpsc::Receiver<StatisticsEvent>,
         statistics_information_tx: broadcast::Sender<StatisticsInformationEvent>,
         statistics_save_mode: StatisticsSaveMode,
+        fb: Arc<FrameBuffer>,
+        pixel_rate_limiter: Arc<PixelRateLimiter>,
+        buffer_pool: Option<Arc<BufferPool>>,
     ) -> Self {
         let mut statistics = Statistics {
             statistics_rx,
@@ -116,9 +219,21 @@ impl Statistics {
             frame: 0,
             connections_for_ip: HashMap::new(),
             bytes_for_ip: HashMap::new(),
+            connections_for_listener: HashMap::new(),
+            bytes_for_listener: HashMap::new(),
+            rejected_connections: HashMap::new(),
+            timed_out_connections: HashMap::new(),
+            failed_accepts: HashMap::new(),
+            listeners_at_file_descriptor_limit: 0,
+            output_overflow_disconnects: 0,
+            dropped_responses: 0,
+            failed_datagram_receives: 0,
             bytes_per_s_window: SingleSumSMA::new(),
             fps_window: SingleSumSMA::new(),
             statistics_save_mode,
+            fb,
+            pixel_rate_limiter,
+            buffer_pool,
         };
 
         if let StatisticsSaveMode::Enabled { save_file, .. } = &statistics.statistics_save_mode {
@@ -127,37 +242,34 @@ impl Statistics {
                 statistics.statistic_events = save_point.statistic_events;
                 statistics.frame = save_point.frame;
                 statistics.bytes_for_ip = save_point.bytes_for_ip;
+                statistics.bytes_for_listener = save_point.bytes_for_listener;
+                statistics.rejected_connections = save_point.rejected_connections;
+                statistics.timed_out_connections = save_point.timed_out_connections;
+                statistics.failed_accepts = save_point.failed_accepts;
+                statistics.output_overflow_disconnects = save_point.output_overflow_disconnects;
+                statistics.dropped_responses = save_point.dropped_responses;
+                statistics.failed_datagram_receives = save_point.failed_datagram_receives;
             }
         }
 
         statistics
     }
 
-    pub async fn start(&mut self) -> Result<(), Error> {
+    /// Runs until `shutdown` is set, then processes the events already sent and writes the save file one last time
+    pub async fn start(&mut self, mut shutdown: watch::Receiver<bool>) -> Result<(), Error> {
         let mut last_stat_report = Instant::now();
         let mut last_save_file_written = Instant::now();
         let mut statistics_information_event = StatisticsInformationEvent::default();
 
-        while let Some(statistics_update) = self.statistics_rx.recv().await {
-            self.statistic_events += 1;
-            match statistics_update {
-                StatisticsEvent::ConnectionCreated { ip } => {
-                    *self.connections_for_ip.entry(ip).or_insert(0) += 1;
-                }
-                StatisticsEvent::ConnectionClosed { ip } => {
-                    if let Entry::Occupied(mut o) = self.connections_for_ip.entry(ip) {
-                        let connections = o.get_mut();
-                        *connections -= 1;
-                        if *connections == 0 {
-                            o.remove_entry();
-                        }
-                    }
-                }
-                StatisticsEvent::BytesRead { ip, bytes } => {
-                    *self.bytes_for_ip.entry(ip).or_insert(0) += bytes;
-                }
-                StatisticsEvent::FrameRendered => self.frame += 1,
-            }
+        loop {
+            let statistics_update = tokio::select! {
+                statistics_update = self.statistics_rx.recv() => match statistics_update {
+                    Some(statistics_update) => statistics_update,
+                    None => break,
+                },
+                _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+            };
+            self.process(statistics_update);
 
             // As there is an event for every frame we are guaranteed to land here every second
             let last_stat_report_elapsed = last_stat_report.elapsed();
@@ -185,9 +297,74 @@ impl Statistics {
             }
         }
 
+        while let Ok(statistics_update) = self.statistics_rx.try_recv() {
+            self.process(statistics_update);
+        }
+        let statistics_information_event = self.calculate_statistics_information_event(
+            &statistics_information_event,
+            last_stat_report.elapsed(),
+        );
+        if let StatisticsSaveMode::Enabled { save_file, .. } = &self.statistics_save_mode {
+            statistics_information_event.save_to_file(save_file)?;
+        }
+
         Ok(())
     }
 
+    fn process(&mut self, statistics_update: StatisticsEvent) {
+        self.statistic_events += 1;
+        match statistics_update {
+            StatisticsEvent::ConnectionCreated { ip, listener } => {
+                *self.connections_for_ip.entry(ip).or_insert(0) += 1;
+                if let Some(listener) = listener {
+                    *self
+                        .connections_for_listener
+                        .entry(listener.to_string())
+                        .or_insert(0) += 1;
+                }
+            }
+            StatisticsEvent::ConnectionClosed { ip, listener } => {
+                decrement(&mut self.connections_for_ip, ip);
+                if let Some(listener) = listener {
+                    decrement(&mut self.connections_for_listener, listener.to_string());
+                }
+            }
+            StatisticsEvent::ConnectionRejected { limit } => {
+                *self.rejected_connections.entry(limit).or_insert(0) += 1;
+            }
+            StatisticsEvent::ConnectionTimedOut { timeout } => {
+                *self.timed_out_connections.entry(timeout).or_insert(0) += 1;
+            }
+            StatisticsEvent::AcceptFailed { error } => {
+                *self.failed_accepts.entry(error).or_insert(0) += 1;
+            }
+            StatisticsEvent::FileDescriptorLimitReached => {
+                self.listeners_at_file_descriptor_limit += 1;
+            }
+            StatisticsEvent::FileDescriptorLimitRecovered => {
+                self.listeners_at_file_descriptor_limit =
+                    self.listeners_at_file_descriptor_limit.saturating_sub(1);
+            }
+            StatisticsEvent::OutputOverflowed => self.output_overflow_disconnects += 1,
+            StatisticsEvent::ResponsesDropped { responses } => self.dropped_responses += responses,
+            StatisticsEvent::ReceiveDatagramFailed => self.failed_datagram_receives += 1,
+            StatisticsEvent::BytesRead {
+                ip,
+                listener,
+                bytes,
+            } => {
+                *self.bytes_for_ip.entry(ip).or_insert(0) += bytes;
+                if let Some(listener) = listener {
+                    *self
+                        .bytes_for_listener
+                        .entry(listener.to_string())
+                        .or_insert(0) += bytes;
+                }
+            }
+            StatisticsEvent::FrameRendered => self.frame += 1,
+        }
+    }
+
     fn calculate_statistics_information_event(
         &mut self,
         prev: &StatisticsInformationEvent,
@@ -208,6 +385,11 @@ impl Statistics {
         self.fps_window
             .add_sample((frame - prev.frame) * 1000 / elapsed_ms);
         let statistic_events = self.statistic_events;
+        let pixels_for_ip = self
+            .fb
+            .pixel_ownership()
+            .map(|pixel_ownership| pixel_ownership.pixels_per_owner())
+            .unwrap_or_default();
 
         StatisticsInformationEvent {
             frame,
@@ -219,7 +401,40 @@ impl Statistics {
             bytes_per_s: self.bytes_per_s_window.get_average(),
             connections_for_ip: self.connections_for_ip.clone(),
             bytes_for_ip: self.bytes_for_ip.clone(),
+            pixels_for_ip,
+            connections_for_listener: self.connections_for_listener.clone(),
+            bytes_for_listener: self.bytes_for_listener.clone(),
+            rejected_connections: self.rejected_connections.clone(),
+            timed_out_connections: self.timed_out_connections.clone(),
+            failed_accepts: self.failed_accepts.clone(),
+            file_descriptor_limit_reached: self.listeners_at_file_descriptor_limit > 0,
+            output_overflow_disconnects: self.output_overflow_disconnects,
+            dropped_responses: self.dropped_responses,
+            failed_datagram_receives: self.failed_datagram_receives,
+            pixel_rate_limit: self.pixel_rate_limiter.pixels_per_s(),
+            network_buffers: self
+                .buffer_pool
+                .as_ref()
+                .map(|buffer_pool| NetworkBufferStatistics {
+                    bytes: buffer_pool.allocated_bytes() as u64,
+                    bytes_in_use: buffer_pool.in_use_bytes() as u64,
+                    memory_budget: buffer_pool
+                        .memory_budget()
+                        .map(|memory_budget| memory_budget as u64),
+                    waiting_connections: buffer_pool.waiting_connections() as u64,
+                }),
             statistic_events,
         }
     }
 }
+
+/// Removes the entry once it reaches zero, so that e.g. clients that disconnected don't stay in the statistics
+pub(crate) fn decrement<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, u32>, key: K) {
+    if let Entry::Occupied(mut o) = counts.entry(key) {
+        let connections = o.get_mut();
+        *connections -= 1;
+        if *connections == 0 {
+            o.remove_entry();
+        }
+    }
+}
@@ -3,13 +3,52 @@ use std::{
     sync::Arc,
 };
 
-use breakwater_core::{framebuffer::FrameBuffer, test::helpers::MockTcpStream, HELP_TEXT};
+use breakwater_core::{
+    framebuffer::FrameBuffer,
+    shared_memory::{SharedMemoryLocation, SharedMemoryReader},
+    test::helpers::MockTcpStream,
+    HELP_TEXT,
+};
+use futures_util::{SinkExt, StreamExt};
 use rstest::{fixture, rstest};
-use tokio::sync::mpsc;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, watch},
+};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+use tokio_tungstenite::tungstenite::Message;
 
+#[cfg(feature = "io-uring")]
+use crate::io_uring::IoUringServer;
 use crate::{
-    cli_args::DEFAULT_NETWORK_BUFFER_SIZE, server::handle_connection, statistics::StatisticsEvent,
+    accept_backoff::{self, AcceptBackoff, AcceptError},
+    access_list::{AccessList, AccessRules},
+    buffer_pool::{BufferPool, ConnectionBuffer, MIN_BUFFER_SIZE},
+    canvas::{Canvas, CanvasConfig, Canvases},
+    cli_args::DEFAULT_NETWORK_BUFFER_SIZE,
+    connection_limits::{ConnectionLimit, ConnectionLimits},
+    connection_timeouts::{ConnectionTimeout, ConnectionTimeouts},
+    decay::CanvasDecay,
+    history::{CanvasHistory, HistoryStorage, TimeLapseFormat},
+    listener::{ListenAddress, ListenEndpoint, Listener, UNIX_SOCKET_CLIENT_IP},
+    output_buffer::{BoundedOutput, OutputLimit, OutputOverflowPolicy},
+    pixel_rate_limit::{max_pixels, PixelRateLimitMode, PixelRateLimiter},
+    proxy_protocol,
+    server::{handle_connection, handle_connection_to_canvases, Server, Transport},
+    statistics::StatisticsEvent,
+    thread_per_core::ThreadPerCoreServer,
+    tls,
+    udp_server::UdpServer,
+    websocket::WebSocketAdapter,
 };
+#[cfg(feature = "vnc")]
+use {crate::video_wall::VideoWallLayout, breakwater_core::protected_regions::Rect};
 
 #[fixture]
 fn ip() -> IpAddr {
@@ -269,3 +308,2169 @@ async fn test_drawing_rect(
     .unwrap();
     assert_eq!(read_other_pixels_commands_expected, stream.get_output());
 }
+
+#[rstest]
+#[case("WHO 0 0\n", "WHO 0 0 none\n")]
+#[case("PX 0 0 ffffff\nWHO 0 0\n", "WHO 0 0 127.0.0.1\n")]
+#[case("PX 0 0 ffffff\nWHO 0 1\n", "WHO 0 1 none\n")]
+#[case("OFFSET 10 10\nPX 0 0 ffffff\nWHO 0 0\n", "WHO 0 0 127.0.0.1\n")]
+#[case("PX 9999 0 ffffff\nWHO 9999 0\n", "")]
+#[tokio::test]
+async fn test_pixel_ownership(
+    #[case] input: &str,
+    #[case] expected: &str,
+    ip: IpAddr,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let fb = Arc::new(FrameBuffer::new(1920, 1080).with_pixel_ownership(64));
+    let mut stream = MockTcpStream::from_input(input);
+    handle_connection(
+        &mut stream,
+        ip,
+        Arc::clone(&fb),
+        statistics_channel.0,
+        DEFAULT_NETWORK_BUFFER_SIZE,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(expected, stream.get_output());
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_pixel_ownership_statistics(
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let fb = Arc::new(FrameBuffer::new(1920, 1080).with_pixel_ownership(64));
+    let ip_v4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    let ip_v6_a: IpAddr = "2001:db8::1".parse().unwrap();
+    let ip_v6_b: IpAddr = "2001:db8::2".parse().unwrap();
+
+    for (ip, input) in [
+        (ip_v4, "PX 0 0 ffffff\nPX 1 0 ffffff\nPX 2 0 ffffff\n"),
+        (ip_v6_a, "PX 2 0 ffffff\nPX 3 0 ffffff\n"),
+        (ip_v6_b, "PX 4 0 ffffff\nWHO 4 0\n"),
+    ] {
+        let mut stream = MockTcpStream::from_input(input);
+        handle_connection(
+            &mut stream,
+            ip,
+            Arc::clone(&fb),
+            statistics_channel.0.clone(),
+            DEFAULT_NETWORK_BUFFER_SIZE,
+        )
+        .await
+        .unwrap();
+        if ip == ip_v6_b {
+            assert_eq!("WHO 4 0 2001:db8::/64\n", stream.get_output());
+        }
+    }
+
+    let pixels_for_ip = fb.pixel_ownership().unwrap().pixels_per_owner();
+    assert_eq!(pixels_for_ip.len(), 2);
+    assert_eq!(pixels_for_ip[&ip_v4], 2);
+    assert_eq!(pixels_for_ip[&"2001:db8::".parse::<IpAddr>().unwrap()], 3);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_heatmap(
+    ip: IpAddr,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let fb = Arc::new(FrameBuffer::new(1920, 1080).with_heatmap());
+    let mut stream = MockTcpStream::from_input(
+        "PX 0 0 ffffff\nPX 0 0 000000\nPX 0 0 ff\nPX 0 0 abcdef\nPX 1 0 ffffff\nPX 9999 0 ffffff\n",
+    );
+    handle_connection(
+        &mut stream,
+        ip,
+        Arc::clone(&fb),
+        statistics_channel.0,
+        DEFAULT_NETWORK_BUFFER_SIZE,
+    )
+    .await
+    .unwrap();
+
+    let heatmap = fb.heatmap().unwrap();
+    assert_eq!(heatmap.get_unchecked(0), 4);
+    assert_eq!(heatmap.get_unchecked(1), 1);
+    assert_eq!(heatmap.get_unchecked(2), 0);
+
+    let rendered = heatmap.render();
+    assert_eq!(rendered.len(), fb.get_size());
+    assert_ne!(rendered[0], rendered[1]);
+    assert_eq!(rendered[2], 0);
+    let mut rendered_into = vec![u32::MAX; 3];
+    heatmap.render_into(&mut rendered_into);
+    assert_eq!(rendered_into, rendered[0..3]);
+
+    heatmap.decay();
+    assert_eq!(heatmap.get_unchecked(0), 2);
+    assert_eq!(heatmap.get_unchecked(1), 0);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_history_rollback_and_export(
+    ip: IpAddr,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let fb = Arc::new(FrameBuffer::new(64, 48));
+    let history = CanvasHistory::new(
+        Arc::clone(&fb),
+        std::time::Duration::from_secs(10),
+        10,
+        HistoryStorage::Memory,
+    )
+    .unwrap();
+
+    let mut stream = MockTcpStream::from_input("PX 0 0 abcdef\nPX 63 47 123456\n");
+    handle_connection(
+        &mut stream,
+        ip,
+        Arc::clone(&fb),
+        statistics_channel.0.clone(),
+        DEFAULT_NETWORK_BUFFER_SIZE,
+    )
+    .await
+    .unwrap();
+    history.take_snapshot().unwrap();
+    let snapshots = history.list();
+    assert_eq!(snapshots.len(), 1);
+
+    let mut stream = MockTcpStream::from_input("PX 0 0 ffffff\nPX 1 1 ffffff\n");
+    handle_connection(
+        &mut stream,
+        ip,
+        Arc::clone(&fb),
+        statistics_channel.0.clone(),
+        DEFAULT_NETWORK_BUFFER_SIZE,
+    )
+    .await
+    .unwrap();
+
+    history.rollback(snapshots[0]).unwrap();
+    let mut stream = MockTcpStream::from_input("PX 0 0\nPX 1 1\nPX 63 47\n");
+    handle_connection(
+        &mut stream,
+        ip,
+        Arc::clone(&fb),
+        statistics_channel.0,
+        DEFAULT_NETWORK_BUFFER_SIZE,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        "PX 0 0 abcdef\nPX 1 1 000000\nPX 63 47 123456\n",
+        stream.get_output()
+    );
+    assert!(history.rollback(42).is_err());
+
+    let export_dir = std::env::temp_dir().join(format!("breakwater-test-{}", std::process::id()));
+    for (format, path) in [
+        (TimeLapseFormat::Apng, export_dir.join("time-lapse.png")),
+        (TimeLapseFormat::Gif, export_dir.join("time-lapse.gif")),
+        (TimeLapseFormat::PngSequence, export_dir.join("frames")),
+    ] {
+        std::fs::create_dir_all(&export_dir).unwrap();
+        history.export(format, path.clone(), 10).unwrap();
+        assert!(path.exists());
+    }
+    std::fs::remove_dir_all(export_dir).unwrap();
+}
+
+#[rstest]
+#[case(
+    "CAPS\n",
+    "CAPS PX SIZE HELP OFFSET CAPS PROTECTED:0,1045,1920,35 PROTECTED:10,10,5,5\n"
+)]
+#[case("SIZE\n", "SIZE 1920 1045\n")]
+#[case("PX 10 10 ffffff\nPX 10 10\n", "PX 10 10 000000\n")]
+#[case("PX 14 14 ffffff\nPX 14 14\n", "PX 14 14 000000\n")]
+#[case("PX 15 15 ffffff\nPX 15 15\n", "PX 15 15 ffffff\n")]
+#[case("PX 0 1044 ffffff\nPX 0 1044\n", "PX 0 1044 ffffff\n")]
+#[case("PX 0 1045 ffffff\nPX 0 1045\n", "PX 0 1045 000000\n")]
+#[tokio::test]
+async fn test_protected_regions(
+    #[case] input: &str,
+    #[case] expected: &str,
+    ip: IpAddr,
+    fb: Arc<FrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    fb.protected_regions()
+        .add("0,1045,1920,35".parse().unwrap());
+    fb.protected_regions().add("10,10,5,5".parse().unwrap());
+
+    let mut stream = MockTcpStream::from_input(input);
+    handle_connection(
+        &mut stream,
+        ip,
+        fb,
+        statistics_channel.0,
+        DEFAULT_NETWORK_BUFFER_SIZE,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(expected, stream.get_output());
+}
+
+#[rstest]
+#[case("SIZE\n", "SIZE 64 48\n", 0)]
+#[case("PX 63 47 ffffff\nPX 63 47\n", "PX 63 47 ffffff\n", 1)]
+#[case("PX 64 0 ffffff\nPX 0 1\nPX 64 0\n", "PX 0 1 000000\n", 0)]
+#[case("PX 9999 9999 ffffff\nPX 0 0\n", "PX 0 0 000000\n", 0)]
+#[case("PX 10 10 ffffff\nPX 10 10\n", "PX 10 10 000000\n", 0)]
+#[case("PX 14 14 ffffff\nPX 14 14\n", "PX 14 14 000000\n", 0)]
+#[case("PX 15 14 ffffff\nPX 15 14\n", "PX 15 14 ffffff\n", 1)]
+#[tokio::test]
+async fn test_padded_framebuffer(
+    #[case] input: &str,
+    #[case] expected: &str,
+    #[case] expected_visible_pixels_drawn: usize,
+    ip: IpAddr,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let fb = Arc::new(FrameBuffer::new_padded(64, 48));
+    fb.protected_regions().add("10,10,5,5".parse().unwrap());
+
+    let mut stream = MockTcpStream::from_input(input);
+    handle_connection(
+        &mut stream,
+        ip,
+        Arc::clone(&fb),
+        statistics_channel.0,
+        DEFAULT_NETWORK_BUFFER_SIZE,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(expected, stream.get_output());
+
+    // Sinks only get to see the visible pixels
+    let mut visible = vec![0; fb.get_size()];
+    fb.copy_visible_into(&mut visible);
+    assert_eq!(
+        visible.iter().filter(|pixel| **pixel != 0).count(),
+        expected_visible_pixels_drawn
+    );
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_shared_memory(
+    ip: IpAddr,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let file = std::env::temp_dir().join(format!(
+        "breakwater-test-shared-memory-{}",
+        std::process::id()
+    ));
+    let location = SharedMemoryLocation::File(file.clone());
+    let fb = Arc::new(FrameBuffer::new_in_shared_memory(64, 48, location.clone()).unwrap());
+
+    let mut stream = MockTcpStream::from_input("PX 0 0 abcdef\nPX 63 47 123456\nPX 64 0 ffffff\n");
+    handle_connection(
+        &mut stream,
+        ip,
+        Arc::clone(&fb),
+        statistics_channel.0,
+        DEFAULT_NETWORK_BUFFER_SIZE,
+    )
+    .await
+    .unwrap();
+
+    let reader = SharedMemoryReader::open(location).unwrap();
+    assert_eq!((reader.get_width(), reader.get_height()), (64, 48));
+    assert_eq!(reader.get(0, 0), fb.get(0, 0));
+    assert_eq!(reader.get(63, 47), fb.get(63, 47));
+    assert_eq!(reader.get(64, 0), None);
+    assert_eq!(
+        reader
+            .get_pixels()
+            .iter()
+            .filter(|pixel| **pixel != 0)
+            .count(),
+        2
+    );
+
+    assert_eq!(reader.get_frame(), 0);
+    fb.shared_memory().unwrap().increment_frame();
+    assert_eq!(reader.get_frame(), 1);
+
+    std::fs::remove_file(file).unwrap();
+}
+
+#[rstest]
+#[case(
+    0x000000,
+    "PX 0 0 ff8001\nPX 1 0 ffffff\n",
+    "PX 0 0 7f4000\nPX 1 0 ffffff\nPX 2 0 000000\n"
+)]
+#[case(
+    0xffffff,
+    "PX 0 0 ff8001\nPX 1 0 000000\n",
+    "PX 0 0 ffc080\nPX 1 0 000000\nPX 2 0 808080\n"
+)]
+#[tokio::test]
+async fn test_decay(
+    #[case] background_color: u32,
+    #[case] input: &str,
+    #[case] expected: &str,
+    ip: IpAddr,
+    fb: Arc<FrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let mut stream = MockTcpStream::from_input(input);
+    handle_connection(
+        &mut stream,
+        ip,
+        Arc::clone(&fb),
+        statistics_channel.0.clone(),
+        DEFAULT_NETWORK_BUFFER_SIZE,
+    )
+    .await
+    .unwrap();
+
+    // Protected pixels don't decay
+    fb.protected_regions().add("1,0,1,1".parse().unwrap());
+    // Half life equals the sweep interval, so every sweep halves the distance to the background color
+    let decay = CanvasDecay::new(
+        Arc::clone(&fb),
+        background_color,
+        std::time::Duration::from_secs(1),
+        std::time::Duration::from_secs(1),
+    );
+    decay.sweep(0..fb.get_height());
+
+    let mut stream = MockTcpStream::from_input("PX 0 0\nPX 1 0\nPX 2 0\n");
+    handle_connection(
+        &mut stream,
+        ip,
+        Arc::clone(&fb),
+        statistics_channel.0,
+        DEFAULT_NETWORK_BUFFER_SIZE,
+    )
+    .await
+    .unwrap();
+    assert_eq!(expected, stream.get_output());
+}
+
+#[rstest]
+#[case(
+    "name=kids,listen=[::]:1235",
+    Ok(CanvasConfig {
+        name: "kids".to_string(),
+        listen_address: Some("[::]:1235".parse().unwrap()),
+        width: 1280,
+        height: 720,
+        vnc_port: None,
+        statistics_save_file: None,
+    })
+)]
+#[case(
+    "name=kids,listen=0.0.0.0:1235,width=640,height=480,vnc-port=5901,statistics-save-file=kids.json",
+    Ok(CanvasConfig {
+        name: "kids".to_string(),
+        listen_address: Some("0.0.0.0:1235".parse().unwrap()),
+        width: 640,
+        height: 480,
+        vnc_port: Some(5901),
+        statistics_save_file: Some("kids.json".to_string()),
+    })
+)]
+#[case("listen=[::]:1235", Err("canvas setting \"name\" is missing"))]
+#[case(
+    "name=kids",
+    Ok(CanvasConfig {
+        name: "kids".to_string(),
+        listen_address: None,
+        width: 1280,
+        height: 720,
+        vnc_port: None,
+        statistics_save_file: None,
+    })
+)]
+#[case(
+    "name=kids corner",
+    Err("invalid canvas name \"kids corner\", it must consist of 1 to 16 characters without whitespace")
+)]
+#[case(
+    "name=kids,listen=[::]:1235,width=big",
+    Err("invalid value \"big\" for canvas setting \"width\"")
+)]
+#[case(
+    "name=kids,listen=[::]:1235,color=red",
+    Err("unknown canvas setting \"color\"")
+)]
+fn test_parse_canvas_config(#[case] input: &str, #[case] expected: Result<CanvasConfig, &str>) {
+    assert_eq!(
+        input.parse::<CanvasConfig>(),
+        expected.map_err(|err| err.to_string())
+    );
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_canvas_switching(ip: IpAddr) {
+    let main_fb = Arc::new(FrameBuffer::new(1920, 1080));
+    let kids_fb = Arc::new(FrameBuffer::new(64, 48));
+    let (main_statistics_tx, mut main_statistics_rx) = mpsc::channel(100);
+    let (kids_statistics_tx, mut kids_statistics_rx) = mpsc::channel(100);
+    let canvases = Canvases::from([
+        (
+            "main".to_string(),
+            Canvas {
+                fb: Arc::clone(&main_fb),
+                statistics_tx: main_statistics_tx,
+            },
+        ),
+        (
+            "kids".to_string(),
+            Canvas {
+                fb: Arc::clone(&kids_fb),
+                statistics_tx: kids_statistics_tx,
+            },
+        ),
+    ]);
+
+    let mut stream = MockTcpStream::from_input(
+        "PX 0 0 ffffff\nCANVAS kids\nPX 0 0 abcdef\nSIZE\nCANVAS main\nPX 0 0\nCANVAS nope\nPX 1 0 123456\n",
+    );
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    handle_connection_to_canvases(
+        &mut stream,
+        ip,
+        None,
+        Arc::new(canvases),
+        "main".to_string(),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        Arc::new(PixelRateLimiter::new(0, PixelRateLimitMode::Drop, 64)),
+        ConnectionTimeouts::default(),
+        OutputLimit::default(),
+        shutdown_rx,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        "SIZE 64 48\nPX 0 0 ffffff\nCANVAS nope not found\n",
+        stream.get_output()
+    );
+    assert_eq!(main_fb.get(0, 0), Some(0xffffff));
+    assert_eq!(main_fb.get(1, 0), Some(0x563412));
+    assert_eq!(kids_fb.get(0, 0), Some(0xefcdab));
+
+    // The connection moved over to the statistics of the selected canvas and back
+    let mut kids_events = Vec::new();
+    while let Ok(event) = kids_statistics_rx.try_recv() {
+        kids_events.push(format!("{event:?}"));
+    }
+    assert_eq!(
+        kids_events,
+        [
+            format!("ConnectionCreated {{ ip: {ip}, listener: None }}"),
+            format!("BytesRead {{ ip: {ip}, listener: None, bytes: 0 }}"),
+            format!("ConnectionClosed {{ ip: {ip}, listener: None }}"),
+        ]
+    );
+    let mut main_connections = 0;
+    while let Ok(event) = main_statistics_rx.try_recv() {
+        match event {
+            StatisticsEvent::ConnectionCreated { .. } => main_connections += 1,
+            StatisticsEvent::ConnectionClosed { .. } => main_connections -= 1,
+            _ => {}
+        }
+    }
+    assert_eq!(main_connections, 0);
+}
+
+#[cfg(feature = "vnc")]
+#[rstest]
+#[case("1x1", 1280, 720, 0, 0, Some(vec![(0, 0, 1280, 720)]))]
+#[case("2x1", 1280, 720, 0, 0, Some(vec![(0, 0, 640, 720), (640, 0, 640, 720)]))]
+#[case("2x2", 1280, 720, 20, 10, Some(vec![(0, 0, 630, 355), (650, 0, 630, 355), (0, 365, 630, 355), (650, 365, 630, 355)]))]
+// Left over pixels are not shown
+#[case("3x1", 100, 10, 0, 0, Some(vec![(0, 0, 33, 10), (33, 0, 33, 10), (66, 0, 33, 10)]))]
+#[case("3x1", 100, 10, 50, 0, None)]
+#[case("1x20", 100, 10, 0, 0, None)]
+fn test_video_wall_tiles(
+    #[case] layout: &str,
+    #[case] width: usize,
+    #[case] height: usize,
+    #[case] bezel_width: usize,
+    #[case] bezel_height: usize,
+    #[case] expected: Option<Vec<(usize, usize, usize, usize)>>,
+) {
+    let layout: VideoWallLayout = layout.parse().unwrap();
+    let expected = expected.map(|tiles| {
+        tiles
+            .into_iter()
+            .map(|(x, y, width, height)| Rect {
+                x,
+                y,
+                width,
+                height,
+            })
+            .collect::<Vec<_>>()
+    });
+    assert_eq!(
+        layout.tiles(width, height, bezel_width, bezel_height),
+        expected
+    );
+}
+
+#[cfg(feature = "vnc")]
+#[rstest]
+#[case("3x2", Ok(VideoWallLayout { columns: 3, rows: 2 }))]
+#[case("3", Err("invalid video wall layout \"3\", expected <columns>x<rows>"))]
+#[case(
+    "0x2",
+    Err("invalid video wall layout \"0x2\", expected <columns>x<rows>")
+)]
+fn test_parse_video_wall_layout(
+    #[case] input: &str,
+    #[case] expected: Result<VideoWallLayout, &str>,
+) {
+    assert_eq!(
+        input.parse::<VideoWallLayout>(),
+        expected.map_err(|err| err.to_string())
+    );
+}
+
+#[rstest]
+#[case::unlimited(None, None, None, &["10.0.0.1", "10.0.0.1", "10.0.0.1"], &[Ok(()), Ok(()), Ok(())])]
+#[case::total(Some(2), None, None, &["10.0.0.1", "10.0.0.2", "10.0.0.3"], &[Ok(()), Ok(()), Err(ConnectionLimit::Total)])]
+#[case::ip(None, Some(1), None, &["10.0.0.1", "10.0.0.2", "10.0.0.1"], &[Ok(()), Ok(()), Err(ConnectionLimit::Ip)])]
+#[case::ipv6_prefix(
+    None,
+    Some(1),
+    Some(2),
+    &["2001:db8::1", "2001:db8::2", "2001:db8::3", "2001:db8:1::1", "10.0.0.1", "10.0.0.2", "10.0.0.3"],
+    &[Ok(()), Ok(()), Err(ConnectionLimit::Ipv6Prefix), Ok(()), Ok(()), Ok(()), Ok(())],
+)]
+fn test_connection_limits(
+    #[case] max_connections: Option<u32>,
+    #[case] max_connections_per_ip: Option<u32>,
+    #[case] max_connections_per_ipv6_prefix: Option<u32>,
+    #[case] ips: &[&str],
+    #[case] expected: &[Result<(), ConnectionLimit>],
+) {
+    let connection_limits = Arc::new(ConnectionLimits::new(
+        max_connections,
+        max_connections_per_ip,
+        max_connections_per_ipv6_prefix,
+        64,
+    ));
+
+    // Closing the connections frees up the slots again, so the second round behaves the same
+    for _ in 0..2 {
+        let permits: Vec<_> = ips
+            .iter()
+            .map(|ip| connection_limits.try_acquire(ip.parse().unwrap()))
+            .collect();
+        assert_eq!(
+            permits
+                .iter()
+                .map(|permit| permit.as_ref().map(|_| ()).map_err(|limit| *limit))
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+}
+
+#[rstest]
+#[case(libc::EMFILE, Some(AcceptError::FileDescriptorLimit))]
+#[case(libc::ENFILE, Some(AcceptError::FileDescriptorLimit))]
+#[case(libc::ENOBUFS, Some(AcceptError::OutOfMemory))]
+#[case(libc::ENOMEM, Some(AcceptError::OutOfMemory))]
+#[case(libc::ECONNABORTED, Some(AcceptError::Other))]
+#[case(libc::EPERM, Some(AcceptError::Other))]
+#[case(libc::EBADF, None)]
+#[case(libc::EINVAL, None)]
+fn test_accept_error(#[case] errno: i32, #[case] expected: Option<AcceptError>) {
+    assert_eq!(
+        AcceptError::from_io_error(&std::io::Error::from_raw_os_error(errno)),
+        expected
+    );
+}
+
+#[test]
+fn test_accept_backoff() {
+    let mut backoff = AcceptBackoff::default();
+    // Errors of a single connection are retried right away
+    assert_eq!(
+        backoff.failed(AcceptError::Other),
+        std::time::Duration::ZERO
+    );
+    assert!(!backoff.file_descriptor_limit_reached());
+    assert!(!backoff.succeeded());
+
+    let delays: Vec<_> = (0..10)
+        .map(|_| backoff.failed(AcceptError::FileDescriptorLimit))
+        .collect();
+    assert_eq!(delays[0], std::time::Duration::from_millis(5));
+    assert_eq!(delays[1], std::time::Duration::from_millis(10));
+    assert_eq!(delays[9], std::time::Duration::from_secs(1));
+    assert!(delays.windows(2).all(|delays| delays[0] <= delays[1]));
+    assert_eq!(
+        backoff.failed(AcceptError::Other),
+        std::time::Duration::ZERO
+    );
+    assert!(backoff.file_descriptor_limit_reached());
+
+    assert!(backoff.succeeded());
+    assert!(!backoff.file_descriptor_limit_reached());
+    assert!(!backoff.succeeded());
+    assert_eq!(
+        backoff.failed(AcceptError::OutOfMemory),
+        std::time::Duration::from_millis(5)
+    );
+    assert!(!backoff.file_descriptor_limit_reached());
+}
+
+#[test]
+fn test_raise_open_files_limit() {
+    let (previous, limit) = accept_backoff::raise_open_files_limit().unwrap();
+    assert!(limit >= previous);
+    // Already raised
+    assert_eq!(
+        accept_backoff::raise_open_files_limit().unwrap(),
+        (limit, limit)
+    );
+}
+
+#[tokio::test]
+async fn test_connection_buffer_adapts() {
+    let pool = Arc::new(BufferPool::new(4 * MIN_BUFFER_SIZE, None));
+    let mut buffer = ConnectionBuffer::new(&pool).await;
+    assert_eq!(buffer.len(), MIN_BUFFER_SIZE);
+    buffer[..3].copy_from_slice(b"PX ");
+
+    // Heavy clients fill the buffer with every read
+    for expected in [
+        2 * MIN_BUFFER_SIZE,
+        4 * MIN_BUFFER_SIZE,
+        4 * MIN_BUFFER_SIZE,
+    ] {
+        let space = buffer.len() - 3;
+        buffer.record_read(space, space);
+        buffer.adapt(3);
+        assert_eq!(buffer.len(), expected);
+        assert_eq!(&buffer[..3], b"PX ");
+    }
+    // The smaller buffers went back into the pool
+    assert_eq!(pool.allocated_bytes(), 7 * MIN_BUFFER_SIZE);
+    assert_eq!(pool.in_use_bytes(), 4 * MIN_BUFFER_SIZE);
+
+    // Light clients only use a fraction of it
+    for _ in 0..15 {
+        buffer.record_read(100, buffer.len());
+        buffer.adapt(0);
+    }
+    assert_eq!(buffer.len(), 4 * MIN_BUFFER_SIZE);
+    buffer.record_read(100, buffer.len());
+    buffer.adapt(0);
+    assert_eq!(buffer.len(), 2 * MIN_BUFFER_SIZE);
+    assert_eq!(pool.allocated_bytes(), 7 * MIN_BUFFER_SIZE);
+
+    drop(buffer);
+    assert_eq!(pool.in_use_bytes(), 0);
+}
+
+#[tokio::test]
+async fn test_buffer_pool_memory_budget() {
+    let pool = Arc::new(BufferPool::new(
+        4 * MIN_BUFFER_SIZE,
+        Some(3 * MIN_BUFFER_SIZE),
+    ));
+    let mut first = ConnectionBuffer::new(&pool).await;
+    let second = ConnectionBuffer::new(&pool).await;
+
+    // Growing would exceed the budget, so the connection keeps its buffer
+    first.record_read(MIN_BUFFER_SIZE, MIN_BUFFER_SIZE);
+    first.adapt(0);
+    assert_eq!(first.len(), MIN_BUFFER_SIZE);
+
+    let third = ConnectionBuffer::new(&pool).await;
+    let fourth = tokio::spawn({
+        let pool = Arc::clone(&pool);
+        async move { ConnectionBuffer::new(&pool).await.len() }
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!fourth.is_finished());
+    assert_eq!(pool.waiting_connections(), 1);
+    assert_eq!(pool.allocated_bytes(), 3 * MIN_BUFFER_SIZE);
+
+    drop(second);
+    assert_eq!(fourth.await.unwrap(), MIN_BUFFER_SIZE);
+    assert_eq!(pool.waiting_connections(), 0);
+    assert_eq!(pool.allocated_bytes(), 3 * MIN_BUFFER_SIZE);
+    drop((first, third));
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_growing_network_buffer(
+    ip: IpAddr,
+    fb: Arc<FrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    // Much more than the smallest buffer, so commands are split between reads and buffers
+    let gray = |x: usize, y: usize| ((x + y) % 256) as u32 * 0x010101;
+    let input: String = (0..200)
+        .flat_map(|y| (0..200).map(move |x| format!("PX {x} {y} {:06x}\n", gray(x, y))))
+        .chain(["PX 199 199\n".to_string()])
+        .collect();
+    let mut stream = MockTcpStream::from_input(&input);
+    handle_connection(
+        &mut stream,
+        ip,
+        Arc::clone(&fb),
+        statistics_channel.0,
+        DEFAULT_NETWORK_BUFFER_SIZE,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(stream.get_output(), "PX 199 199 8e8e8e\n");
+    for y in 0..200 {
+        for x in 0..200 {
+            assert_eq!(fb.get(x, y).unwrap() & 0x00ff_ffff, gray(x, y));
+        }
+    }
+}
+
+#[rstest]
+#[case::everyone("", &["10.0.0.1", "2001:db8::1"], &[])]
+#[case::deny(
+    "deny 10.0.0.0/8 # abusive\ndeny 2001:db8::/32",
+    &["192.168.0.1", "2001:db9::1"],
+    &["10.1.2.3", "2001:db8::1"]
+)]
+#[case::venue_only(
+    "# Only the venue network\nallow 10.0.0.0/8\nallow 2001:db8::/32\ndeny 10.0.0.66",
+    &["10.1.2.3", "2001:db8::1"],
+    &["10.0.0.66", "192.168.0.1", "2001:db9::1"]
+)]
+fn test_access_rules(#[case] rules: &str, #[case] allowed: &[&str], #[case] denied: &[&str]) {
+    let rules: AccessRules = rules.parse().unwrap();
+    for ip in allowed {
+        assert!(
+            rules.is_allowed(ip.parse().unwrap()),
+            "{ip} should be allowed"
+        );
+    }
+    for ip in denied {
+        assert!(
+            !rules.is_allowed(ip.parse().unwrap()),
+            "{ip} should be denied"
+        );
+    }
+}
+
+#[rstest]
+#[case("allow", (1, "expected \"allow <cidr>\" or \"deny <cidr>\", got \"allow\""))]
+#[case("\nblock 10.0.0.0/8", (2, "unknown action \"block\", expected allow or deny"))]
+#[case("deny 10.0.0.0/33", (1, "invalid IP range \"10.0.0.0/33\""))]
+fn test_parse_access_rules_errors(#[case] rules: &str, #[case] expected: (usize, &str)) {
+    assert_eq!(
+        rules.parse::<AccessRules>(),
+        Err((expected.0, expected.1.to_string()))
+    );
+}
+
+#[test]
+fn test_access_list_reload() {
+    let file = std::env::temp_dir().join(format!(
+        "breakwater-test-access-list-{}",
+        std::process::id()
+    ));
+    std::fs::write(&file, "deny 10.0.0.0/8\n").unwrap();
+    let access_list = AccessList::load(file.clone()).unwrap();
+    assert!(!access_list.is_allowed("10.0.0.1".parse().unwrap()));
+
+    std::fs::write(&file, "allow 10.0.0.0/8\n").unwrap();
+    access_list.reload().unwrap();
+    assert!(access_list.is_allowed("10.0.0.1".parse().unwrap()));
+    assert!(!access_list.is_allowed("192.168.0.1".parse().unwrap()));
+    assert_eq!(access_list.list(), vec!["allow 10.0.0.0/8"]);
+
+    // Invalid files keep the previous rules
+    std::fs::write(&file, "allow everyone\n").unwrap();
+    assert!(access_list.reload().is_err());
+    assert!(access_list.is_allowed("10.0.0.1".parse().unwrap()));
+
+    std::fs::remove_file(file).unwrap();
+}
+
+#[rstest]
+#[case::drop(PixelRateLimitMode::Drop, 10, 4, std::time::Duration::ZERO)]
+// The bucket starts with a second worth of pixels, the remaining ones come in with 20 pixels/s
+#[case::throttle(
+    PixelRateLimitMode::Throttle,
+    30,
+    30,
+    std::time::Duration::from_millis(400)
+)]
+#[tokio::test]
+async fn test_pixel_rate_limit(
+    ip: IpAddr,
+    #[case] mode: PixelRateLimitMode,
+    #[case] pixels: usize,
+    #[case] expected_pixels_drawn: usize,
+    #[case] expected_min_duration: std::time::Duration,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let pixels_per_s = if mode == PixelRateLimitMode::Drop {
+        4
+    } else {
+        20
+    };
+    let fb = Arc::new(FrameBuffer::new(64, 1));
+    let canvases = Canvases::from([(
+        "main".to_string(),
+        Canvas {
+            fb: Arc::clone(&fb),
+            statistics_tx: statistics_channel.0,
+        },
+    )]);
+    let input: String = (0..pixels).map(|x| format!("PX {x} 0 ffffff\n")).collect();
+    let mut stream = MockTcpStream::from_input(&input);
+
+    let start = std::time::Instant::now();
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    handle_connection_to_canvases(
+        &mut stream,
+        ip,
+        None,
+        Arc::new(canvases),
+        "main".to_string(),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        Arc::new(PixelRateLimiter::new(pixels_per_s, mode, 64)),
+        ConnectionTimeouts::default(),
+        OutputLimit::default(),
+        shutdown_rx,
+    )
+    .await
+    .unwrap();
+
+    assert!(start.elapsed() >= expected_min_duration);
+    for x in 0..pixels {
+        let expected = if x < expected_pixels_drawn {
+            Some(0x00ff_ffff)
+        } else {
+            Some(0)
+        };
+        assert_eq!(fb.get(x, 0), expected, "pixel {x}");
+    }
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_pixel_rate_limit_ignores_invalid_pixels(
+    ip: IpAddr,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let fb = Arc::new(FrameBuffer::new(4, 1));
+    let canvases = Canvases::from([(
+        "main".to_string(),
+        Canvas {
+            fb: Arc::clone(&fb),
+            statistics_tx: statistics_channel.0,
+        },
+    )]);
+    let invalid: String = (0..4).map(|x| format!("PX {x} 0 fff\n")).collect();
+    let valid: String = (0..4).map(|x| format!("PX {x} 0 ffffff\n")).collect();
+    let mut stream = MockTcpStream::from_input(&(invalid + &valid));
+
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    handle_connection_to_canvases(
+        &mut stream,
+        ip,
+        None,
+        Arc::new(canvases),
+        "main".to_string(),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        Arc::new(PixelRateLimiter::new(4, PixelRateLimitMode::Drop, 64)),
+        ConnectionTimeouts::default(),
+        OutputLimit::default(),
+        shutdown_rx,
+    )
+    .await
+    .unwrap();
+
+    for x in 0..4 {
+        assert_eq!(fb.get(x, 0), Some(0x00ff_ffff), "pixel {x}");
+    }
+}
+
+#[rstest]
+#[case::shutdown(ConnectionTimeouts::default(), None)]
+#[case::max_lifetime(
+    ConnectionTimeouts {
+        max_lifetime: Some(std::time::Duration::from_millis(200)),
+        ..Default::default()
+    },
+    Some(ConnectionTimeout::MaxLifetime)
+)]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_throttled_connection_closed(
+    ip: IpAddr,
+    fb: Arc<FrameBuffer>,
+    #[case] connection_timeouts: ConnectionTimeouts,
+    #[case] expected_timeout: Option<ConnectionTimeout>,
+) {
+    let (statistics_tx, mut statistics_rx) = mpsc::channel(10000);
+    let canvases = Canvases::from([(
+        "main".to_string(),
+        Canvas {
+            fb: Arc::clone(&fb),
+            statistics_tx,
+        },
+    )]);
+    let (mut client, server) = tokio::io::duplex(1024);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let connection = tokio::spawn(handle_connection_to_canvases(
+        server,
+        ip,
+        None,
+        Arc::new(canvases),
+        "main".to_string(),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        // Drawing all the pixels would take a minute
+        Arc::new(PixelRateLimiter::new(1, PixelRateLimitMode::Throttle, 64)),
+        connection_timeouts,
+        OutputLimit::default(),
+        shutdown_rx,
+    ));
+
+    let input: String = (0..60).map(|x| format!("PX {x} 0 ffffff\n")).collect();
+    client.write_all(input.as_bytes()).await.unwrap();
+    while fb.get(0, 0) != Some(0xffffff) {
+        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+    }
+    if expected_timeout.is_none() {
+        shutdown_tx.send(true).unwrap();
+    }
+    connection.await.unwrap().unwrap();
+
+    assert_eq!(fb.get(59, 0), Some(0));
+    let mut events = Vec::new();
+    while let Ok(event) = statistics_rx.try_recv() {
+        events.push(event);
+    }
+    let timed_out = events.iter().find_map(|event| match event {
+        StatisticsEvent::ConnectionTimedOut { timeout } => Some(*timeout),
+        _ => None,
+    });
+    assert_eq!(timed_out, expected_timeout);
+    assert!(matches!(
+        events.last(),
+        Some(StatisticsEvent::ConnectionClosed { .. })
+    ));
+}
+
+#[test]
+fn test_pixel_rate_limiter() {
+    let ip: IpAddr = "2001:db8::1".parse().unwrap();
+    let same_prefix: IpAddr = "2001:db8::2".parse().unwrap();
+    let limiter = PixelRateLimiter::new(100, PixelRateLimitMode::Drop, 64);
+
+    assert_eq!(limiter.take(ip, 30), 30);
+    assert_eq!(limiter.take(ip, u64::MAX), 70);
+    // Shared by the whole /64
+    assert_eq!(limiter.take(same_prefix, u64::MAX), 0);
+    limiter.give_back(ip, 40);
+    assert_eq!(limiter.take(same_prefix, u64::MAX), 40);
+
+    limiter.set_pixels_per_s(None);
+    assert_eq!(limiter.pixels_per_s(), None);
+    assert_eq!(limiter.take(ip, 10), u64::MAX);
+
+    limiter.set_pixels_per_s(Some(10));
+    assert_eq!(limiter.take(ip, u64::MAX), 10);
+    assert_eq!(max_pixels(0), 1);
+    assert_eq!(max_pixels("PX 0 0 gg\n".len() * 3), 4);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_pixel_rate_limit_shared_by_connections(
+    ip: IpAddr,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let fb = Arc::new(FrameBuffer::new(20, 2));
+    let canvases = Arc::new(Canvases::from([(
+        "main".to_string(),
+        Canvas {
+            fb: Arc::clone(&fb),
+            statistics_tx: statistics_channel.0,
+        },
+    )]));
+    let pixel_rate_limiter = Arc::new(PixelRateLimiter::new(100, PixelRateLimitMode::Drop, 64));
+
+    // The first connection of the client is in the middle of parsing what it read
+    let first_input: String = (0..20).map(|x| format!("PX {x} 0 ffffff\n")).collect();
+    let first_budget = pixel_rate_limiter.take(ip, max_pixels(first_input.len()));
+    assert!(first_budget >= 20);
+
+    let second_input: String = (0..20).map(|x| format!("PX {x} 1 ffffff\n")).collect();
+    let mut stream = MockTcpStream::from_input(&second_input);
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    handle_connection_to_canvases(
+        &mut stream,
+        ip,
+        None,
+        canvases,
+        "main".to_string(),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        Arc::clone(&pixel_rate_limiter),
+        ConnectionTimeouts::default(),
+        OutputLimit::default(),
+        shutdown_rx,
+    )
+    .await
+    .unwrap();
+    pixel_rate_limiter.give_back(ip, first_budget);
+
+    for x in 0..20 {
+        assert_eq!(fb.get(x, 1), Some(0x00ff_ffff), "pixel {x}");
+    }
+}
+
+#[rstest]
+#[case::idle(
+    ConnectionTimeouts {
+        idle: Some(std::time::Duration::from_millis(100)),
+        ..Default::default()
+    },
+    None,
+    ConnectionTimeout::Idle
+)]
+#[case::too_slow(
+    ConnectionTimeouts {
+        min_bytes_per_s: Some(1000),
+        min_throughput_window: std::time::Duration::from_millis(200),
+        ..Default::default()
+    },
+    Some(std::time::Duration::from_millis(20)),
+    ConnectionTimeout::TooSlow
+)]
+#[case::max_lifetime(
+    ConnectionTimeouts {
+        idle: Some(std::time::Duration::from_secs(10)),
+        max_lifetime: Some(std::time::Duration::from_millis(200)),
+        ..Default::default()
+    },
+    Some(std::time::Duration::from_millis(20)),
+    ConnectionTimeout::MaxLifetime
+)]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_connection_timeouts(
+    ip: IpAddr,
+    fb: Arc<FrameBuffer>,
+    #[case] connection_timeouts: ConnectionTimeouts,
+    #[case] send_interval: Option<std::time::Duration>,
+    #[case] expected: ConnectionTimeout,
+) {
+    let (statistics_tx, mut statistics_rx) = mpsc::channel(10000);
+    let canvases = Canvases::from([("main".to_string(), Canvas { fb, statistics_tx })]);
+    let (mut client, server) = tokio::io::duplex(1024);
+
+    // Keeps the connection open, trickling in a pixel every now and then
+    let client = tokio::spawn(async move {
+        loop {
+            match send_interval {
+                Some(send_interval) => {
+                    tokio::time::sleep(send_interval).await;
+                    if client.write_all(b"PX 0 0 ffffff\n").await.is_err() {
+                        return;
+                    }
+                }
+                None => {
+                    let mut buffer = [0; 1];
+                    // Returns once the server closes the connection
+                    let _ = client.read(&mut buffer).await;
+                    return;
+                }
+            }
+        }
+    });
+
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    handle_connection_to_canvases(
+        server,
+        ip,
+        None,
+        Arc::new(canvases),
+        "main".to_string(),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        Arc::new(PixelRateLimiter::new(0, PixelRateLimitMode::Drop, 64)),
+        connection_timeouts,
+        OutputLimit::default(),
+        shutdown_rx,
+    )
+    .await
+    .unwrap();
+    client.await.unwrap();
+
+    let mut events = Vec::new();
+    while let Ok(event) = statistics_rx.try_recv() {
+        events.push(event);
+    }
+    assert!(matches!(
+        events.as_slice(),
+        [
+            StatisticsEvent::ConnectionCreated { .. },
+            ..,
+            StatisticsEvent::ConnectionTimedOut { timeout },
+            StatisticsEvent::ConnectionClosed { .. },
+        ] if *timeout == expected
+    ));
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_command_split_across_reads(ip: IpAddr, fb: Arc<FrameBuffer>) {
+    let (statistics_tx, _statistics_rx) = mpsc::channel(10000);
+    let canvases = Canvases::from([(
+        "main".to_string(),
+        Canvas {
+            fb: Arc::clone(&fb),
+            statistics_tx,
+        },
+    )]);
+    let (mut client, server) = tokio::io::duplex(1024);
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let connection = tokio::spawn(handle_connection_to_canvases(
+        server,
+        ip,
+        None,
+        Arc::new(canvases),
+        "main".to_string(),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        Arc::new(PixelRateLimiter::new(0, PixelRateLimitMode::Drop, 64)),
+        ConnectionTimeouts::default(),
+        OutputLimit::default(),
+        shutdown_rx,
+    ));
+
+    // The first read does not contain a single complete command
+    client.write_all(b"PX 1 2 ff").await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    client.write_all(b"0000\nPX 1 2\n").await.unwrap();
+    client.shutdown().await.unwrap();
+    connection.await.unwrap().unwrap();
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert_eq!(response, "PX 1 2 ff0000\n");
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_connection_closed_on_shutdown(ip: IpAddr, fb: Arc<FrameBuffer>) {
+    let (statistics_tx, mut statistics_rx) = mpsc::channel(10000);
+    let canvases = Canvases::from([(
+        "main".to_string(),
+        Canvas {
+            fb: Arc::clone(&fb),
+            statistics_tx,
+        },
+    )]);
+    let (mut client, server) = tokio::io::duplex(1024);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let connection = tokio::spawn(handle_connection_to_canvases(
+        server,
+        ip,
+        None,
+        Arc::new(canvases),
+        "main".to_string(),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        Arc::new(PixelRateLimiter::new(0, PixelRateLimitMode::Drop, 64)),
+        ConnectionTimeouts::default(),
+        OutputLimit::default(),
+        shutdown_rx,
+    ));
+
+    client.write_all(b"PX 0 0 ffffff\n").await.unwrap();
+    while fb.get(0, 0) != Some(0xffffff) {
+        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+    }
+    shutdown_tx.send(true).unwrap();
+    connection.await.unwrap().unwrap();
+
+    // The server closed the connection, although the client did not
+    let mut buffer = [0; 1];
+    assert_eq!(client.read(&mut buffer).await.unwrap(), 0);
+    let mut events = Vec::new();
+    while let Ok(event) = statistics_rx.try_recv() {
+        events.push(event);
+    }
+    assert!(matches!(
+        events.as_slice(),
+        [
+            StatisticsEvent::ConnectionCreated { .. },
+            ..,
+            StatisticsEvent::ConnectionClosed { .. },
+        ]
+    ));
+}
+
+#[rstest]
+#[case(OutputOverflowPolicy::Drop)]
+#[case(OutputOverflowPolicy::Pause)]
+#[case(OutputOverflowPolicy::Disconnect)]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_bounded_output(#[case] overflow_policy: OutputOverflowPolicy) {
+    const RESPONSE: &[u8] = b"PX 0 0 000000\n";
+    const RESPONSES: usize = 20;
+    // Fits 64 bytes until the client reads, the output buffer another 100
+    let (mut client, server) = tokio::io::duplex(64);
+    let mut output = BoundedOutput::new(
+        server,
+        OutputLimit {
+            max_size: 100,
+            overflow_policy,
+        },
+    );
+
+    match overflow_policy {
+        OutputOverflowPolicy::Drop => {
+            for _ in 0..RESPONSES {
+                output.write_all(RESPONSE).await.unwrap();
+            }
+            let dropped = output.take_dropped_responses() as usize;
+            assert!(dropped > 0);
+            assert!(output.pending() <= 100);
+
+            let mut received = vec![0; (RESPONSES - dropped) * RESPONSE.len()];
+            let (flushed, read) = tokio::join!(output.flush(), client.read_exact(&mut received));
+            flushed.unwrap();
+            read.unwrap();
+            assert!(received.chunks(RESPONSE.len()).all(|r| r == RESPONSE));
+        }
+        OutputOverflowPolicy::Pause => {
+            let mut written = 0;
+            let writes = async {
+                while written < RESPONSES {
+                    output.write_all(RESPONSE).await.unwrap();
+                    written += 1;
+                }
+            };
+            assert!(
+                tokio::time::timeout(std::time::Duration::from_millis(50), writes)
+                    .await
+                    .is_err(),
+                "Writing needs to wait for the client"
+            );
+            assert!(written < RESPONSES);
+
+            let mut received = vec![0; RESPONSES * RESPONSE.len()];
+            let remaining_writes = async {
+                for _ in written..RESPONSES {
+                    output.write_all(RESPONSE).await.unwrap();
+                }
+                output.flush().await.unwrap();
+            };
+            let (_, read) = tokio::join!(remaining_writes, client.read_exact(&mut received));
+            read.unwrap();
+            assert!(received.chunks(RESPONSE.len()).all(|r| r == RESPONSE));
+            assert_eq!(output.take_dropped_responses(), 0);
+        }
+        OutputOverflowPolicy::Disconnect => {
+            let mut results = Vec::new();
+            for _ in 0..RESPONSES {
+                results.push(output.write_all(RESPONSE).await.is_ok());
+            }
+            assert!(results.contains(&false));
+            assert!(output.overflowed());
+        }
+    }
+}
+
+#[rstest]
+#[case::shutdown(ConnectionTimeouts::default(), None)]
+#[case::idle(
+    ConnectionTimeouts {
+        idle: Some(std::time::Duration::from_millis(200)),
+        ..Default::default()
+    },
+    Some(ConnectionTimeout::Idle)
+)]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_paused_output_connection_closed(
+    ip: IpAddr,
+    fb: Arc<FrameBuffer>,
+    #[case] connection_timeouts: ConnectionTimeouts,
+    #[case] expected_timeout: Option<ConnectionTimeout>,
+) {
+    let (statistics_tx, mut statistics_rx) = mpsc::channel(10000);
+    let canvases = Canvases::from([(String::new(), Canvas { fb, statistics_tx })]);
+    let (client, server) = tokio::io::duplex(1024);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let connection = tokio::spawn(handle_connection_to_canvases(
+        server,
+        ip,
+        None,
+        Arc::new(canvases),
+        String::new(),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        Arc::new(PixelRateLimiter::new(0, PixelRateLimitMode::Drop, 64)),
+        connection_timeouts,
+        OutputLimit {
+            max_size: 1024,
+            overflow_policy: OutputOverflowPolicy::Pause,
+        },
+        shutdown_rx,
+    ));
+
+    // Reads a lot of pixels, but never the responses
+    let (_client_reader, mut client_writer) = tokio::io::split(client);
+    let requests = "PX 0 0\n".repeat(10_000);
+    let client = tokio::spawn(async move {
+        // Fails once the server closes the connection
+        let _ = client_writer.write_all(requests.as_bytes()).await;
+    });
+
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(
+        !connection.is_finished(),
+        "The connection needs to be paused"
+    );
+    if expected_timeout.is_none() {
+        shutdown_tx.send(true).unwrap();
+    }
+    connection.await.unwrap().unwrap();
+    client.await.unwrap();
+
+    let mut events = Vec::new();
+    while let Ok(event) = statistics_rx.try_recv() {
+        events.push(event);
+    }
+    let timed_out = events.iter().find_map(|event| match event {
+        StatisticsEvent::ConnectionTimedOut { timeout } => Some(*timeout),
+        _ => None,
+    });
+    assert_eq!(timed_out, expected_timeout);
+    assert!(matches!(
+        events.last(),
+        Some(StatisticsEvent::ConnectionClosed { .. })
+    ));
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_output_overflow_disconnect(ip: IpAddr, fb: Arc<FrameBuffer>) {
+    let (statistics_tx, mut statistics_rx) = mpsc::channel(10000);
+    let canvases = Canvases::from([(String::new(), Canvas { fb, statistics_tx })]);
+    let (client, server) = tokio::io::duplex(1024);
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    let connection = tokio::spawn(handle_connection_to_canvases(
+        server,
+        ip,
+        None,
+        Arc::new(canvases),
+        String::new(),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        Arc::new(PixelRateLimiter::new(0, PixelRateLimitMode::Drop, 64)),
+        ConnectionTimeouts::default(),
+        OutputLimit {
+            max_size: 1024,
+            overflow_policy: OutputOverflowPolicy::Disconnect,
+        },
+        shutdown_rx,
+    ));
+
+    // Reads a lot of pixels, but never the responses
+    let (_client_reader, mut client_writer) = tokio::io::split(client);
+    let requests = "PX 0 0\n".repeat(10_000);
+    // Fails once the server closes the connection
+    let _ = client_writer.write_all(requests.as_bytes()).await;
+
+    connection.await.unwrap().unwrap();
+    let mut events = Vec::new();
+    while let Ok(event) = statistics_rx.try_recv() {
+        events.push(event);
+    }
+    assert!(matches!(
+        events.as_slice(),
+        [
+            StatisticsEvent::ConnectionCreated { .. },
+            ..,
+            StatisticsEvent::OutputOverflowed,
+            StatisticsEvent::ConnectionClosed { .. }
+        ]
+    ));
+}
+
+#[cfg(feature = "io-uring")]
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_io_uring_server(
+    ip: IpAddr,
+    fb: Arc<FrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let (statistics_tx, mut statistics_rx) = statistics_channel;
+    let other_fb = Arc::new(FrameBuffer::new(640, 480));
+    let (other_statistics_tx, mut other_statistics_rx) = mpsc::channel(10000);
+    let canvases = Canvases::from([
+        (
+            "main".to_string(),
+            Canvas {
+                fb: Arc::clone(&fb),
+                statistics_tx,
+            },
+        ),
+        (
+            "other".to_string(),
+            Canvas {
+                fb: Arc::clone(&other_fb),
+                statistics_tx: other_statistics_tx,
+            },
+        ),
+    ]);
+    let connection_limits = Arc::new(ConnectionLimits::new(None, None, None, 64));
+    let server = IoUringServer::new(
+        &"tag=127.0.0.1:0".parse().unwrap(),
+        "main".to_string(),
+        Arc::new(canvases),
+        Arc::clone(&connection_limits),
+        false,
+        None,
+        Arc::new(PixelRateLimiter::new(0, PixelRateLimitMode::Drop, 64)),
+        4,
+        4096,
+    )
+    .unwrap();
+    let server_addr = server.local_addr();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let server = std::thread::spawn(move || server.run(shutdown_rx));
+
+    let mut client = tokio::net::TcpStream::connect(server_addr).await.unwrap();
+    // Commands spanning multiple reads are stitched together
+    let writes = [
+        "PX 1 2 ff0000\nSIZE\nPX 3 4 00",
+        "ff00\nCANVAS missing\nCANVAS other\nPX 5 6 0000ff\n",
+        &"PX 7 8 ffffff\n".repeat(1000),
+        "SIZE\n",
+    ];
+    for write in writes {
+        client.write_all(write.as_bytes()).await.unwrap();
+        client.flush().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    let expected = "SIZE 1920 1080\nCANVAS missing not found\nSIZE 640 480\n";
+    let mut response = vec![0; expected.len()];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(String::from_utf8(response).unwrap(), expected);
+    assert_eq!(fb.get(1, 2), Some(0x0000ff));
+    assert_eq!(fb.get(3, 4), Some(0x00ff00));
+    assert_eq!(other_fb.get(5, 6), Some(0xff0000));
+    assert_eq!(other_fb.get(7, 8), Some(0xffffff));
+    assert_eq!(connection_limits.connections(), 1);
+
+    shutdown_tx.send(true).unwrap();
+    server.join().unwrap().unwrap();
+    // The server closed the connection, although the client did not
+    let mut buffer = [0; 1];
+    assert_eq!(client.read(&mut buffer).await.unwrap(), 0);
+    assert_eq!(connection_limits.connections(), 0);
+
+    let mut main_events = Vec::new();
+    while let Ok(event) = statistics_rx.try_recv() {
+        main_events.push(event);
+    }
+    let mut other_events = Vec::new();
+    let mut other_bytes_read = 0;
+    while let Ok(event) = other_statistics_rx.try_recv() {
+        if let StatisticsEvent::BytesRead { bytes, .. } = event {
+            other_bytes_read += bytes;
+        } else {
+            other_events.push(event);
+        }
+    }
+    let listener = Some(Arc::from("tag"));
+    assert!(matches!(
+        main_events.as_slice(),
+        [
+            StatisticsEvent::ConnectionCreated { ip: created_ip, listener: created_listener },
+            ..,
+            StatisticsEvent::ConnectionClosed { .. },
+        ] if *created_ip == ip && *created_listener == listener
+    ));
+    assert!(matches!(
+        other_events.as_slice(),
+        [
+            StatisticsEvent::ConnectionCreated { .. },
+            StatisticsEvent::ConnectionClosed { .. },
+        ]
+    ));
+    let total_bytes: u64 = writes.iter().map(|write| write.len() as u64).sum();
+    assert!(other_bytes_read > 0 && other_bytes_read < total_bytes);
+}
+
+#[rstest]
+#[case::with_responses(true)]
+#[case::without_responses(false)]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_udp_server(
+    ip: IpAddr,
+    fb: Arc<FrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+    #[case] send_responses: bool,
+) {
+    let (statistics_tx, mut statistics_rx) = statistics_channel;
+    let server = UdpServer::new(
+        "127.0.0.1:0",
+        Arc::clone(&fb),
+        statistics_tx,
+        send_responses,
+        None,
+        Arc::new(PixelRateLimiter::new(0, PixelRateLimitMode::Drop, 64)),
+    )
+    .await
+    .unwrap();
+    let server_addr = server.local_addr();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let server = tokio::spawn(async move { server.start(shutdown_rx).await });
+
+    let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(server_addr).await.unwrap();
+    // The last command of a datagram does not need a newline, CANVAS is ignored
+    let datagrams = [
+        "PX 1 2 ff0000\nSIZE\nPX 3 4 00ff00",
+        "CANVAS other\nPX 10 10 0000ff\n",
+    ];
+    for datagram in datagrams {
+        client.send(datagram.as_bytes()).await.unwrap();
+    }
+
+    while fb.get(10, 10) != Some(0xff0000) {
+        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+    }
+    assert_eq!(fb.get(1, 2), Some(0x0000ff));
+    assert_eq!(fb.get(3, 4), Some(0x00ff00));
+
+    if send_responses {
+        let mut response = [0; 64];
+        let bytes = client.recv(&mut response).await.unwrap();
+        assert_eq!(&response[..bytes], b"SIZE 1920 1080\n");
+    }
+
+    shutdown_tx.send(true).unwrap();
+    server.await.unwrap().unwrap();
+    let mut bytes_read = 0;
+    while let Ok(event) = statistics_rx.try_recv() {
+        if let StatisticsEvent::BytesRead {
+            ip: event_ip,
+            bytes,
+            ..
+        } = event
+        {
+            assert_eq!(event_ip, ip);
+            bytes_read += bytes;
+        }
+    }
+    assert_eq!(
+        bytes_read,
+        datagrams
+            .iter()
+            .map(|datagram| datagram.len() as u64)
+            .sum::<u64>()
+    );
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_websocket(ip: IpAddr, fb: Arc<FrameBuffer>) {
+    let (statistics_tx, _statistics_rx) = mpsc::channel(10000);
+    let canvases = Canvases::from([(
+        "main".to_string(),
+        Canvas {
+            fb: Arc::clone(&fb),
+            statistics_tx,
+        },
+    )]);
+    let (client, server) = tokio::io::duplex(1024);
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let connection = tokio::spawn(async move {
+        let websocket_stream = tokio_tungstenite::accept_async(server).await.unwrap();
+        handle_connection_to_canvases(
+            WebSocketAdapter::new(websocket_stream),
+            ip,
+            None,
+            Arc::new(canvases),
+            "main".to_string(),
+            Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+            Arc::new(PixelRateLimiter::new(0, PixelRateLimitMode::Drop, 64)),
+            ConnectionTimeouts::default(),
+            OutputLimit::default(),
+            shutdown_rx,
+        )
+        .await
+    });
+    let (mut client, _) = tokio_tungstenite::client_async("ws://localhost/", client)
+        .await
+        .unwrap();
+
+    // Commands can span messages
+    client
+        .send(Message::Text("PX 1 2 ff0000\nPX 3 4 ".to_string()))
+        .await
+        .unwrap();
+    client
+        .send(Message::Binary(b"00ff00\nSIZE\nPX 1 2\n".to_vec()))
+        .await
+        .unwrap();
+    // Responses are written in bulk, so they might share a message
+    let mut responses = String::new();
+    while responses.lines().count() < 2 {
+        match client.next().await.unwrap().unwrap() {
+            Message::Text(text) => responses.push_str(&text),
+            message => panic!("Unexpected message {message:?}"),
+        }
+    }
+    assert_eq!(responses, "SIZE 1920 1080\nPX 1 2 ff0000\n");
+    client.close(None).await.unwrap();
+
+    connection.await.unwrap().unwrap();
+    assert_eq!(fb.get(1, 2), Some(0x0000ff));
+    assert_eq!(fb.get(3, 4), Some(0x00ff00));
+}
+
+#[rstest]
+#[case("[::]:1234", Ok((None, ListenEndpoint::Tcp("[::]:1234".to_string()))))]
+#[case(
+    "wifi=0.0.0.0:1234",
+    Ok((Some("wifi"), ListenEndpoint::Tcp("0.0.0.0:1234".to_string())))
+)]
+#[case(
+    "unix:/run/breakwater.sock",
+    Ok((None, ListenEndpoint::Unix("/run/breakwater.sock".into())))
+)]
+#[case(
+    "generative-art=unix:/tmp/a=b.sock",
+    Ok((Some("generative-art"), ListenEndpoint::Unix("/tmp/a=b.sock".into())))
+)]
+#[case("unix:", Err("invalid listen address \"unix:\", the path is missing"))]
+#[case(
+    "wifi=",
+    Err("invalid listen address \"wifi=\", the address is missing")
+)]
+fn test_parse_listen_address(
+    #[case] input: &str,
+    #[case] expected: Result<(Option<&str>, ListenEndpoint), &str>,
+) {
+    let expected = expected
+        .map(|(tag, endpoint)| ListenAddress {
+            tag: tag.map(str::to_string),
+            endpoint,
+        })
+        .map_err(str::to_string);
+    let parsed = input.parse::<ListenAddress>();
+    assert_eq!(parsed, expected);
+    if let Ok(parsed) = parsed {
+        assert_eq!(parsed.to_string(), input);
+    }
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_unix_socket_listener(fb: Arc<FrameBuffer>) {
+    let path = std::env::temp_dir().join(format!("breakwater-test-{}.sock", std::process::id()));
+    // A leftover socket file is replaced
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    // Unix domain sockets are not subject to the access list
+    let access_list_file = std::env::temp_dir().join(format!(
+        "breakwater-test-unix-access-list-{}",
+        std::process::id()
+    ));
+    std::fs::write(&access_list_file, "allow 192.0.2.0/24\n").unwrap();
+    let access_list = AccessList::load(access_list_file.clone()).unwrap();
+    std::fs::remove_file(access_list_file).unwrap();
+
+    let (statistics_tx, mut statistics_rx) = mpsc::channel(10000);
+    let canvases = Canvases::from([(
+        "main".to_string(),
+        Canvas {
+            fb: Arc::clone(&fb),
+            statistics_tx,
+        },
+    )]);
+    let server = Server::new(
+        &ListenAddress {
+            tag: Some("art".to_string()),
+            endpoint: ListenEndpoint::Unix(path.clone()),
+        },
+        "main".to_string(),
+        Arc::new(canvases),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        // Neither are the per-IP limits, as all clients share the same IP address
+        Arc::new(ConnectionLimits::new(None, Some(1), None, 64)),
+        false,
+        Some(Arc::new(access_list)),
+        Arc::new(PixelRateLimiter::new(1, PixelRateLimitMode::Drop, 64)),
+        ConnectionTimeouts::default(),
+        OutputLimit::default(),
+        Transport::Plain,
+        Arc::from([]),
+        false,
+    )
+    .await
+    .unwrap();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let server = tokio::spawn(async move { server.start(shutdown_rx).await });
+
+    let mut client = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let mut other_client = tokio::net::UnixStream::connect(&path).await.unwrap();
+    client
+        .write_all(b"PX 1 2 ff0000\nPX 3 4 00ff00\nSIZE\n")
+        .await
+        .unwrap();
+    other_client
+        .write_all(b"PX 5 6 0000ff\nSIZE\n")
+        .await
+        .unwrap();
+    for client in [&mut client, &mut other_client] {
+        let mut response = [0; 15];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"SIZE 1920 1080\n");
+    }
+    assert_eq!(fb.get(1, 2), Some(0x0000ff));
+    assert_eq!(fb.get(3, 4), Some(0x00ff00));
+    assert_eq!(fb.get(5, 6), Some(0xff0000));
+    drop(client);
+    drop(other_client);
+
+    match statistics_rx.recv().await.unwrap() {
+        StatisticsEvent::ConnectionCreated { ip, listener } => {
+            assert_eq!(ip, UNIX_SOCKET_CLIENT_IP);
+            assert_eq!(listener.as_deref(), Some("art"));
+        }
+        event => panic!("unexpected statistics event {event:?}"),
+    }
+
+    shutdown_tx.send(true).unwrap();
+    server.await.unwrap().unwrap();
+    // The socket file is removed together with the listener
+    assert!(!path.exists());
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_listener_reuse_port() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let endpoint = ListenEndpoint::Tcp(format!("127.0.0.1:{port}"));
+
+    let _first = Listener::bind(&endpoint, true).await.unwrap();
+    let _second = Listener::bind(&endpoint, true).await.unwrap();
+    assert!(Listener::bind(&endpoint, false).await.is_err());
+}
+
+#[rstest]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_thread_per_core(
+    fb: Arc<FrameBuffer>,
+    statistics_channel: (
+        mpsc::Sender<StatisticsEvent>,
+        mpsc::Receiver<StatisticsEvent>,
+    ),
+) {
+    let (statistics_tx, mut statistics_rx) = statistics_channel;
+    let canvases = Canvases::from([(
+        "main".to_string(),
+        Canvas {
+            fb: Arc::clone(&fb),
+            statistics_tx,
+        },
+    )]);
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let connection_limits = Arc::new(ConnectionLimits::default());
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let workers = ThreadPerCoreServer::new(
+        vec![format!("127.0.0.1:{port}").parse().unwrap()],
+        "main".to_string(),
+        Arc::new(canvases),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        Arc::clone(&connection_limits),
+        false,
+        None,
+        Arc::new(PixelRateLimiter::new(0, PixelRateLimitMode::Drop, 64)),
+        ConnectionTimeouts::default(),
+        OutputLimit::default(),
+        Arc::from([]),
+        vec![0, 0],
+        None,
+    )
+    .start(shutdown_rx, stop_rx)
+    .await
+    .unwrap();
+    assert_eq!(workers.len(), 2);
+
+    // Whichever worker the kernel hands the connections to, they are all served
+    let mut clients = Vec::new();
+    for x in 0..8 {
+        let mut client = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        client
+            .write_all(format!("PX {x} 0 ff0000\nSIZE\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = [0; 15];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"SIZE 1920 1080\n");
+        assert_eq!(fb.get(x, 0), Some(0x0000ff));
+        clients.push(client);
+    }
+
+    // The workers keep running until they are stopped, so the connections can close on their own
+    shutdown_tx.send(true).unwrap();
+    drop(clients);
+    while connection_limits.connections() > 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+    }
+    stop_tx.send(true).unwrap();
+    for worker in workers {
+        tokio::task::spawn_blocking(move || worker.join())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    let mut connections_created = 0;
+    let mut connections_closed = 0;
+    while let Ok(event) = statistics_rx.try_recv() {
+        match event {
+            StatisticsEvent::ConnectionCreated { .. } => connections_created += 1,
+            StatisticsEvent::ConnectionClosed { .. } => connections_closed += 1,
+            _ => {}
+        }
+    }
+    assert_eq!(connections_created, 8);
+    assert_eq!(connections_closed, 8);
+}
+
+#[rstest]
+#[case::without_client_ca(false, false, true)]
+#[case::with_client_certificate(true, true, true)]
+#[case::without_client_certificate(true, false, false)]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_tls(
+    fb: Arc<FrameBuffer>,
+    #[case] require_client_certificate: bool,
+    #[case] send_client_certificate: bool,
+    #[case] expect_served: bool,
+) {
+    let ca = {
+        let mut params = rcgen::CertificateParams::new(Vec::new());
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        rcgen::Certificate::from_params(params).unwrap()
+    };
+    let server_certificate = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![
+        "localhost".to_string(),
+    ]))
+    .unwrap();
+    let client_certificate =
+        rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec!["team-1".to_string()]))
+            .unwrap();
+
+    let dir = std::env::temp_dir().join(format!(
+        "breakwater-test-tls-{}-{require_client_certificate}-{send_client_certificate}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("cert.pem"),
+        server_certificate.serialize_pem_with_signer(&ca).unwrap(),
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("key.pem"),
+        server_certificate.serialize_private_key_pem(),
+    )
+    .unwrap();
+    std::fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+    let tls_acceptor = tls::load_acceptor(
+        &dir.join("cert.pem"),
+        &dir.join("key.pem"),
+        require_client_certificate
+            .then(|| dir.join("ca.pem"))
+            .as_deref(),
+    )
+    .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let (statistics_tx, _statistics_rx) = mpsc::channel(10000);
+    let canvases = Canvases::from([(
+        "main".to_string(),
+        Canvas {
+            fb: Arc::clone(&fb),
+            statistics_tx,
+        },
+    )]);
+    let server = Server::new(
+        &"127.0.0.1:0".parse().unwrap(),
+        "main".to_string(),
+        Arc::new(canvases),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        Arc::new(ConnectionLimits::default()),
+        false,
+        None,
+        Arc::new(PixelRateLimiter::new(0, PixelRateLimitMode::Drop, 64)),
+        ConnectionTimeouts::default(),
+        OutputLimit::default(),
+        Transport::Tls(tls_acceptor),
+        Arc::from([]),
+        false,
+    )
+    .await
+    .unwrap();
+    let server_addr = server.local_addr();
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move { server.start(shutdown_rx).await });
+
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from(ca.serialize_der().unwrap()))
+        .unwrap();
+    let client_config = ClientConfig::builder().with_root_certificates(roots);
+    let client_config = if send_client_certificate {
+        client_config
+            .with_client_auth_cert(
+                vec![CertificateDer::from(
+                    client_certificate.serialize_der_with_signer(&ca).unwrap(),
+                )],
+                PrivateKeyDer::Pkcs8(client_certificate.serialize_private_key_der().into()),
+            )
+            .unwrap()
+    } else {
+        client_config.with_no_client_auth()
+    };
+    let stream = tokio::net::TcpStream::connect(server_addr).await.unwrap();
+    let mut client = TlsConnector::from(Arc::new(client_config))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+
+    // With TLS 1.3 the server rejects a missing client certificate only after the client finished the handshake
+    let _ = client.write_all(b"PX 1 2 ff0000\nSIZE\n").await;
+    if expect_served {
+        let mut response = [0; 15];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"SIZE 1920 1080\n");
+        assert_eq!(fb.get(1, 2), Some(0x0000ff));
+    } else {
+        let mut response = Vec::new();
+        let _ = client.read_to_end(&mut response).await;
+        assert!(response.is_empty());
+        assert_eq!(fb.get(1, 2), Some(0));
+    }
+}
+
+#[rstest]
+#[case::v1_tcp4(
+    b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 1234\r\n",
+    Ok(Some("192.0.2.1"))
+)]
+#[case::v1_tcp6(
+    b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 1234\r\n",
+    Ok(Some("2001:db8::1"))
+)]
+#[case::v1_unknown(b"PROXY UNKNOWN\r\n", Ok(None))]
+#[case::v1_unknown_with_addresses(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n", Ok(None))]
+#[case::v1_wrong_family(
+    b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 1234\r\n",
+    Err(
+        "Invalid PROXY protocol v1 header \"PROXY TCP4 2001:db8::1 2001:db8::2 56324 1234\\r\\n\""
+    )
+)]
+#[case::v1_missing_ports(
+    b"PROXY TCP4 192.0.2.1 198.51.100.1\r\n",
+    Err("Invalid PROXY protocol v1 header \"PROXY TCP4 192.0.2.1 198.51.100.1\\r\\n\"")
+)]
+#[case::v1_too_long(&[b"PROXY UNKNOWN ".as_slice(), &[b'a'; 100]].concat(), Err("Invalid PROXY protocol v1 header \"PROXY UNKNOWN aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\""))]
+#[case::v2_inet(
+    b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\xc0\x00\x02\x01\xc6\x33\x64\x01\xdc\x04\x04\xd2",
+    Ok(Some("192.0.2.1"))
+)]
+#[case::v2_inet6(
+    &[
+        b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".as_slice(),
+        &"2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets(),
+        &[0; 20],
+    ].concat(),
+    Ok(Some("2001:db8::1"))
+)]
+#[case::v2_local(b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00", Ok(None))]
+#[case::v2_unspec(b"\r\n\r\n\0\r\nQUIT\n\x21\x00\x00\x00", Ok(None))]
+#[case::v2_truncated(
+    b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x02\xc0\x00",
+    Err("Invalid PROXY protocol v2 header: addresses are truncated")
+)]
+#[case::v2_wrong_version(
+    b"\r\n\r\n\0\r\nQUIT\n\x11\x11\x00\x00",
+    Err("Invalid PROXY protocol v2 header: unsupported version")
+)]
+#[case::missing(
+    b"PX 1 2 ff0000\nPX 3 4 00ff00\n",
+    Err("The connection does not start with a PROXY protocol header")
+)]
+#[tokio::test]
+async fn test_proxy_protocol_header(
+    #[case] header: &[u8],
+    #[case] expected: Result<Option<&str>, &str>,
+) {
+    // The data after the header is left untouched
+    let input = [header, b"PX 1 2 ff0000\n"].concat();
+    let mut stream = input.as_slice();
+
+    let result = proxy_protocol::read_header(&mut stream).await;
+    match expected {
+        Ok(expected) => {
+            assert_eq!(
+                result.unwrap(),
+                expected.map(|ip| ip.parse::<IpAddr>().unwrap())
+            );
+            assert_eq!(stream, b"PX 1 2 ff0000\n");
+        }
+        Err(expected) => assert_eq!(result.unwrap_err().to_string(), expected),
+    }
+}
+
+#[rstest]
+#[case::trusted("127.0.0.0/8", Some("192.0.2.1"))]
+#[case::untrusted("10.0.0.0/8", None)]
+#[timeout(std::time::Duration::from_secs(5))]
+#[tokio::test]
+async fn test_proxy_protocol(
+    ip: IpAddr,
+    fb: Arc<FrameBuffer>,
+    #[case] trusted_proxy: &str,
+    #[case] expected_ip: Option<&str>,
+) {
+    let (statistics_tx, mut statistics_rx) = mpsc::channel(10000);
+    let canvases = Canvases::from([(
+        "main".to_string(),
+        Canvas {
+            fb: Arc::clone(&fb),
+            statistics_tx,
+        },
+    )]);
+    let server = Server::new(
+        &"127.0.0.1:0".parse().unwrap(),
+        "main".to_string(),
+        Arc::new(canvases),
+        Arc::new(BufferPool::new(DEFAULT_NETWORK_BUFFER_SIZE, None)),
+        Arc::new(ConnectionLimits::default()),
+        false,
+        None,
+        Arc::new(PixelRateLimiter::new(0, PixelRateLimitMode::Drop, 64)),
+        ConnectionTimeouts::default(),
+        OutputLimit::default(),
+        Transport::Plain,
+        Arc::from([trusted_proxy.parse().unwrap()]),
+        false,
+    )
+    .await
+    .unwrap();
+    let server_addr = server.local_addr();
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move { server.start(shutdown_rx).await });
+
+    let mut client = tokio::net::TcpStream::connect(server_addr).await.unwrap();
+    client
+        .write_all(b"PROXY TCP4 192.0.2.1 127.0.0.1 56324 1234\r\nPX 1 2 ff0000\nSIZE\n")
+        .await
+        .unwrap();
+
+    match statistics_rx.recv().await.unwrap() {
+        StatisticsEvent::ConnectionCreated {
+            ip: connection_ip, ..
+        } => assert_eq!(
+            connection_ip,
+            expected_ip.map_or(ip, |expected_ip| expected_ip.parse().unwrap())
+        ),
+        event => panic!("unexpected statistics event {event:?}"),
+    }
+    // Untrusted peers can't send a header, so it is parsed as (invalid) Pixelflut command
+    let mut response = [0; 15];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"SIZE 1920 1080\n");
+    assert_eq!(fb.get(1, 2), Some(0x0000ff));
+}
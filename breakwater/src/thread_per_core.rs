@@ -0,0 +1,241 @@
+use std::{sync::Arc, thread::JoinHandle};
+
+use core_affinity::CoreId;
+use ipnet::IpNet;
+use log::{info, warn};
+use snafu::{OptionExt, ResultExt, Snafu};
+use thread_priority::{set_current_thread_priority, ThreadPriority};
+use tokio::{
+    runtime::Runtime,
+    sync::{oneshot, watch},
+};
+
+use crate::{
+    access_list::AccessList,
+    buffer_pool::BufferPool,
+    canvas::Canvases,
+    connection_limits::ConnectionLimits,
+    connection_timeouts::ConnectionTimeouts,
+    listener::ListenAddress,
+    output_buffer::OutputLimit,
+    pixel_rate_limit::PixelRateLimiter,
+    server::{self, Server, Transport},
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to get the cores of this machine"))]
+    GetCores {},
+
+    #[snafu(display("Failed to get cross-platform ThreadPriority: {message}"))]
+    GetThreadPriority { message: String },
+
+    #[snafu(display("Failed to spawn worker thread for core {core}"))]
+    SpawnWorkerThread { source: std::io::Error, core: usize },
+
+    #[snafu(display("Failed to build the runtime of the worker on core {core}"))]
+    BuildRuntime { source: std::io::Error, core: usize },
+
+    #[snafu(display("Failed to start Pixelflut server of the worker on core {core}"))]
+    StartServer { source: server::Error, core: usize },
+
+    #[snafu(display("The worker on core {core} stopped while starting"))]
+    WorkerStopped { core: usize },
+
+    #[snafu(display("Pixelflut server of the worker on core {core} failed"))]
+    RunServer { source: server::Error, core: usize },
+}
+
+/// Serves the listen addresses from one worker thread per core instead of the shared multi-threaded runtime.
+///
+/// Every worker is pinned to its core, runs a single-threaded runtime and has its own `SO_REUSEPORT` listener per
+/// listen address. The kernel distributes the connections between the listeners, so a connection stays on the core it
+/// was accepted on.
+pub struct ThreadPerCoreServer {
+    listen_addresses: Vec<ListenAddress>,
+    canvas: String,
+    canvases: Arc<Canvases>,
+    buffer_pool: Arc<BufferPool>,
+    connection_limits: Arc<ConnectionLimits>,
+    send_connection_limit_reason: bool,
+    access_list: Option<Arc<AccessList>>,
+    pixel_rate_limiter: Arc<PixelRateLimiter>,
+    connection_timeouts: ConnectionTimeouts,
+    output_limit: OutputLimit,
+    trusted_proxies: Arc<[IpNet]>,
+    /// All cores if empty
+    cores: Vec<usize>,
+    priority: Option<u8>,
+}
+
+impl ThreadPerCoreServer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        listen_addresses: Vec<ListenAddress>,
+        canvas: String,
+        canvases: Arc<Canvases>,
+        buffer_pool: Arc<BufferPool>,
+        connection_limits: Arc<ConnectionLimits>,
+        send_connection_limit_reason: bool,
+        access_list: Option<Arc<AccessList>>,
+        pixel_rate_limiter: Arc<PixelRateLimiter>,
+        connection_timeouts: ConnectionTimeouts,
+        output_limit: OutputLimit,
+        trusted_proxies: Arc<[IpNet]>,
+        cores: Vec<usize>,
+        priority: Option<u8>,
+    ) -> Self {
+        Self {
+            listen_addresses,
+            canvas,
+            canvases,
+            buffer_pool,
+            connection_limits,
+            send_connection_limit_reason,
+            access_list,
+            pixel_rate_limiter,
+            connection_timeouts,
+            output_limit,
+            trusted_proxies,
+            cores,
+            priority,
+        }
+    }
+
+    /// Spawns the workers and returns once all of them are listening.
+    ///
+    /// The workers stop accepting connections once `shutdown` is set, but keep running the open connections until
+    /// `stop` is set.
+    pub async fn start(
+        self,
+        shutdown: watch::Receiver<bool>,
+        stop: watch::Receiver<bool>,
+    ) -> Result<Vec<JoinHandle<Result<(), Error>>>, Error> {
+        let cores = if self.cores.is_empty() {
+            core_affinity::get_core_ids().context(GetCoresSnafu)?
+        } else {
+            self.cores.iter().map(|&id| CoreId { id }).collect()
+        };
+
+        let this = Arc::new(self);
+        let mut workers = Vec::with_capacity(cores.len());
+        for core in cores {
+            let (started_tx, started_rx) = oneshot::channel();
+            let this = Arc::clone(&this);
+            let shutdown = shutdown.clone();
+            let stop = stop.clone();
+            let worker = std::thread::Builder::new()
+                .name(format!("breakwater worker (core {})", core.id))
+                .spawn(move || this.run_worker(core, started_tx, shutdown, stop))
+                .context(SpawnWorkerThreadSnafu { core: core.id })?;
+            started_rx
+                .await
+                .map_err(|_| Error::WorkerStopped { core: core.id })??;
+            workers.push(worker);
+        }
+        info!(
+            "Started {} thread-per-core workers serving {}",
+            workers.len(),
+            this.listen_addresses
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        Ok(workers)
+    }
+
+    fn run_worker(
+        &self,
+        core: CoreId,
+        started_tx: oneshot::Sender<Result<(), Error>>,
+        shutdown: watch::Receiver<bool>,
+        mut stop: watch::Receiver<bool>,
+    ) -> Result<(), Error> {
+        let runtime = match self.setup_worker(core) {
+            Ok(runtime) => runtime,
+            Err(err) => {
+                let _ = started_tx.send(Err(err));
+                return Ok(());
+            }
+        };
+
+        runtime.block_on(async move {
+            let mut servers = Vec::with_capacity(self.listen_addresses.len());
+            for listen_address in &self.listen_addresses {
+                match self.new_server(listen_address).await {
+                    Ok(server) => servers.push(server),
+                    Err(err) => {
+                        let _ =
+                            started_tx.send(Err(err).context(StartServerSnafu { core: core.id }));
+                        return Ok(());
+                    }
+                }
+            }
+            let _ = started_tx.send(Ok(()));
+
+            // The connections are spawned onto this runtime, so they never leave this thread
+            let server_tasks: Vec<_> = servers
+                .into_iter()
+                .map(|server| {
+                    let shutdown = shutdown.clone();
+                    tokio::spawn(async move { server.start(shutdown).await })
+                })
+                .collect();
+            for server_task in server_tasks {
+                if let Ok(result) = server_task.await {
+                    result.context(RunServerSnafu { core: core.id })?;
+                }
+            }
+
+            // Keep the connections spawned onto this runtime running while they drain
+            let _ = stop.wait_for(|stop| *stop).await;
+            Ok(())
+        })
+    }
+
+    fn setup_worker(&self, core: CoreId) -> Result<Runtime, Error> {
+        // Neither is fatal, the worker just might perform worse
+        if !core_affinity::set_for_current(core) {
+            warn!("Failed to pin worker thread to core {}", core.id);
+        }
+        if let Some(priority) = self.priority {
+            let priority = priority
+                .try_into()
+                .map_err(|err: &str| Error::GetThreadPriority {
+                    message: err.to_string(),
+                })?;
+            if let Err(err) = set_current_thread_priority(ThreadPriority::Crossplatform(priority)) {
+                warn!(
+                    "Failed to set priority of worker thread on core {}: {err:?}",
+                    core.id
+                );
+            }
+        }
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context(BuildRuntimeSnafu { core: core.id })
+    }
+
+    async fn new_server(&self, listen_address: &ListenAddress) -> Result<Server, server::Error> {
+        Server::new(
+            listen_address,
+            self.canvas.clone(),
+            Arc::clone(&self.canvases),
+            Arc::clone(&self.buffer_pool),
+            Arc::clone(&self.connection_limits),
+            self.send_connection_limit_reason,
+            self.access_list.clone(),
+            Arc::clone(&self.pixel_rate_limiter),
+            self.connection_timeouts,
+            self.output_limit,
+            Transport::Plain,
+            Arc::clone(&self.trusted_proxies),
+            true,
+        )
+        .await
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use snafu::{OptionExt, ResultExt, Snafu};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::{VerifierBuilderError, WebPkiClientVerifier},
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to read PEM file {path:?}"))]
+    ReadPemFile {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("There is no certificate in {path:?}"))]
+    NoCertificate { path: PathBuf },
+
+    #[snafu(display("There is no private key in {path:?}"))]
+    NoPrivateKey { path: PathBuf },
+
+    #[snafu(display("Invalid client CA certificate in {path:?}"))]
+    InvalidClientCaCertificate {
+        source: tokio_rustls::rustls::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Failed to set up client certificate verification"))]
+    BuildClientCertificateVerifier { source: VerifierBuilderError },
+
+    #[snafu(display("The certificate does not match the private key"))]
+    BuildServerConfig { source: tokio_rustls::rustls::Error },
+}
+
+/// Loads the certificate chain and private key of the TLS listener.
+///
+/// If `client_ca` is set, clients need a certificate signed by one of the CA certificates in that file to connect.
+pub fn load_acceptor(
+    certificate: &Path,
+    private_key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<TlsAcceptor, Error> {
+    let certificates = read_certificates(certificate)?;
+    let private_key = read_private_key(private_key)?;
+
+    let config = ServerConfig::builder();
+    let config = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(client_ca)? {
+                roots
+                    .add(certificate)
+                    .context(InvalidClientCaCertificateSnafu { path: client_ca })?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .context(BuildClientCertificateVerifierSnafu)?;
+            config.with_client_cert_verifier(verifier)
+        }
+        None => config.with_no_client_auth(),
+    };
+    let config = config
+        .with_single_cert(certificates, private_key)
+        .context(BuildServerConfigSnafu)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut reader = BufReader::new(File::open(path).context(ReadPemFileSnafu { path })?);
+    let certificates = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .context(ReadPemFileSnafu { path })?;
+    if certificates.is_empty() {
+        return NoCertificateSnafu { path }.fail();
+    }
+    Ok(certificates)
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    let mut reader = BufReader::new(File::open(path).context(ReadPemFileSnafu { path })?);
+    rustls_pemfile::private_key(&mut reader)
+        .context(ReadPemFileSnafu { path })?
+        .context(NoPrivateKeySnafu { path })
+}
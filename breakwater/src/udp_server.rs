@@ -0,0 +1,214 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use breakwater_core::framebuffer::FrameBuffer;
+use breakwater_parser::{implementations::SimpleParser, Parser, ParserError};
+use log::{debug, info, warn};
+use snafu::{ResultExt, Snafu};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, watch},
+    time::interval,
+};
+
+use crate::{
+    accept_backoff::{AcceptBackoff, AcceptError},
+    access_list::AccessList,
+    pixel_rate_limit::{max_pixels, PixelRateLimiter},
+    server::{ip_to_canonical, new_parser, STATISTICS_REPORT_INTERVAL},
+    statistics::StatisticsEvent,
+};
+
+/// Largest payload of a UDP datagram (over IPv4)
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to bind to UDP listen address {listen_address:?}"))]
+    BindToUdpListenAddress {
+        source: std::io::Error,
+        listen_address: String,
+    },
+
+    #[snafu(display("Failed to receive datagram"))]
+    ReceiveDatagram { source: std::io::Error },
+
+    #[snafu(display("Failed to write to statistics channel"))]
+    WriteToStatisticsChannel {
+        source: mpsc::error::SendError<StatisticsEvent>,
+    },
+
+    #[snafu(display("Failed to parse Pixelflut commands"))]
+    ParsePixelflutCommands { source: ParserError },
+}
+
+/// Pixelflut over UDP, every datagram contains one or more newline separated commands.
+///
+/// Datagrams are independent of each other, so e.g. an `OFFSET` only applies to the rest of its datagram.
+/// There are no connections, clients are only counted in the bytes statistics and can not switch the canvas.
+pub struct UdpServer {
+    socket: UdpSocket,
+    fb: Arc<FrameBuffer>,
+    statistics_tx: mpsc::Sender<StatisticsEvent>,
+    /// Whether responses (e.g. to `SIZE`) are sent back. The source address of a datagram can be spoofed, so this
+    /// allows reflection attacks using e.g. `HELP`.
+    send_responses: bool,
+    access_list: Option<Arc<AccessList>>,
+    /// Datagrams can not be throttled, pixels exceeding the limit are always dropped
+    pixel_rate_limiter: Arc<PixelRateLimiter>,
+}
+
+impl UdpServer {
+    pub async fn new(
+        listen_address: &str,
+        fb: Arc<FrameBuffer>,
+        statistics_tx: mpsc::Sender<StatisticsEvent>,
+        send_responses: bool,
+        access_list: Option<Arc<AccessList>>,
+        pixel_rate_limiter: Arc<PixelRateLimiter>,
+    ) -> Result<Self, Error> {
+        let socket = UdpSocket::bind(listen_address)
+            .await
+            .context(BindToUdpListenAddressSnafu { listen_address })?;
+        info!("Started Pixelflut UDP server on {listen_address}");
+
+        Ok(Self {
+            socket,
+            fb,
+            statistics_tx,
+            send_responses,
+            access_list,
+            pixel_rate_limiter,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+
+    /// Receives datagrams until `shutdown` is set.
+    ///
+    /// Just like accepting TCP connections, failing to receive a datagram is only fatal if the socket is broken.
+    /// Otherwise the error is counted and receiving is retried, with an exponential backoff if we are out of memory.
+    pub async fn start(&self, mut shutdown: watch::Receiver<bool>) -> Result<(), Error> {
+        let parser_lookahead = SimpleParser::parser_lookahead();
+        // One additional byte for the newline terminating the last command
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE + 1 + parser_lookahead];
+        let mut response = Vec::new();
+
+        // Same as for the TCP connections, the bytes are aggregated per client before reporting them
+        let mut statistics_interval = interval(STATISTICS_REPORT_INTERVAL);
+        let mut statistics_bytes_read: HashMap<IpAddr, u64> = HashMap::new();
+        let mut backoff = AcceptBackoff::default();
+
+        loop {
+            let received = tokio::select! {
+                received = self.socket.recv_from(&mut buffer[..MAX_DATAGRAM_SIZE]) => Some(received),
+                _ = statistics_interval.tick() => None,
+                _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+            };
+            let (bytes_read, socket_addr) = match received {
+                Some(Ok(received)) => {
+                    if backoff.succeeded() {
+                        info!("Receiving UDP datagrams again");
+                    }
+                    received
+                }
+                Some(Err(err)) => {
+                    let Some(error) = AcceptError::from_io_error(&err) else {
+                        return Err(err).context(ReceiveDatagramSnafu);
+                    };
+                    let was_backing_off = backoff.is_backing_off();
+                    let delay = backoff.failed(error);
+                    // Only log once per streak of failures, just like for TCP connections
+                    if !delay.is_zero() && !was_backing_off {
+                        warn!("Failed to receive UDP datagram, backing off: {err}");
+                    } else {
+                        debug!("Failed to receive UDP datagram: {err}");
+                    }
+                    self.statistics_tx
+                        .send(StatisticsEvent::ReceiveDatagramFailed)
+                        .await
+                        .context(WriteToStatisticsChannelSnafu)?;
+
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => continue,
+                        _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+                    }
+                }
+                None => {
+                    self.report_statistics(&mut statistics_bytes_read).await?;
+                    continue;
+                }
+            };
+            let ip = ip_to_canonical(socket_addr.ip());
+            if self
+                .access_list
+                .as_ref()
+                .is_some_and(|access_list| !access_list.is_allowed(ip))
+            {
+                continue;
+            }
+            *statistics_bytes_read.entry(ip).or_insert(0) += bytes_read as u64;
+
+            // The last command does not need to be terminated by a newline
+            let mut data_end = bytes_read;
+            if bytes_read > 0 && buffer[bytes_read - 1] != b'\n' {
+                buffer[bytes_read] = b'\n';
+                data_end += 1;
+            }
+            // Zero the lookahead, so that the parser does not see commands of a previous datagram
+            buffer[data_end..data_end + parser_lookahead].fill(0);
+
+            let mut parser = new_parser(&self.fb, ip);
+            parser.set_pixel_budget(
+                self.pixel_rate_limiter.take(ip, max_pixels(data_end)),
+                false,
+            );
+            response.clear();
+            let mut parse_start = 0;
+            loop {
+                parser
+                    .parse(
+                        &buffer[parse_start..data_end + parser_lookahead],
+                        &self.fb,
+                        &mut response,
+                    )
+                    .await
+                    .context(ParsePixelflutCommandsSnafu)?;
+                // The parser stops at `CANVAS` commands, switching is not supported so we just carry on
+                if parser.take_requested_canvas().is_none() {
+                    break;
+                }
+                parse_start += parser.resume_offset();
+            }
+            self.pixel_rate_limiter.give_back(ip, parser.pixel_budget());
+
+            if self.send_responses {
+                for chunk in response.chunks(MAX_DATAGRAM_SIZE) {
+                    // Just like the datagrams we receive, responses can get lost
+                    let _ = self.socket.send_to(chunk, socket_addr).await;
+                }
+            }
+        }
+
+        self.report_statistics(&mut statistics_bytes_read).await
+    }
+
+    async fn report_statistics(
+        &self,
+        statistics_bytes_read: &mut HashMap<IpAddr, u64>,
+    ) -> Result<(), Error> {
+        for (ip, bytes) in statistics_bytes_read.drain() {
+            self.statistics_tx
+                .send(StatisticsEvent::BytesRead {
+                    ip,
+                    listener: None,
+                    bytes,
+                })
+                .await
+                .context(WriteToStatisticsChannelSnafu)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use std::str::FromStr;
+
+use breakwater_core::protected_regions::Rect;
+
+/// Grid of screens (e.g. projectors) showing one drawing surface together, every screen gets its own VNC server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VideoWallLayout {
+    pub columns: usize,
+    pub rows: usize,
+}
+
+impl FromStr for VideoWallLayout {
+    type Err = String;
+
+    /// Parses `<columns>x<rows>`, e.g. `3x2`
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid video wall layout {input:?}, expected <columns>x<rows>");
+        let (columns, rows) = input.split_once('x').ok_or_else(invalid)?;
+        let columns = columns.parse().map_err(|_| invalid())?;
+        let rows = rows.parse().map_err(|_| invalid())?;
+        if columns == 0 || rows == 0 {
+            return Err(invalid());
+        }
+
+        Ok(VideoWallLayout { columns, rows })
+    }
+}
+
+impl VideoWallLayout {
+    /// Splits the drawing surface into equally sized tiles, row by row starting at the top left.
+    ///
+    /// The bezels between two screens hide `bezel_width` (between columns) and `bezel_height` (between rows) pixels,
+    /// so that lines continue straight across the screens. Pixels left over by the division are not shown.
+    /// Returns [`None`] if the tiles would be empty.
+    pub fn tiles(
+        &self,
+        width: usize,
+        height: usize,
+        bezel_width: usize,
+        bezel_height: usize,
+    ) -> Option<Vec<Rect>> {
+        let tile_width = width.checked_sub((self.columns - 1) * bezel_width)? / self.columns;
+        let tile_height = height.checked_sub((self.rows - 1) * bezel_height)? / self.rows;
+        if tile_width == 0 || tile_height == 0 {
+            return None;
+        }
+
+        Some(
+            (0..self.rows)
+                .flat_map(|row| {
+                    (0..self.columns).map(move |column| Rect {
+                        x: column * (tile_width + bezel_width),
+                        y: row * (tile_height + bezel_height),
+                        width: tile_width,
+                        height: tile_height,
+                    })
+                })
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+/// Exposes a WebSocket connection as plain byte stream, so that it can be handled like a TCP connection.
+///
+/// Text and binary messages are concatenated, so commands can span messages just like they can span TCP segments.
+/// Every write is sent as a single message, as text if it is valid UTF-8 (which all responses are).
+pub struct WebSocketAdapter<S> {
+    inner: WebSocketStream<S>,
+    /// Payload of the last message received, of which `read_position` bytes were read already
+    read_buffer: Vec<u8>,
+    read_position: usize,
+    /// Written messages might only be buffered, as nobody flushes the stream they are flushed on the next read
+    needs_flush: bool,
+}
+
+impl<S> WebSocketAdapter<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        WebSocketAdapter {
+            inner,
+            read_buffer: Vec::new(),
+            read_position: 0,
+            needs_flush: false,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketAdapter<S> {
+    fn poll_flush_pending(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if self.needs_flush {
+            if let Poll::Ready(result) = self.inner.poll_flush_unpin(cx) {
+                self.needs_flush = false;
+                result.map_err(io::Error::other)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketAdapter<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_flush_pending(cx)?;
+
+        loop {
+            let remaining = &this.read_buffer[this.read_position..];
+            if !remaining.is_empty() {
+                let bytes = remaining.len().min(buf.remaining());
+                buf.put_slice(&remaining[..bytes]);
+                this.read_position += bytes;
+                return Poll::Ready(Ok(()));
+            }
+
+            this.read_buffer = match ready!(this.inner.poll_next_unpin(cx)) {
+                Some(Ok(Message::Text(text))) => text.into_bytes(),
+                Some(Ok(Message::Binary(data))) => data,
+                // Pings are answered by tungstenite
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                // Reading nothing signals the end of the stream
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(err)) => return Poll::Ready(Err(io::Error::other(err))),
+            };
+            this.read_position = 0;
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketAdapter<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.inner.poll_ready_unpin(cx)).map_err(io::Error::other)?;
+
+        let message = match std::str::from_utf8(buf) {
+            Ok(text) => Message::Text(text.to_owned()),
+            Err(_) => Message::Binary(buf.to_vec()),
+        };
+        this.inner
+            .start_send_unpin(message)
+            .map_err(io::Error::other)?;
+        this.needs_flush = true;
+        this.poll_flush_pending(cx)?;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.inner.poll_flush_unpin(cx)).map_err(io::Error::other)?;
+        this.needs_flush = false;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .inner
+            .poll_close_unpin(cx)
+            .map_err(io::Error::other)
+    }
+}